@@ -21,6 +21,10 @@ pub fn make_app() -> App<'static, 'static> {
 }
 
 fn main() {
+    // mdbook runs the preprocessor as a separate process, which has to set up
+    // its own logger; warnings and the run summary are shown by default
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
     let matches = make_app().get_matches();
 
     // Users will want to construct their own preprocessor here
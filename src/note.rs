@@ -1,437 +1,1153 @@
-use mdbook::book::{Book, Chapter, SectionNumber};
+use glob::{MatchOptions, Pattern};
+use log::{debug, info, warn};
+use mdbook::book::{Book, Chapter};
 use mdbook::errors::Error;
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
-use mdbook::BookItem;
-use regex::{Captures, Regex, RegexBuilder};
-use std::collections::HashMap;
+use mdbook::{BookItem, Config};
+use regex::Regex;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use clean::{Ids, InlineStyle};
+use config::{Insert, Layout, Mode, NoteConfig};
+use generate::{disambiguate_headings, note_chapters, renumber};
+use util::{for_each_chapter_mut, wildcard_match};
+
+mod clean;
+mod config;
+#[cfg(test)]
+mod fixture;
+mod generate;
+mod marker;
+mod util;
 
 pub struct Note {
-    regex: Regex,
+    /// Word of the markers, `note` in `{{#note key}}`.
+    marker: String,
+    /// Enabled marker syntaxes, `braces` or `comments`.
+    syntaxes: Vec<String>,
+    /// One regex per enabled marker syntax.
+    regexes: Vec<Regex>,
+    /// Regex of the `{{#note-exclude}}` and `{{#note-exclude end}}` markers.
+    exclude_regex: Regex,
+    /// Regex of the `{{#note-ref id}}` markers.
+    ref_regex: Regex,
+    /// Regex of the text looking like a marker, as `{{#note key}` or `{{ #note key}}`.
+    almost_regex: Regex,
+    /// Whether the markers inside exclude regions are removed instead of kept
+    /// as literal text.
+    strip_excluded: bool,
+    strict: bool,
+    /// Level separator used when a key contains both `|` and `/`.
+    key_separator: char,
+    /// Whether notes without a `hidden` attribute are dropped from their chapter.
+    hidden_by_default: bool,
+    /// Class of the `<div>` wrapping local notes in their chapter.
+    local_class: String,
+    /// How the collected notes are kept in their chapter.
+    inline_style: InlineStyle,
+    /// Class of the element wrapping the collected notes in their chapter,
+    /// with an `inline-style` other than `plain`.
+    inline_class: String,
+    /// Text of the links of `inline-style = "link"`, `{key}` and `{title}`
+    /// standing for the key path and title of the note.
+    inline_link_text: String,
+    /// Whether an opening marker without end marker closes at the next blank line.
+    paragraph_mode: bool,
+    /// Whether keys differing only by case are merged.
+    normalize_keys: bool,
+    /// Canonical key of each alias key.
+    aliases: HashMap<String, String>,
+    /// Key of the notes written without key, which otherwise go to the root chapter.
+    uncategorized: Option<String>,
+    /// Number of key levels beyond which the levels are joined into the last one.
+    max_depth: Option<usize>,
+    /// Whether the `#tags` of a note body file the note under a key per tag.
+    collect_hashtags: bool,
+    /// Whether a note repeating the key and body of a previous one is dropped.
+    dedupe: bool,
+    /// Whether notes are numbered with an anchor in their chapter, linked from
+    /// the generated chapter.
+    deep_links: bool,
+    /// Whether notes are followed in their chapter by a link to the generated
+    /// chapter of their key.
+    backlinks: bool,
+    /// Text of the backlinks, `{chapter}` standing for the generated chapter.
+    backlink_text: String,
+    /// Keys of the only notes collected when set, `*` matching any text.
+    only_keys: Option<Vec<String>>,
+    /// Keys of the notes cleaned from their chapter but not collected.
+    exclude_keys: Vec<String>,
+    /// Patterns of the only chapters processed when set, matched as `ignore`.
+    include: Option<Vec<Pattern>>,
+    /// Patterns of the chapters left untouched, matched against their path,
+    /// or their name for draft chapters.
+    ignore: Vec<Pattern>,
+    /// Weights of keys, the sub chapters of lighter keys coming first.
+    order: Vec<(String, i64)>,
+    /// Weight of the keys missing from `order`.
+    order_default: i64,
+    /// Whether the notes keep the line of their marker, shown in the
+    /// generated chapter.
+    provenance: bool,
+    /// Whether the notes keep their source chapter, linked from the generated
+    /// chapter when their body is shortened.
+    read_more: bool,
+    /// Markdown starting the generated chapters of keys, after their path.
+    descriptions: Vec<(String, String)>,
+    /// Icons before the names of the generated chapters of keys.
+    icons: Vec<(String, String)>,
+    /// Keys rendered as a glossary, from `key-modes`.
+    glossaries: Vec<String>,
 }
 
-#[derive(Eq, PartialEq, Debug, Clone)]
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
 struct Extract {
     key: Vec<String>,
     val: String,
+    /// Human title of the note, written after `::` in the marker.
+    title: Option<String>,
+    /// `name=value` attributes of the marker.
+    attributes: HashMap<String, String>,
+    /// Whether this is the source chapter heading introducing the next extracts.
+    heading: bool,
+    /// Path of the source chapter of a heading, or of a note with a `site`,
+    /// a `line` or a shortened body, linked from the generated chapter.
+    source: Option<PathBuf>,
+    /// Number of the note in its source chapter with `deep-links`, linked as
+    /// `#note-{site}`.
+    site: Option<usize>,
+    /// Section number of the source chapter of a heading, as `3.4`.
+    number: Option<String>,
+    /// Line of the opening marker of a note in its source chapter, with
+    /// `provenance`.
+    line: Option<usize>,
+    /// Date of the last commit of the source chapter of a heading, with
+    /// `git-dates`.
+    date: Option<String>,
+    /// Index of the part title of the book the source chapter is under, none
+    /// before the first one.
+    part: Option<usize>,
 }
 
-struct Extracts {
-    name: String,
-    list: Vec<Extract>,
-}
+/// Title and path of the generated chapter of each key, as folded levels
+/// from the top one.
+type Backlinks = HashMap<Vec<String>, (String, PathBuf)>;
+
+/// Ids of the headings linking to a source chapter in each generated page,
+/// keyed on the link of the heading.
+type Headings = HashMap<PathBuf, HashMap<String, String>>;
 
 impl Note {
-    pub fn new() -> Note {
-        let re = RegexBuilder::new(r"\{\{#note ?(?P<key>[^}]*)}}(?P<val>[^\{]*)\{\{#note end}}")
-            .multi_line(true)
-            .dot_matches_new_line(true)
-            .build()
-            .unwrap();
-
-        Note { regex: re }
+    /// Weights of the keys of the extracts, as levels from the top one spelled
+    /// as in the generated chapters, warning about the keys of `order` no
+    /// extract has.
+    fn weights(&self, extracts: &[Extract]) -> HashMap<Vec<String>, i64> {
+        let mut weights = HashMap::new();
+
+        for (key, weight) in self.order.iter() {
+            match self.key_path(key, extracts) {
+                Some(path) => {
+                    weights.insert(path, *weight);
+                }
+                None => warn!(
+                    "preprocessor.note.order key {} matches no {} note",
+                    key, self.marker
+                ),
+            }
+        }
+
+        weights
+    }
+
+    /// Values of the keys of the extracts, as `weights`, from the `option`
+    /// table `entries`, warning about the keys no extract has.
+    fn key_values(
+        &self,
+        option: &str,
+        entries: &[(String, String)],
+        extracts: &[Extract],
+    ) -> HashMap<Vec<String>, String> {
+        let mut values = HashMap::new();
+
+        for (key, value) in entries.iter() {
+            match self.key_path(key, extracts) {
+                Some(path) => {
+                    values.insert(path, value.clone());
+                }
+                None => warn!(
+                    "preprocessor.note.{} key {} matches no {} note",
+                    option, key, self.marker
+                ),
+            }
+        }
+
+        values
+    }
+
+    /// Levels from the top one of the keys rendered as a glossary, warning
+    /// about the ones no extract has.
+    fn glossary_keys(&self, extracts: &[Extract]) -> HashSet<Vec<String>> {
+        let mut glossaries = HashSet::new();
+
+        for key in self.glossaries.iter() {
+            match self.key_path(key, extracts) {
+                Some(path) => {
+                    glossaries.insert(path);
+                }
+                None => warn!(
+                    "preprocessor.note.key-modes key {} matches no {} note",
+                    key, self.marker
+                ),
+            }
+        }
+
+        glossaries
+    }
+
+    /// Levels from the top one of `key` spelled as in the first extract
+    /// having it, if any.
+    fn key_path(&self, key: &str, extracts: &[Extract]) -> Option<Vec<String>> {
+        let levels: Vec<String> = self.levels(key).iter().map(|l| self.fold(l)).collect();
+        let found = extracts.iter().find(|extract| {
+            extract.key.len() >= levels.len()
+                && extract
+                    .key
+                    .iter()
+                    .rev()
+                    .zip(levels.iter())
+                    .all(|(k, l)| self.fold(k) == *l)
+        })?;
+
+        Some(found.key.iter().rev().take(levels.len()).cloned().collect())
+    }
+
+    /// Whether a chapter is left untouched, not matching the `include`
+    /// patterns when they are set, matching an `ignore` pattern or starting
+    /// with a `<!-- mdbook-note: skip -->` directive.
+    fn is_skipped(&self, chapter: &Chapter) -> bool {
+        let included = match &self.include {
+            Some(include) => matches_chapter(include, chapter),
+            None => true,
+        };
+
+        !included
+            || matches_chapter(&self.ignore, chapter)
+            || chapter_directive(&chapter.content).map(|(_, d)| d) == Some("skip")
+    }
+
+    /// Key as compared for grouping, case folded when keys are normalized.
+    /// Uppercasing before lowercasing applies the full case mappings, so that
+    /// `Straße` and `STRASSE` or `ΣΑΣ` and `σας` fold alike.
+    fn fold(&self, key: &str) -> String {
+        if self.normalize_keys {
+            key.to_uppercase().to_lowercase()
+        } else {
+            key.to_string()
+        }
+    }
+
+    /// Rename the key levels differing only by case from a level seen before
+    /// to the casing seen first, so that they are grouped together.
+    fn merge_keys(&self, extracts: &mut [Extract]) {
+        let mut names: HashMap<Vec<String>, String> = HashMap::new();
+
+        for extract in extracts.iter_mut() {
+            let mut path = vec![];
+
+            for level in extract.key.iter_mut().rev() {
+                path.push(self.fold(level));
+                *level = names
+                    .entry(path.clone())
+                    .or_insert_with(|| level.clone())
+                    .clone();
+            }
+        }
     }
 
-    fn parse_chapter(&self, chapter: &Chapter) -> Vec<Extract> {
-        let mut res = vec![];
-
-        let mut find_key: HashMap<String, bool> = HashMap::new();
-
-        for cap in self.regex.captures_iter(chapter.content.as_str()) {
-            let key = capture(&cap, "key");
-
-            for key in key.clone().split("||").into_iter() {
-                let mut keys: Vec<String> = key
-                    .split('|')
-                    .into_iter()
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| s != &"".to_string())
-                    .collect();
-                keys.reverse();
-
-                if !find_key.contains_key(&*key) {
-                    res.push(Extract {
-                        key: keys.clone(),
-                        val: format!("### {}", chapter.name),
-                    });
-                    find_key.insert(key.to_string(), true);
+    /// Warn about the notes repeating the key and whitespace normalized body of
+    /// a previous one. With `dedupe` the repetitions are dropped, and so are
+    /// the chapter headings left without notes.
+    fn dedupe(&self, extracts: Vec<Extract>) -> Vec<Extract> {
+        let mut chapters: HashMap<Vec<String>, String> = HashMap::new();
+        let mut seen: HashMap<(Vec<String>, String), String> = HashMap::new();
+        let mut kept: Vec<Extract> = vec![];
+
+        for extract in extracts {
+            if extract.heading {
+                let chapter = extract.val.trim_start_matches("### ").to_string();
+                chapters.insert(extract.key.clone(), chapter);
+                kept.push(extract);
+                continue;
+            }
+
+            let chapter = chapters.get(&extract.key).cloned().unwrap_or_default();
+            let body = extract.val.split_whitespace().collect::<Vec<_>>().join(" ");
+
+            match seen.entry((extract.key.clone(), body)) {
+                Entry::Occupied(first) => {
+                    let mut key = extract.key.clone();
+                    key.reverse();
+                    warn!(
+                        "duplicate note under key {} in chapter \"{}\", already in chapter \"{}\"",
+                        key.join(&self.key_separator.to_string()),
+                        chapter,
+                        first.get()
+                    );
+                    if self.dedupe {
+                        continue;
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(chapter);
                 }
-                res.push(Extract {
-                    key: keys,
-                    val: capture(&cap, "val"),
-                })
             }
+            kept.push(extract);
+        }
+
+        if !self.dedupe {
+            return kept;
+        }
+
+        drop_empty_headings(kept)
+    }
+
+    /// Whether the notes of a key go to the generated chapter, its levels
+    /// joined from the top one matching an `only-keys` pattern when they are
+    /// set and no `exclude-keys` pattern.
+    fn is_key_kept(&self, key: &[String]) -> bool {
+        let mut levels = key.to_vec();
+        levels.reverse();
+        let path = self.fold(&levels.join(&self.key_separator.to_string()));
+
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| wildcard_match(&self.fold(pattern), &path))
+        };
+        let only = match &self.only_keys {
+            Some(only_keys) => matches(only_keys),
+            None => true,
+        };
+
+        only && !matches(&self.exclude_keys)
+    }
+
+    /// Parse every chapter, then clean them once all the note ids are known
+    /// so that a note can be referenced before it is defined.
+    #[cfg(test)]
+    fn process_book(&self, book: &mut Book, cleanup_only: bool) -> Result<Vec<Extract>, Error> {
+        let (extracts, ids) = self.collect_book(book, cleanup_only)?;
+        self.clean_book(book, &ids, &HashMap::new(), &HashMap::new())?;
+
+        Ok(extracts)
+    }
+
+    /// Check and parse every chapter, returning the extracts and the note ids
+    /// with their body and location.
+    fn collect_book(
+        &self,
+        book: &mut Book,
+        cleanup_only: bool,
+    ) -> Result<(Vec<Extract>, Ids), Error> {
+        let mut extracts: Vec<Extract> = vec![];
+        let mut ids: Ids = HashMap::new();
+
+        let mut skipped = 0;
+
+        let mut part = None;
+        for item in book.sections.iter_mut() {
+            if let BookItem::PartTitle(_) = item {
+                part = Some(part.map_or(0, |p| p + 1));
+            }
+            for_each_chapter_mut(std::slice::from_mut(item), &mut |chapter| {
+                if self.is_skipped(chapter) {
+                    skipped += 1;
+                    return Ok(());
+                }
+                self.check_chapter(chapter)?;
+
+                let directive = chapter_directive(&chapter.content).map(|(_, d)| d);
+                if !cleanup_only && directive != Some("clean-only") {
+                    let mut ext = self.parse_chapter(chapter);
+                    debug!(
+                        "{} notes in chapter \"{}\"",
+                        ext.iter().filter(|e| !e.heading).count(),
+                        chapter.name
+                    );
+                    for extract in ext.iter_mut() {
+                        extract.part = part;
+                    }
+                    extracts.append(&mut ext);
+                }
+                self.collect_ids(chapter, &mut ids)?;
+
+                Ok(())
+            })?;
+        }
+
+        if skipped > 0 {
+            info!(
+                "skipped {} chapters outside preprocessor.note.include, in ignore or with a skip directive",
+                skipped
+            );
+        }
+
+        if self.normalize_keys {
+            self.merge_keys(&mut extracts);
+        }
+
+        if self.only_keys.is_some() || !self.exclude_keys.is_empty() {
+            extracts.retain(|extract| extract.heading || self.is_key_kept(&extract.key));
+            extracts = drop_empty_headings(extracts);
+        }
+
+        let mut extracts = self.dedupe(extracts);
+        disambiguate_headings(&mut extracts);
+
+        Ok((extracts, ids))
+    }
+}
+
+/// Drop the source chapter headings not followed by a note of their key
+/// before the next heading of that key.
+fn drop_empty_headings(extracts: Vec<Extract>) -> Vec<Extract> {
+    extracts
+        .iter()
+        .enumerate()
+        .filter(|(i, extract)| {
+            !extract.heading
+                || extracts[i + 1..]
+                    .iter()
+                    .take_while(|e| !(e.heading && e.key == extract.key))
+                    .any(|e| e.key == extract.key)
+        })
+        .map(|(_, extract)| extract.clone())
+        .collect()
+}
+
+/// The `<!-- mdbook-note: skip -->` or `<!-- mdbook-note: clean-only -->`
+/// directive of a chapter, with its line, looked for among the blank lines and
+/// headings starting the chapter.
+fn chapter_directive(content: &str) -> Option<(Range<usize>, &str)> {
+    let mut offset = 0;
+
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim();
+        let start = offset;
+        offset += line.len();
+
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
         }
 
-        res
+        let directive = trimmed
+            .strip_prefix("<!--")
+            .and_then(|s| s.strip_suffix("-->"))
+            .and_then(|s| s.trim().strip_prefix("mdbook-note:"))
+            .map(str::trim);
+
+        return match directive {
+            Some(directive @ ("skip" | "clean-only")) => Some((start..offset, directive)),
+            _ => None,
+        };
     }
 
-    fn clean_chapter(&self, mut chapter: Chapter) -> Chapter {
-        let content = chapter.content.clone();
+    None
+}
+
+/// Whether a chapter matches one of the patterns, by its path or source path,
+/// or by its name when it has no path.
+fn matches_chapter(patterns: &[Pattern], chapter: &Chapter) -> bool {
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+
+    patterns
+        .iter()
+        .any(|pattern| match (&chapter.path, &chapter.source_path) {
+            (None, None) => pattern.matches_with(&chapter.name, options),
+            (path, source_path) => path
+                .iter()
+                .chain(source_path.iter())
+                .any(|path| pattern.matches_path_with(path, options)),
+        })
+}
+
+impl Preprocessor for Note {
+    fn name(&self) -> &str {
+        "note"
+    }
 
-        let new_content = self.regex.replace_all(&content, "$val");
+    fn run(&self, ctx: &PreprocessorContext, book: Book) -> Result<Book, Error> {
+        let note_config = NoteConfig::from_context(ctx)?;
 
-        chapter.content = new_content.to_string();
+        render_book(&ctx.config, &note_config, &ctx.renderer, book)
+    }
 
-        chapter
+    /// Every renderer is supported, the `collect-renderers` option being
+    /// applied by `run` which has access to the configuration.
+    fn supports_renderer(&self, _renderer: &str) -> bool {
+        true
     }
 }
 
-fn capture(cap: &Captures, k: &str) -> String {
-    match cap.name(k) {
-        Some(res) => res.as_str().trim().to_string(),
-        None => "".to_string(),
+/// Process the book for a renderer, in its mode of the `mode` table if any.
+/// The renderers outside `collect-renderers` or `generate-for` get the chapters
+/// only cleaned of their markers, or untouched when `clean-other-renderers` is
+/// false. The print page is rendered by "html" from the same chapters, so it
+/// keeps the notes chapters whenever "html" gets them.
+fn render_book(
+    config: &Config,
+    note_config: &NoteConfig,
+    renderer: &str,
+    mut book: Book,
+) -> Result<Book, Error> {
+    let collected = match &note_config.renderers {
+        Some(renderers) => renderers.iter().any(|r| r == renderer),
+        None => true,
+    };
+
+    if !collected && !note_config.clean_other_renderers {
+        return Ok(book);
+    }
+
+    let mode = match note_config.renderer_modes.get(renderer) {
+        _ if !collected => Mode::CleanOnly,
+        Some(&mode) => mode,
+        None => note_config.mode,
+    };
+    // the other renderers, such as print and epub, get plain headings
+    let layout = Layout {
+        collapsible: note_config.layout.collapsible && renderer == "html",
+        html_wrappers: note_config.layout.html_wrappers && renderer == "html",
+        ..note_config.layout.clone()
+    };
+    let note_config = NoteConfig {
+        mode,
+        layout,
+        ..note_config.clone()
+    };
+
+    let generated = note_chapters(config, &note_config, &mut book)?;
+
+    match &note_config.insert {
+        None => {
+            for note_chapter in generated {
+                book.push_item(note_chapter);
+            }
+        }
+        Some(insert) => {
+            let (key, target) = match insert {
+                Insert::After(target) => ("insert-after", target),
+                Insert::Before(target) => ("insert-before", target),
+            };
+            let found = book.sections.iter().position(|item| match item {
+                BookItem::Chapter(chapter) => {
+                    chapter.name == *target || chapter.path.as_deref() == Some(Path::new(target))
+                }
+                _ => false,
+            });
+            let index = match found {
+                Some(index) => index,
+                None => {
+                    let names: Vec<&str> = book
+                        .sections
+                        .iter()
+                        .filter_map(|item| match item {
+                            BookItem::Chapter(chapter) => Some(chapter.name.as_str()),
+                            _ => None,
+                        })
+                        .collect();
+                    return Err(Error::msg(format!(
+                        "preprocessor.note.{} chapter {:?} not found, chapters are: {}",
+                        key,
+                        target,
+                        names.join(", ")
+                    )));
+                }
+            };
+            let index = match insert {
+                Insert::After(_) => index + 1,
+                Insert::Before(_) => index,
+            };
+
+            book.sections
+                .splice(index..index, generated.into_iter().map(BookItem::Chapter));
+            renumber(&mut book.sections);
+        }
     }
+
+    Ok(book)
 }
 
 #[cfg(test)]
-mod extract_tests {
+mod tests {
     use super::*;
+    use crate::note::fixture::{
+        chapter, config_error, configs, notes_chapters, preprocessor, render,
+    };
+    use mdbook::book::SectionNumber;
 
     #[test]
-    fn test_extract_inline() {
-        let chapter = Chapter {
-            name: "some name".to_string(),
-            content: "some outer content {{#note my_key}}inside contente{{#note end}} other outer content".to_string(),
-            number: None,
-            sub_items: vec![],
-            path: None,
-            source_path: None,
-            parent_names: vec![],
-        };
+    fn test_process_nested_chapters() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "level 1",
+            "one {{#note a}}note 1{{#note end}}",
+            vec![BookItem::Chapter(chapter(
+                "level 2",
+                "two {{#note b}}note 2{{#note end}}",
+                vec![BookItem::Chapter(chapter(
+                    "level 3",
+                    "three {{#note a}}note 3{{#note end}}",
+                    vec![],
+                ))],
+            ))],
+        ));
+        book.push_item(BookItem::Separator);
+        book.push_item(chapter("other", "no note", vec![]));
 
         let note = Note::new();
 
+        let extracts = note.process_book(&mut book, false).unwrap();
+
         assert_eq!(
-            note.parse_chapter(&chapter),
+            extracts,
             vec![
                 Extract {
-                    key: vec!["my_key".to_string()],
-                    val: "### some name".to_string(),
+                    key: vec!["a".to_string()],
+                    val: "### level 1".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["a".to_string()],
+                    val: "note 1".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["b".to_string()],
+                    val: "### level 2".to_string(),
+                    heading: true,
+                    ..Default::default()
                 },
                 Extract {
-                    key: vec!["my_key".to_string()],
-                    val: "inside contente".to_string(),
+                    key: vec!["b".to_string()],
+                    val: "note 2".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["a".to_string()],
+                    val: "### level 3".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["a".to_string()],
+                    val: "note 3".to_string(),
+                    ..Default::default()
                 },
             ]
+        );
+
+        let mut expected = Book::new();
+        expected.push_item(chapter(
+            "level 1",
+            "one note 1",
+            vec![BookItem::Chapter(chapter(
+                "level 2",
+                "two note 2",
+                vec![BookItem::Chapter(chapter(
+                    "level 3",
+                    "three note 3",
+                    vec![],
+                ))],
+            ))],
+        ));
+        expected.push_item(BookItem::Separator);
+        expected.push_item(chapter("other", "no note", vec![]));
+
+        assert_eq!(book, expected);
+    }
+
+    #[test]
+    fn test_process_multiple_markers() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "some name",
+            "{{#note}}remember{{#note end}} {{#todo}}fix it{{#todo end}}",
+            vec![],
+        ));
+
+        let note_chapters = notes_chapters(
+            "[preprocessor.note.markers]
+note = \"Notes\"
+todo = \"TODO\"",
+            &mut book,
         )
+        .unwrap();
+
+        let names: Vec<&str> = note_chapters.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["Notes", "TODO"]);
+
+        assert!(note_chapters[0].content.contains("remember"));
+        assert!(!note_chapters[0].content.contains("fix it"));
+        assert_eq!(note_chapters[0].number, Some(SectionNumber(vec![1])));
+
+        assert!(note_chapters[1].content.contains("fix it"));
+        assert!(!note_chapters[1].content.contains("remember"));
+        assert_eq!(note_chapters[1].number, Some(SectionNumber(vec![2])));
+
+        let mut expected = Book::new();
+        expected.push_item(chapter("some name", "remember fix it", vec![]));
+
+        assert_eq!(book, expected);
     }
 
     #[test]
-    fn test_extract_multiline() {
-        let chapter = Chapter {
-            name: "some name".to_string(),
-            content: "some outer content
-            {{#note my_key}}
-            inside contente
-            {{#note end}}
-            other outer content"
-                .to_string(),
-            number: None,
-            sub_items: vec![],
-            path: None,
-            source_path: None,
-            parent_names: vec![],
+    fn test_process_normalize_keys() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "first",
+            "{{#note Performance|Été}}a{{#note end}} {{#note performance|ÉTÉ}}b{{#note end}}",
+            vec![],
+        ));
+        book.push_item(chapter(
+            "second",
+            "{{#note PERFORMANCE}}c{{#note end}} {{#note Straße}}d{{#note end}} {{#note STRASSE}}e{{#note end}}",
+            vec![],
+        ));
+
+        let note = Note {
+            normalize_keys: true,
+            ..Note::new()
         };
 
-        let note = Note::new();
+        let extracts: Vec<String> = note
+            .process_book(&mut book, false)
+            .unwrap()
+            .into_iter()
+            .map(|e| format!("{}: {}", e.key.join("<"), e.val))
+            .collect();
 
         assert_eq!(
-            note.parse_chapter(&chapter),
+            extracts,
             vec![
-                Extract {
-                    key: vec!["my_key".to_string()],
-                    val: "### some name".to_string(),
-                },
-                Extract {
-                    key: vec!["my_key".to_string()],
-                    val: "inside contente".to_string(),
-                },
+                "Été<Performance: ### first",
+                "Été<Performance: a",
+                "Été<Performance: b",
+                "Performance: ### second",
+                "Performance: c",
+                "Straße: ### second",
+                "Straße: d",
+                "Straße: e",
             ]
-        )
+        );
     }
 
     #[test]
-    fn test_extract_multiline_multicapture() {
-        let chapter = Chapter {
-            name: "some name".to_string(),
-            content: "some outer content
-{{#note ||my_key| my sub key}}
-inside contente split
-{{#note end}}
-other outer content
-blablabla
-{{#note my key 2}}
-other content
-split
-{{#note end}}
-{{#note}}
-some global note
-{{#note end}}
-{{#note my key 2}}
-my other key 2
-{{#note end}}
-end
-"
-            .to_string(),
-            number: None,
-            sub_items: vec![],
-            path: None,
-            source_path: None,
-            parent_names: vec![],
-        };
+    fn test_process_dedupe() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "first",
+            "{{#note tips}}Use the  cache.{{#note end}} {{#note other}}kept{{#note end}}",
+            vec![],
+        ));
+        book.push_item(chapter(
+            "second",
+            "{{#note tips}}Use the\ncache.{{#note end}} {{#note other}}also kept{{#note end}}",
+            vec![],
+        ));
 
-        let note = Note::new();
+        let extracts = |note: Note| -> Vec<String> {
+            note.process_book(&mut book.clone(), false)
+                .unwrap()
+                .into_iter()
+                .map(|e| format!("{}: {}", e.key.join("<"), e.val))
+                .collect()
+        };
 
+        assert_eq!(extracts(Note::new()).len(), 8);
         assert_eq!(
-            note.parse_chapter(&chapter),
+            extracts(Note {
+                dedupe: true,
+                ..Note::new()
+            }),
             vec![
-                Extract {
-                    key: vec![],
-                    val: "### some name".to_string(),
-                },
-                Extract {
-                    key: vec![],
-                    val: "inside contente split".to_string(),
-                },
-                Extract {
-                    key: vec!["my sub key".to_string(), "my_key".to_string()],
-                    val: "### some name".to_string(),
-                },
-                Extract {
-                    key: vec!["my sub key".to_string(), "my_key".to_string()],
-                    val: "inside contente split".to_string(),
-                },
-                Extract {
-                    key: vec!["my key 2".to_string()],
-                    val: "### some name".to_string(),
-                },
-                Extract {
-                    key: vec!["my key 2".to_string()],
-                    val: "other content\nsplit".to_string(),
-                },
-                Extract {
-                    key: vec![],
-                    val: "some global note".to_string(),
-                },
-                Extract {
-                    key: vec!["my key 2".to_string()],
-                    val: "my other key 2".to_string(),
-                },
+                "tips: ### first",
+                "tips: Use the  cache.",
+                "other: ### first",
+                "other: kept",
+                "other: ### second",
+                "other: also kept",
             ]
-        )
+        );
     }
-}
 
-impl Preprocessor for Note {
-    fn name(&self) -> &str {
-        "note"
-    }
+    #[test]
+    fn test_process_ignore() {
+        let mut book = Book::new();
+        book.push_item(chapter("intro", "{{#note a}}kept{{#note end}}", vec![]));
+        book.push_item(chapter(
+            "vendor",
+            "{{#note a}}vendored{{#note end}} {{#note}",
+            vec![],
+        ));
+        book.push_item(chapter("draft", "{{#note a}}skipped{{#note end}}", vec![]));
+        for_each_chapter_mut(&mut book.sections, &mut |chapter| {
+            chapter.path = match chapter.name.as_str() {
+                "vendor" => Some(PathBuf::from("appendix/vendor/lib/x.md")),
+                "draft" => None,
+                _ => chapter.path.take(),
+            };
+            Ok(())
+        })
+        .unwrap();
 
-    fn run(&self, ctx: &PreprocessorContext, book: Book) -> Result<Book, Error> {
-        let mut cleanup_only = false;
-        let mut name = "note".to_string();
+        let note = preprocessor(
+            "[preprocessor.note]
+ignore = [\"appendix/vendor/**\", \"draft\"]
+strict = true",
+        )
+        .unwrap();
+        let original = book.clone();
 
-        // In testing we want to tell the preprocessor to blow up by setting a
-        // particular config value
-        if let Some(nop_cfg) = ctx.config.get_preprocessor(self.name()) {
+        let extracts: Vec<String> = note
+            .process_book(&mut book, false)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.val)
+            .collect();
 
-            match nop_cfg.get("name") {
-                None => {}
-                Some(value) => {
-                    name = value.as_str().unwrap().to_string();
-                }
-            }
-            match nop_cfg.get("cleanup_only") {
-                None => {}
-                Some(value) => {
-                    cleanup_only = value.as_bool().unwrap();
-                }
-            }
+        assert_eq!(extracts, vec!["### intro", "kept"]);
+        assert_eq!(book.sections[1], original.sections[1]);
+        assert_eq!(book.sections[2], original.sections[2]);
+
+        assert!(preprocessor("[preprocessor.note]\nignore = [\"a[\"]").is_err());
+    }
+
+    #[test]
+    fn test_process_include() {
+        let mut book = Book::new();
+        for (name, path) in [
+            ("lesson", "course/one/lesson.md"),
+            ("quiz", "course/one/quiz.md"),
+            ("legacy", "legacy.md"),
+        ] {
+            let mut chapter = chapter(name, "{{#note a}}from {{#note end}}", vec![]);
+            chapter.content.push_str(name);
+            chapter.path = Some(PathBuf::from(path));
+            book.push_item(chapter);
         }
 
-        let mut extracts: Vec<Extract> = vec![];
+        let note = preprocessor(
+            "[preprocessor.note]
+include = [\"course/**\"]
+ignore = [\"**/quiz.md\"]",
+        )
+        .unwrap();
+        let original = book.clone();
 
-        let mut new_book = Book::new();
+        let extracts: Vec<String> = note
+            .process_book(&mut book, false)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.val)
+            .collect();
 
-        for item in book.iter() {
-            let new_item = match item {
-                BookItem::Chapter(chapter) => {
-                    if !cleanup_only {
-                        let mut ext = self.parse_chapter(chapter);
-                        extracts.append(&mut ext);
-                    }
-                    let clean = self.clean_chapter(chapter.clone());
-                    BookItem::Chapter(clean)
-                }
-                BookItem::Separator => BookItem::Separator,
-                BookItem::PartTitle(title) => BookItem::PartTitle(title.to_string()),
-            };
-            new_book.push_item(new_item);
-        }
+        assert_eq!(extracts, vec!["### lesson", "from"]);
+        assert_ne!(book.sections[0], original.sections[0]);
+        assert_eq!(book.sections[1], original.sections[1]);
+        assert_eq!(book.sections[2], original.sections[2]);
+    }
 
-        if extracts.is_empty() {
-            return Ok(new_book);
-        }
+    #[test]
+    fn test_process_key_filters() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "first",
+            "{{#note scratch}}musing{{#note end}} {{#note private|diary}}secret{{#note end}} {{#note public}}shared{{#note end}}",
+            vec![],
+        ));
+        book.push_item(chapter(
+            "second",
+            "{{#note scratch}}other musing{{#note end}}",
+            vec![],
+        ));
+
+        let note = preprocessor(
+            "[preprocessor.note]
+exclude-keys = [\"scratch\", \"private|*\"]",
+        )
+        .unwrap();
+
+        let extracts: Vec<String> = note
+            .process_book(&mut book.clone(), false)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.val)
+            .collect();
+        assert_eq!(extracts, vec!["### first", "shared"]);
 
-        let note_chapter = generate_chapter(extracts, name, vec![], vec![99]);
+        let note = preprocessor(
+            "[preprocessor.note]
+only-keys = [\"priv*\"]",
+        )
+        .unwrap();
 
-        new_book.push_item(note_chapter);
+        let extracts: Vec<String> = note
+            .process_book(&mut book, false)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.val)
+            .collect();
+        assert_eq!(extracts, vec!["### first", "secret"]);
 
-        // we *are* a no-op preprocessor after all
-        Ok(new_book)
+        let mut expected = Book::new();
+        expected.push_item(chapter("first", "musing secret shared", vec![]));
+        expected.push_item(chapter("second", "other musing", vec![]));
+        assert_eq!(book, expected);
     }
 
-    fn supports_renderer(&self, renderer: &str) -> bool {
-        renderer != "not-supported"
+    #[test]
+    fn test_process_renderers() {
+        let mut book = Book::new();
+        book.push_item(chapter("first", "{{#note a}}body{{#note end}}", vec![]));
+
+        let html = render(
+            "[preprocessor.note]\ncollect-renderers = [\"html\", \"markdown\"]",
+            "html",
+            book.clone(),
+        )
+        .unwrap();
+        assert_eq!(html.sections.len(), 2);
+
+        let epub = render(
+            "[preprocessor.note]\ncollect-renderers = [\"html\", \"markdown\"]",
+            "epub",
+            book.clone(),
+        )
+        .unwrap();
+        let mut expected = Book::new();
+        expected.push_item(chapter("first", "body", vec![]));
+        assert_eq!(epub, expected);
+
+        let epub = render(
+            "[preprocessor.note]
+collect-renderers = [\"html\"]
+clean-other-renderers = false",
+            "epub",
+            book.clone(),
+        )
+        .unwrap();
+        assert_eq!(epub, book);
+
+        assert_eq!(
+            render("[book]", "epub", book.clone())
+                .unwrap()
+                .sections
+                .len(),
+            2
+        );
     }
-}
 
-fn generate_chapter(
-    extracts: Vec<Extract>,
-    name: String,
-    parent: Vec<String>,
-    section: Vec<u32>,
-) -> Chapter {
-    let mut extract_by_key = HashMap::new();
-
-    let mut current_name = parent.clone();
-    current_name.push(name.clone());
-
-    let mut chapter = Chapter {
-        name: name.clone(),
-        content: format!("## {}", current_name.join(" / ")),
-        number: Some(SectionNumber(section.clone())),
-        sub_items: vec![],
-        path: Some(current_name.join("/").parse().unwrap()),
-        source_path: None,
-        parent_names: parent.clone(),
-    };
+    #[test]
+    fn test_process_generate_for() {
+        let mut book = Book::new();
+        book.push_item(chapter("first", "{{#note a}}body{{#note end}}", vec![]));
 
-    let mut parent = parent;
-    parent.push(name);
+        let html = render(
+            "[preprocessor.note]\ngenerate-for = [\"html\"]",
+            "html",
+            book.clone(),
+        )
+        .unwrap();
+        assert_eq!(html.sections.len(), 2);
 
-    for extract in extracts {
-        let mut local = extract.clone();
+        let epub = render(
+            "[preprocessor.note]\ngenerate-for = [\"html\"]",
+            "epub",
+            book.clone(),
+        )
+        .unwrap();
+        let mut expected = Book::new();
+        expected.push_item(chapter("first", "body", vec![]));
+        assert_eq!(epub, expected);
 
-        match local.key.pop() {
-            None => {
-                if !chapter.content.is_empty() {
-                    chapter.content = format!("{}\n\n{}", chapter.content, extract.val);
-                } else {
-                    chapter.content = extract.val;
-                }
-            }
-            Some(k) => {
-                let val = extract_by_key.entry(k).or_insert_with(Vec::new);
-                val.push(local);
-            }
-        }
+        assert_eq!(
+            render("[preprocessor.note]\ngenerate-for = [\"html\"]\nclean-other-renderers = false", "epub", book.clone())
+            .unwrap_err()
+            .to_string(),
+            "preprocessor.note.generate-for always cleans the chapters of the other renderers, clean-other-renderers = false is not allowed with it"
+        );
+        assert_eq!(
+            render(
+                "[preprocessor.note]\ngenerate-for = [\"html\"]\ncollect-renderers = [\"html\"]",
+                "epub",
+                book.clone()
+            )
+            .unwrap_err()
+            .to_string(),
+            "preprocessor.note.collect-renderers and generate-for are mutually exclusive"
+        );
+        assert_eq!(
+            render(
+                "[preprocessor.note]\ngenerate-for = \"html\"",
+                "epub",
+                book.clone()
+            )
+            .unwrap_err()
+            .to_string(),
+            "preprocessor.note.generate-for must be an array, found string"
+        );
     }
 
-    let mut extract_to_sort = vec![];
-    for (name, list) in extract_by_key.into_iter() {
-        let extract = Extracts { name, list };
-        extract_to_sort.push(extract);
+    #[test]
+    fn test_process_min_notes() {
+        let mut book = Book::new();
+        book.push_item(chapter("first", "{{#todo a}}renamed{{#todo end}}", vec![]));
+        book.push_item(chapter("second", "{{#note a}}kept{{#note end}}", vec![]));
+
+        assert!(notes_chapters(
+            "[preprocessor.note]\nfail-on-zero-notes = true",
+            &mut book.clone()
+        )
+        .is_ok());
+        assert_eq!(
+            notes_chapters(
+                "[preprocessor.note]\nfail-on-zero-notes = true\nmarker = \"fixme\"",
+                &mut book.clone()
+            )
+            .unwrap_err()
+            .to_string(),
+            "found 0 notes in 2 scanned chapters, preprocessor.note.min-notes requires at least 1"
+        );
+        assert_eq!(
+            notes_chapters("[preprocessor.note]\nmin-notes = 2", &mut book.clone())
+                .unwrap_err()
+                .to_string(),
+            "found 1 note in 2 scanned chapters, preprocessor.note.min-notes requires at least 2"
+        );
+        assert!(notes_chapters(
+            "[preprocessor.note]\nmin-notes = 2\ncleanup_only = true",
+            &mut book.clone()
+        )
+        .is_ok());
     }
 
-    extract_to_sort.sort_by(|a, b| a.name.cmp(&b.name));
+    #[test]
+    fn test_process_clean_only_mode() {
+        let mut book = Book::new();
+        book.push_item(chapter("first", "{{#note a}}body{{#note end}}", vec![]));
 
-    let mut i = 1;
-    for extract in extract_to_sort {
-        let mut section = section.clone();
-        section.push(i);
+        let mut cleaned = Book::new();
+        cleaned.push_item(chapter("first", "body", vec![]));
 
-        let new_chapter = generate_chapter(extract.list, extract.name, parent.clone(), section);
+        assert_eq!(
+            render(
+                "[preprocessor.note]\nmode = \"clean-only\"",
+                "html",
+                book.clone()
+            )
+            .unwrap(),
+            cleaned
+        );
 
-        chapter.sub_items.push(BookItem::Chapter(new_chapter));
+        let per_renderer = "[preprocessor.note.mode]
+epub = \"clean-only\"
+html = \"aggregate\"";
+        assert_eq!(render(per_renderer, "epub", book.clone()).unwrap(), cleaned);
+        assert_eq!(
+            render(per_renderer, "html", book.clone())
+                .unwrap()
+                .sections
+                .len(),
+            2
+        );
 
-        i += 1;
+        assert_eq!(
+            config_error("[preprocessor.note]\nmode = \"draft\""),
+            "preprocessor.note.mode must be \"aggregate\", \"clean-only\" or \"collect-only\", found \"draft\""
+        );
     }
 
-    chapter
-}
+    #[test]
+    fn test_process_collect_only_mode() {
+        let mut book = Book::new();
+        book.push_item(chapter("first", "{{#note a}}body{{#note end}}", vec![]));
 
-#[cfg(test)]
-mod generate_tests {
-    use super::*;
-    use mdbook::book::SectionNumber;
+        let (config, note_config) = configs("[preprocessor.note]\nmode = \"collect-only\"");
+        assert_eq!(note_config.mode, Mode::CollectOnly);
+
+        let rendered = render_book(&config, &note_config, "html", book.clone()).unwrap();
+        assert_eq!(rendered.sections[0], book.sections[0]);
+        assert_eq!(rendered.sections.len(), 2);
+
+        // the references are still checked
+        let mut referenced = book.clone();
+        referenced.push_item(chapter("second", "{{#note-ref y}}", vec![]));
+        assert_eq!(
+            render_book(&config, &note_config, "html", referenced)
+                .unwrap_err()
+                .to_string(),
+            "unknown note id y referenced in chapter \"second\" line 1, known ids are: "
+        );
+
+        assert_eq!(
+            config_error("[preprocessor.note]
+cleanup_only = true
+mode = \"collect-only\""),
+            "preprocessor.note.mode \"collect-only\" and cleanup_only = true are mutually exclusive"
+        );
+    }
 
     #[test]
-    fn test_generate_chapter() {
-        let extracts = vec![
-            Extract {
-                key: vec!["b".to_string()],
-                val: "content b".to_string(),
-            },
-            Extract {
-                key: vec!["a1".to_string(), "a".to_string()],
-                val: "content a1".to_string(),
-            },
-            Extract {
-                key: vec![],
-                val: "note content".to_string(),
-            },
-            Extract {
-                key: vec!["a2".to_string(), "a".to_string()],
-                val: "content a2".to_string(),
-            },
-            Extract {
-                key: vec!["a2".to_string(), "a".to_string()],
-                val: "content a2 2".to_string(),
-            },
-        ];
-
-        let chapter = Chapter {
-            name: "note".to_string(),
-            content: "## note\n\nnote content".to_string(),
-            number: Some(SectionNumber(vec![1])),
-            sub_items: vec![
-                BookItem::Chapter(Chapter {
-                    name: "a".to_string(),
-                    content: "## note / a".to_string(),
-                    number: Some(SectionNumber(vec![1, 1])),
-                    sub_items: vec![
-                        BookItem::Chapter(Chapter {
-                            name: "a1".to_string(),
-                            content: "## note / a / a1\n\ncontent a1".to_string(),
-                            number: Some(SectionNumber(vec![1, 1, 1])),
-                            sub_items: vec![],
-                            path: Some("a1".parse().unwrap()),
-                            source_path: None,
-                            parent_names: vec!["note".to_string(), "a".to_string()],
-                        }),
-                        BookItem::Chapter(Chapter {
-                            name: "a2".to_string(),
-                            content: "## note / a / a2\n\ncontent a2\n\ncontent a2 2".to_string(),
-                            number: Some(SectionNumber(vec![1, 1, 2])),
-                            sub_items: vec![],
-                            path: Some("a2".parse().unwrap()),
-                            source_path: None,
-                            parent_names: vec!["note".to_string(), "a".to_string()],
-                        }),
-                    ],
-                    path: Some("a".parse().unwrap()),
-                    source_path: None,
-                    parent_names: vec!["note".to_string()],
-                }),
-                BookItem::Chapter(Chapter {
-                    name: "b".to_string(),
-                    content: "## note / b\n\ncontent b".to_string(),
-                    number: Some(SectionNumber(vec![1, 2])),
-                    sub_items: vec![],
-                    path: Some("b".parse().unwrap()),
-                    source_path: None,
-                    parent_names: vec!["note".to_string()],
-                }),
-            ],
-            path: Some("note".parse().unwrap()),
-            source_path: None,
-            parent_names: vec![],
-        };
+    fn test_process_chapter_directives() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "guest",
+            "# Guest\n\n<!-- mdbook-note: skip -->\n{{#note a}}verbatim{{#note end}}",
+            vec![],
+        ));
+        book.push_item(chapter(
+            "draft",
+            "<!--mdbook-note: clean-only-->\n{{#note a}}cleaned{{#note end}}",
+            vec![],
+        ));
+        book.push_item(chapter(
+            "regular",
+            "{{#note a}}collected{{#note end}}\n<!-- mdbook-note: skip -->",
+            vec![],
+        ));
+
+        let chapters = notes_chapters("[book]", &mut book).unwrap();
 
         assert_eq!(
-            generate_chapter(extracts, "note".to_string(), vec![], vec![1]),
-            chapter
-        )
+            chapters[0].sub_items,
+            vec![BookItem::Chapter(Chapter {
+                number: Some(SectionNumber(vec![1, 1])),
+                path: Some(PathBuf::from("note/a")),
+                parent_names: vec!["note".to_string()],
+                ..chapter("a", "## note / a\n\n### regular\n\ncollected", vec![])
+            })]
+        );
+
+        let mut expected = Book::new();
+        expected.push_item(chapter(
+            "guest",
+            "# Guest\n\n{{#note a}}verbatim{{#note end}}",
+            vec![],
+        ));
+        expected.push_item(chapter("draft", "cleaned", vec![]));
+        expected.push_item(chapter(
+            "regular",
+            "collected\n<!-- mdbook-note: skip -->",
+            vec![],
+        ));
+        assert_eq!(book, expected);
     }
 }
@@ -0,0 +1,825 @@
+use mdbook::book::{Book, Chapter};
+use mdbook::errors::Error;
+use mdbook::utils::id_from_content;
+use mdbook::BookItem;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use super::config::Layout;
+use super::generate::chapter_levels;
+use super::marker::{dedent, Marker};
+use super::util::{code_fences, escape_html, for_each_chapter_mut, location, page_link};
+use super::{Backlinks, Headings, Note};
+
+/// How a collected note is kept in its chapter.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(super) enum InlineStyle {
+    /// The body alone, as if the markers were not there.
+    Plain,
+    /// A blockquote starting with a label line of the key.
+    Blockquote,
+    /// A `<div>` with the key as its `data-note-key` attribute.
+    Div,
+    /// Nothing, the markers and body being removed with the blank lines
+    /// around them.
+    Remove,
+    /// A link to the generated chapter of each key, `inline-link-text` being
+    /// its text, or the body when the notes chapters are not generated.
+    Link,
+}
+
+/// Note ids with the body and location of their note.
+pub(super) type Ids = HashMap<String, (String, String)>;
+
+/// Lines of the notes of a book, keyed on their source chapter, folded key
+/// and body, in the order of the book.
+type Lines = HashMap<(Option<PathBuf>, Vec<String>, String), VecDeque<usize>>;
+
+/// Highest number of the `#note-N` anchors of a chapter, the deep links of
+/// the markers cleaned before, so that the ones of the next marker follow
+/// them on the same page.
+pub(super) fn deep_link_offset(content: &str) -> usize {
+    content
+        .match_indices("<a id=\"note-")
+        .filter_map(|(start, prefix)| {
+            let rest = &content[start + prefix.len()..];
+            rest.split_once("\"></a>")?.0.parse::<usize>().ok()
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// HTML anchor of a note with an `id` attribute, so `#note-{id}` links to it.
+pub(super) fn anchor(id: &str) -> String {
+    format!("<a id=\"note-{}\"></a>", id)
+}
+
+/// Ids mdbook gives to the headings of a generated chapter and of its sub
+/// chapters, keyed on their link for the ones which are a link, as the
+/// headings of the source chapters, or on their text.
+pub(super) fn heading_ids(chapter: &Chapter, headings: &mut Headings) {
+    if let Some(path) = &chapter.path {
+        let fences = code_fences(&chapter.content);
+        // mdbook suffixes the repeated ids of a page with their count
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        let mut ids = HashMap::new();
+        let mut offset = 0;
+
+        for line in chapter.content.split_inclusive('\n') {
+            let start = offset;
+            offset += line.len();
+            if fences.iter().any(|fence| fence.contains(&start)) {
+                continue;
+            }
+            let text = line.trim_start_matches('#');
+            let level = line.len() - text.len();
+            if !(1..=6).contains(&level) || !text.starts_with(' ') {
+                continue;
+            }
+
+            // the heading as rendered to HTML, which mdbook makes its id from
+            let text = text.trim();
+            let link = text
+                .strip_prefix('[')
+                .and_then(|text| text.strip_suffix(')'))
+                .and_then(|text| text.rsplit_once("]("));
+            let html = match link {
+                Some((name, href)) => format!("<a href=\"{}\">{}</a>", href, escape_html(name)),
+                None => escape_html(text),
+            };
+            let id = id_from_content(&html);
+            let count = counts.entry(id.clone()).or_insert(0);
+            let id = match *count {
+                0 => id,
+                count => format!("{}-{}", id, count),
+            };
+            *count += 1;
+
+            let key = link.map_or(text, |(_, href)| href);
+            ids.entry(key.to_string()).or_insert(id);
+        }
+        headings.insert(path.clone(), ids);
+    }
+
+    for item in chapter.sub_items.iter() {
+        if let BookItem::Chapter(sub_chapter) = item {
+            heading_ids(sub_chapter, headings);
+        }
+    }
+}
+
+/// Fragment of a link from a chapter to its heading in the generated `page`,
+/// a link to the chapter or its name for a draft, empty when the page has
+/// none.
+fn heading_fragment(chapter: &Chapter, page: &Path, headings: &Headings) -> String {
+    let root = "../".repeat(page.components().count().saturating_sub(1));
+    let key = match &chapter.path {
+        Some(source) => format!("{}{}", root, page_link(source)),
+        None => chapter.name.clone(),
+    };
+
+    headings
+        .get(page)
+        .and_then(|ids| ids.get(&key))
+        .map(|id| format!("#{}", id))
+        .unwrap_or_default()
+}
+
+impl Note {
+    /// Lines of the notes of the book, which should be the book before any
+    /// marker is cleaned.
+    pub(super) fn note_lines(&self, book: &mut Book) -> Result<Lines, Error> {
+        let mut lines: Lines = HashMap::new();
+
+        for_each_chapter_mut(&mut book.sections, &mut |chapter| {
+            for extract in self.parse_chapter(chapter) {
+                if let Some(line) = extract.line {
+                    let key = extract.key.iter().map(|k| self.fold(k)).collect();
+                    lines
+                        .entry((extract.source, key, extract.val))
+                        .or_default()
+                        .push_back(line);
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(lines)
+    }
+
+    /// Map the keys of a generated chapter and of its sub chapters, `key`
+    /// being its folded levels from the top one, to their title and path.
+    pub(super) fn backlink_targets(
+        &self,
+        chapter: &Chapter,
+        key: Vec<String>,
+        layout: &Layout,
+        backlinks: &mut Backlinks,
+    ) {
+        let mut title = chapter.parent_names.clone();
+        title.push(chapter.name.clone());
+
+        if let Some(path) = &chapter.path {
+            backlinks.insert(key.clone(), (title.join(" / "), path.clone()));
+        }
+
+        for item in chapter.sub_items.iter() {
+            if let BookItem::Chapter(sub_chapter) = item {
+                let mut sub_key = key.clone();
+                let depth = chapter.parent_names.len() + 1;
+                for level in chapter_levels(sub_chapter, depth, layout) {
+                    sub_key.push(self.fold(&level));
+                }
+                self.backlink_targets(sub_chapter, sub_key, layout, backlinks);
+            }
+        }
+    }
+
+    /// Replace every block by its body, wrapped in a `<div>` for local notes
+    /// or by nothing for hidden notes, drop the orphan end markers and the
+    /// exclude markers and unescape the escaped markers. A collected note is
+    /// followed by a link to the generated chapter of each of its keys found
+    /// in `backlinks`.
+    pub(super) fn clean_chapter(
+        &self,
+        chapter: &mut Chapter,
+        backlinks: &Backlinks,
+        headings: &Headings,
+    ) {
+        let root = match &chapter.path {
+            Some(path) => "../".repeat(path.components().count().saturating_sub(1)),
+            None => String::new(),
+        };
+        let content = chapter.content.as_str();
+        let scan = self.scan(content);
+        let offset = deep_link_offset(content);
+
+        let mut edits: Vec<(Range<usize>, String)> = vec![];
+        // removed notes leave no blank lines nor double spaces behind
+        let mut removed: HashSet<usize> = HashSet::new();
+        for (index, block) in scan.blocks.iter().enumerate() {
+            let marker = self.parse_marker(&block.key);
+            if self.inline_style == InlineStyle::Remove && !self.is_local(block, &marker) {
+                edits.push((block.span.clone(), String::new()));
+                removed.insert(block.span.start);
+                continue;
+            }
+            let replacement = if self.is_hidden(&marker) {
+                String::new()
+            } else if self.is_local(block, &marker) {
+                format!(
+                    "<div class=\"{}\">\n\n{}\n\n</div>",
+                    self.local_class,
+                    scan.text(content, block.body.clone()).trim()
+                )
+            } else if self.inline_style == InlineStyle::Link {
+                match self.note_links(&marker, chapter, &root, backlinks, headings) {
+                    links if links.is_empty() => scan.text(content, block.body.clone()),
+                    links => links.join("\n\n"),
+                }
+            } else {
+                self.inline(&marker, scan.text(content, block.body.clone()))
+            };
+            let replacement = match marker.attributes.get("id") {
+                Some(id) if !self.is_hidden(&marker) => format!("{}{}", anchor(id), replacement),
+                _ => replacement,
+            };
+            let replacement = if self.is_hidden(&marker)
+                || self.is_local(block, &marker)
+                || self.inline_style == InlineStyle::Link
+            {
+                replacement
+            } else {
+                let mut replacement = replacement;
+                for key in marker.keys.iter() {
+                    let mut levels = self.levels(key);
+                    if levels.is_empty() {
+                        levels.extend(self.uncategorized.clone());
+                    }
+                    let levels: Vec<String> = levels.iter().map(|l| self.fold(l)).collect();
+
+                    if let Some((title, page)) = backlinks.get(&levels) {
+                        replacement = format!(
+                            "{}\n\n[{}]({}{}{})",
+                            replacement,
+                            self.backlink_text.replace("{chapter}", title),
+                            root,
+                            page_link(page),
+                            heading_fragment(chapter, page, headings)
+                        );
+                    }
+                }
+                replacement
+            };
+            let replacement = if self.deep_links && !self.is_local(block, &marker) {
+                format!(
+                    "{}{}",
+                    anchor(&(offset + index + 1).to_string()),
+                    replacement
+                )
+            } else {
+                replacement
+            };
+            edits.push((block.span.clone(), replacement));
+        }
+        for orphan in scan.orphans.iter() {
+            edits.push((orphan.clone(), String::new()));
+        }
+        for marker in scan.exclude_markers.iter() {
+            edits.push((marker.clone(), String::new()));
+        }
+        if self.strip_excluded {
+            for marker in scan.excluded.iter() {
+                edits.push((marker.clone(), String::new()));
+            }
+        }
+        for &escape in scan.escapes.iter() {
+            if !scan.blocks.iter().any(|b| b.span.contains(&escape)) {
+                edits.push((escape..escape + 1, String::new()));
+            }
+        }
+        edits.sort_by_key(|(span, _)| span.start);
+
+        let mut new_content = String::new();
+        let mut last = 0;
+        // end of the text dropped with the last removed note, whose edits
+        // are dropped with it
+        let mut removed_end = 0;
+
+        for (span, replacement) in edits {
+            if span.start < removed_end {
+                continue;
+            }
+            new_content.push_str(&content[last..span.start]);
+            new_content.push_str(&replacement);
+            last = span.end;
+
+            if removed.contains(&span.start) {
+                while new_content.is_empty() || new_content.ends_with("\n\n") {
+                    match content[last..].find('\n') {
+                        Some(end) if content[last..last + end].trim().is_empty() => last += end + 1,
+                        _ => break,
+                    }
+                }
+                let line_start = new_content.is_empty() || new_content.ends_with('\n');
+                if (line_start || new_content.ends_with(' ')) && content[last..].starts_with(' ') {
+                    last += 1;
+                }
+                removed_end = last;
+            }
+        }
+        new_content.push_str(&content[last..]);
+
+        chapter.content = new_content;
+    }
+
+    /// Links of `inline-style = "link"` from a note to the generated chapters
+    /// of its keys found in `backlinks`.
+    fn note_links(
+        &self,
+        marker: &Marker,
+        chapter: &Chapter,
+        root: &str,
+        backlinks: &Backlinks,
+        headings: &Headings,
+    ) -> Vec<String> {
+        let mut links = vec![];
+
+        for key in marker.keys.iter() {
+            let mut levels = self.levels(key);
+            if levels.is_empty() {
+                levels.extend(self.uncategorized.clone());
+            }
+            let folded: Vec<String> = levels.iter().map(|l| self.fold(l)).collect();
+
+            if let Some((_, page)) = backlinks.get(&folded) {
+                let text = self
+                    .inline_link_text
+                    .replace("{key}", &levels.join(" / "))
+                    .replace("{title}", marker.title.as_deref().unwrap_or(""));
+                links.push(format!(
+                    "[{}]({}{}{})",
+                    text.trim(),
+                    root,
+                    page_link(page),
+                    heading_fragment(chapter, page, headings)
+                ));
+            }
+        }
+
+        links
+    }
+
+    /// Body of a collected note as kept in its chapter, with `inline-style`.
+    fn inline(&self, marker: &Marker, body: String) -> String {
+        let key = escape_html(&marker.keys.join(", "));
+
+        match self.inline_style {
+            InlineStyle::Plain | InlineStyle::Remove | InlineStyle::Link => body,
+            InlineStyle::Blockquote => {
+                let mut lines = vec![];
+                if !key.is_empty() {
+                    lines.push(format!(
+                        "> <small class=\"{}\" data-note-key=\"{}\">{}</small>",
+                        self.inline_class, key, key
+                    ));
+                    lines.push(">".to_string());
+                }
+                for line in body.trim().lines() {
+                    lines.push(format!("> {}", line).trim_end().to_string());
+                }
+
+                format!("\n\n{}\n\n", lines.join("\n"))
+            }
+            InlineStyle::Div => {
+                let key = match key.is_empty() {
+                    true => String::new(),
+                    false => format!(" data-note-key=\"{}\"", key),
+                };
+
+                format!(
+                    "<div class=\"{}\"{}>\n\n{}\n\n</div>",
+                    self.inline_class,
+                    key,
+                    body.trim()
+                )
+            }
+        }
+    }
+
+    /// Collect the bodies of the notes with an `id` attribute, with their
+    /// location, failing when an id is used twice. Escaped markers are kept
+    /// escaped as the bodies are inserted before cleaning.
+    pub(super) fn collect_ids(&self, chapter: &Chapter, ids: &mut Ids) -> Result<(), Error> {
+        let scan = self.scan(&chapter.content);
+
+        for block in scan.blocks.iter() {
+            if let Some(id) = self.parse_marker(&block.key).attributes.get("id") {
+                let here = location(chapter, block.span.start);
+
+                if let Some((_, first)) = ids.get(id) {
+                    return Err(Error::msg(format!(
+                        "duplicate note id {} in {}, already used in {}",
+                        id, here, first
+                    )));
+                }
+
+                let body = chapter.content[block.body.clone()].replace("\r\n", "\n");
+                ids.insert(id.clone(), (dedent(&body).trim().to_string(), here));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace every `{{#note-ref id}}` by the body of the note with this id.
+    fn resolve_refs(&self, chapter: &mut Chapter, ids: &Ids) -> Result<(), Error> {
+        self.check_refs(chapter, ids)?;
+
+        let scan = self.scan(&chapter.content);
+        for (marker, id) in scan.refs.iter().rev() {
+            if let Some((body, _)) = ids.get(id) {
+                chapter.content.replace_range(marker.clone(), body);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fail on the first `{{#note-ref id}}` of a chapter without a note of
+    /// this id.
+    pub(super) fn check_refs(&self, chapter: &Chapter, ids: &Ids) -> Result<(), Error> {
+        for (marker, id) in self.scan(&chapter.content).refs.iter() {
+            if !ids.contains_key(id) {
+                let mut known: Vec<&String> = ids.keys().collect();
+                known.sort();
+
+                return Err(Error::msg(format!(
+                    "unknown note id {} referenced in {}, known ids are: {}",
+                    id,
+                    location(chapter, marker.start),
+                    known
+                        .iter()
+                        .map(|id| id.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Clean every chapter, resolving the note references and linking the
+    /// notes to their `backlinks` targets, at the heading of their chapter
+    /// found in `headings`.
+    pub(super) fn clean_book(
+        &self,
+        book: &mut Book,
+        ids: &Ids,
+        backlinks: &Backlinks,
+        headings: &Headings,
+    ) -> Result<(), Error> {
+        for_each_chapter_mut(&mut book.sections, &mut |chapter| {
+            if self.is_skipped(chapter) {
+                return Ok(());
+            }
+            self.resolve_refs(chapter, ids)?;
+            self.clean_chapter(chapter, backlinks, headings);
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note::fixture::{chapter, configs, notes_chapters, preprocessor};
+    use crate::note::generate::{generate_chapter, note_chapters};
+    use mdbook::book::SectionNumber;
+
+    #[test]
+    fn test_clean_inline_style() {
+        let chapter = chapter(
+            "some name",
+            "before\n\n{{#note a|b}}\nfirst\n\nsecond\n{{#note end}}\n\nafter",
+            vec![],
+        );
+
+        let note = Note {
+            inline_style: InlineStyle::Blockquote,
+            ..Note::new()
+        };
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+        assert_eq!(
+            cleaned.content,
+            "before\n\n\n\n> <small class=\"note\" data-note-key=\"a|b\">a|b</small>\n>\n\
+             > first\n>\n> second\n\n\n\nafter"
+        );
+
+        let note = Note {
+            inline_style: InlineStyle::Div,
+            inline_class: "aside".to_string(),
+            ..Note::new()
+        };
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+        assert_eq!(
+            cleaned.content,
+            "before\n\n<div class=\"aside\" data-note-key=\"a|b\">\n\nfirst\n\nsecond\n\n</div>\n\nafter"
+        );
+
+        let note = Note {
+            inline_style: InlineStyle::Remove,
+            ..Note::new()
+        };
+        let mut cleaned = Chapter {
+            content: "{{#note a}}x{{#note end}}\n\nbefore\n\n{{#note a}}\nfirst\n{{#note end}}\n\n\
+                      {{#note!}}local{{#note end}}\n\n{{#note b}}y{{#note end}}\n\nafter {{#note c}}z{{#note end}} end"
+                .to_string(),
+            ..chapter
+        };
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+        assert_eq!(
+            cleaned.content,
+            "before\n\n<div class=\"note\">\n\nlocal\n\n</div>\n\nafter end"
+        );
+        cleaned.content = "{{#note a}}x{{#note end}} starts\\{{#note}}".to_string();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+        assert_eq!(cleaned.content, "starts{{#note}}");
+
+        assert_eq!(
+            preprocessor("[preprocessor.note]\ninline-style = \"callout\"").err().unwrap().to_string(),
+            "preprocessor.note.inline-style must be \"plain\", \"blockquote\", \"div\", \"remove\" or \"link\", found \"callout\""
+        );
+    }
+
+    #[test]
+    fn test_process_note_refs() {
+        let mut book = Book::new();
+        book.push_item(chapter("first", "see {{#note-ref monad}} here", vec![]));
+        book.push_item(chapter(
+            "second",
+            "{{#note glossary id=monad}}
+    A monoid.
+{{#note end}} `{{#note-ref code}}` \\{{#note-ref escaped}}",
+            vec![],
+        ));
+
+        let note = Note::new();
+        note.process_book(&mut book, false).unwrap();
+
+        let mut expected = Book::new();
+        expected.push_item(chapter("first", "see A monoid. here", vec![]));
+        expected.push_item(chapter(
+            "second",
+            "<a id=\"note-monad\"></a>\n    A monoid.\n `{{#note-ref code}}` {{#note-ref escaped}}",
+            vec![],
+        ));
+
+        assert_eq!(book, expected);
+
+        let mut book = Book::new();
+        book.push_item(chapter("first", "{{#note a id=x}}a{{#note end}}", vec![]));
+        book.push_item(chapter("second", "{{#note-ref y}}", vec![]));
+
+        assert_eq!(
+            note.process_book(&mut book, false).unwrap_err().to_string(),
+            "unknown note id y referenced in chapter \"second\" line 1, known ids are: x"
+        );
+    }
+
+    #[test]
+    fn test_process_note_ids() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "first",
+            "{{#note limits id=rate-limits}}Slow down.{{#note end}}",
+            vec![],
+        ));
+
+        let note = Note::new();
+        let extracts = note.process_book(&mut book, false).unwrap();
+
+        let mut expected = Book::new();
+        expected.push_item(chapter(
+            "first",
+            "<a id=\"note-rate-limits\"></a>Slow down.",
+            vec![],
+        ));
+        assert_eq!(book, expected);
+
+        let generated = generate_chapter(
+            extracts,
+            "note".to_string(),
+            "note".to_string(),
+            vec![],
+            vec![1],
+            &Layout::default(),
+        )
+        .unwrap();
+        match &generated.sub_items[0] {
+            BookItem::Chapter(limits) => assert_eq!(
+                limits.content,
+                "## note / limits\n\n### first\n\n<a id=\"note-rate-limits\"></a>\n\nSlow down."
+            ),
+            _ => unreachable!(),
+        }
+
+        let mut book = Book::new();
+        book.push_item(chapter("first", "{{#note a id=x}}a{{#note end}}", vec![]));
+        book.push_item(chapter(
+            "second",
+            "\n{{#note b id=x}}b{{#note end}}",
+            vec![],
+        ));
+
+        assert_eq!(
+            note.process_book(&mut book, false).unwrap_err().to_string(),
+            "duplicate note id x in chapter \"second\" line 2, already used in chapter \"first\" line 1"
+        );
+    }
+
+    #[test]
+    fn test_process_deep_links() {
+        let mut first = chapter(
+            "first",
+            "{{#note! aside}}local{{#note end}} {{#note a}}one{{#note end}} {{#note a hidden}}two{{#note end}}",
+            vec![],
+        );
+        first.path = Some(PathBuf::from("part/first.md"));
+        let mut book = Book::new();
+        book.push_item(first);
+
+        let note = Note {
+            deep_links: true,
+            ..Note::new()
+        };
+        let extracts = note.process_book(&mut book, false).unwrap();
+
+        match &book.sections[0] {
+            BookItem::Chapter(c) => assert_eq!(
+                c.content,
+                "<div class=\"note\">\n\nlocal\n\n</div> <a id=\"note-2\"></a>one <a id=\"note-3\"></a>"
+            ),
+            _ => unreachable!(),
+        }
+
+        let generated = generate_chapter(
+            extracts,
+            "note".to_string(),
+            "note".to_string(),
+            vec![],
+            vec![1],
+            &Layout::default(),
+        )
+        .unwrap();
+        match &generated.sub_items[0] {
+            BookItem::Chapter(a) => assert_eq!(
+                a.content,
+                "## note / a\n\n### [first](../part/first.html)\n\none\n\n[↩](../part/first.html#note-2)\n\ntwo\n\n[↩](../part/first.html#note-3)"
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_deep_links_markers() {
+        let mut first = chapter(
+            "first",
+            "{{#note a}}one{{#note end}} {{#todo b}}two{{#todo end}} {{#note a}}three{{#note end}}",
+            vec![],
+        );
+        first.path = Some(PathBuf::from("first.md"));
+        let mut book = Book::new();
+        book.push_item(first);
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+deep-links = true
+
+[preprocessor.note.markers]
+note = \"Notes\"
+todo = \"TODO\"",
+            &mut book,
+        )
+        .unwrap();
+
+        match &book.sections[0] {
+            BookItem::Chapter(c) => assert_eq!(
+                c.content,
+                "<a id=\"note-1\"></a>one <a id=\"note-3\"></a>two <a id=\"note-2\"></a>three"
+            ),
+            _ => unreachable!(),
+        }
+        let mut contents = vec![];
+        let mut items: Vec<BookItem> = chapters.into_iter().map(BookItem::Chapter).collect();
+        for_each_chapter_mut(&mut items, &mut |chapter| {
+            contents.push(chapter.content.clone());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            contents,
+            vec![
+                "## Notes",
+                "## Notes / a\n\n### [first](../first.html)\n\none\n\n[↩](../first.html#note-1)\n\nthree\n\n[↩](../first.html#note-2)",
+                "## TODO",
+                "## TODO / b\n\n### [first](../first.html)\n\ntwo\n\n[↩](../first.html#note-3)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_backlinks() {
+        let mut first = chapter(
+            "Tuning guide",
+            "{{#note engineering|performance}}cache it{{#note end}} {{#note a hidden}}gone{{#note end}}",
+            vec![],
+        );
+        first.path = Some(PathBuf::from("part/tuning.md"));
+        let mut book = Book::new();
+        book.push_item(first);
+
+        let (config, note_config) = configs(
+            "[preprocessor.note]
+name = \"Notes\"
+backlinks = true",
+        );
+        note_chapters(&config, &note_config, &mut book).unwrap();
+
+        match &book.sections[0] {
+            BookItem::Chapter(c) => assert_eq!(
+                c.content,
+                "cache it\n\n[↪ collected in Notes / engineering / performance](../notes/engineering/performance.html#a-hrefparttuninghtmltuning-guidea) "
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_backlinks_heading_ids() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            number: Some(SectionNumber(vec![3, 4])),
+            path: Some(PathBuf::from("one/exercises.md")),
+            ..chapter("Exercises", "{{#note a}}x{{#note end}}", vec![])
+        });
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("two/exercises.md")),
+            ..chapter("Exercises", "{{#note a}}y{{#note end}}", vec![])
+        });
+        book.push_item(Chapter {
+            number: Some(SectionNumber(vec![5])),
+            path: Some(PathBuf::from("setup.md")),
+            ..chapter("Setup -- Rust & C", "{{#note a}}z{{#note end}}", vec![])
+        });
+
+        let (config, note_config) = configs(
+            "[preprocessor.note]
+backlinks = true
+show-section-numbers = true",
+        );
+        note_chapters(&config, &note_config, &mut book).unwrap();
+
+        let contents: Vec<String> = book
+            .sections
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(c) => Some(c.content.clone()),
+                _ => None,
+            })
+            .collect();
+        // mdbook makes the ids from the HTML of the headings, their link
+        // included, keeping the runs of dashes
+        assert_eq!(
+            contents,
+            vec![
+                "x\n\n[↪ collected in note / a](../note/a.html#a-hrefoneexerciseshtml34-exercisesa)",
+                "y\n\n[↪ collected in note / a](../note/a.html#a-hreftwoexerciseshtmlexercises-twoexercisesmda)",
+                "z\n\n[↪ collected in note / a](note/a.html#a-hrefsetuphtml5-setup----rust--ca)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_inline_link() {
+        let mut first = chapter(
+            "Tuning guide",
+            "{{#note performance|caching :: Warm up}}\nlong note\n{{#note end}}",
+            vec![],
+        );
+        first.path = Some(PathBuf::from("part/tuning.md"));
+        first.number = Some(SectionNumber(vec![2, 1]));
+        let mut book = Book::new();
+        book.push_item(first);
+        let content = |toml: &str| -> String {
+            let (config, note_config) = configs(toml);
+            let mut book = book.clone();
+            note_chapters(&config, &note_config, &mut book).unwrap();
+            match &book.sections[0] {
+                BookItem::Chapter(c) => c.content.clone(),
+                _ => unreachable!(),
+            }
+        };
+        let config = "[preprocessor.note]
+title = \"Notes\"
+inline-style = \"link\"
+inline-link-text = \"See {title} in {key}\"";
+
+        assert_eq!(
+            content(config),
+            "[See Warm up in performance / caching](../notes/performance/caching.html#a-hrefparttuninghtmltuning-guidea)"
+        );
+        // the heading starts with the section number of the chapter
+        assert_eq!(
+            content(&format!("{}\nshow-section-numbers = true", config)),
+            "[See Warm up in performance / caching](../notes/performance/caching.html#a-hrefparttuninghtml21-tuning-guidea)"
+        );
+    }
+}
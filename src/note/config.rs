@@ -0,0 +1,1923 @@
+use glob::Pattern;
+use handlebars::Template;
+use log::warn;
+use mdbook::errors::Error;
+use mdbook::preprocess::PreprocessorContext;
+use mdbook::Config;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use super::clean::InlineStyle;
+use super::generate::{chapter_path, collator};
+use super::marker::{almost_regex, exclude_regex, ref_regex, syntax_regex};
+use super::Note;
+
+impl Note {
+    pub fn new() -> Note {
+        Note {
+            marker: "note".to_string(),
+            syntaxes: vec!["braces".to_string()],
+            regexes: vec![syntax_regex("braces", "note").unwrap()],
+            exclude_regex: exclude_regex("note"),
+            ref_regex: ref_regex("note"),
+            almost_regex: almost_regex("note"),
+            strip_excluded: false,
+            strict: false,
+            key_separator: '|',
+            hidden_by_default: false,
+            local_class: "note".to_string(),
+            inline_style: InlineStyle::Plain,
+            inline_class: "note".to_string(),
+            inline_link_text: "📝 See note: {key}".to_string(),
+            paragraph_mode: false,
+            normalize_keys: false,
+            aliases: HashMap::new(),
+            uncategorized: None,
+            max_depth: None,
+            collect_hashtags: false,
+            dedupe: false,
+            deep_links: false,
+            backlinks: false,
+            backlink_text: "↪ collected in {chapter}".to_string(),
+            only_keys: None,
+            exclude_keys: vec![],
+            include: None,
+            ignore: vec![],
+            order: vec![],
+            order_default: 0,
+            provenance: false,
+            read_more: false,
+            descriptions: vec![],
+            icons: vec![],
+            glossaries: vec![],
+        }
+    }
+
+    /// Build the preprocessor from the `[preprocessor.note]` table of the book.
+    pub fn from_config(config: &Config) -> Result<Note, Error> {
+        let mut note = Note::new();
+        let mut marker = note.marker.clone();
+
+        if let Some(nop_cfg) = config.get_preprocessor("note") {
+            match nop_cfg.get("marker") {
+                None => {}
+                Some(value) => {
+                    marker = config_str("marker", value)?;
+                }
+            }
+            match nop_cfg.get("strict") {
+                None => {}
+                Some(value) => {
+                    note.strict = config_bool("strict", value)?;
+                }
+            }
+            match nop_cfg.get("key-separator") {
+                None => {}
+                Some(value) => {
+                    note.key_separator = match value.as_str() {
+                        Some("|") => '|',
+                        Some("/") => '/',
+                        _ => {
+                            return Err(Error::msg(format!(
+                                "preprocessor.note.key-separator must be \"|\" or \"/\", found {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+            }
+            match nop_cfg.get("hidden-by-default") {
+                None => {}
+                Some(value) => {
+                    note.hidden_by_default = config_bool("hidden-by-default", value)?;
+                }
+            }
+            match nop_cfg.get("local-class") {
+                None => {}
+                Some(value) => {
+                    note.local_class = config_str("local-class", value)?;
+                }
+            }
+            match nop_cfg.get("inline-style") {
+                None => {}
+                Some(value) => {
+                    note.inline_style = match value.as_str() {
+                        Some("plain") => InlineStyle::Plain,
+                        Some("blockquote") => InlineStyle::Blockquote,
+                        Some("div") => InlineStyle::Div,
+                        Some("remove") => InlineStyle::Remove,
+                        Some("link") => InlineStyle::Link,
+                        _ => {
+                            return Err(Error::msg(format!(
+                                "preprocessor.note.inline-style must be \"plain\", \"blockquote\", \"div\", \"remove\" or \"link\", found {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+            }
+            match nop_cfg.get("inline-class") {
+                None => {}
+                Some(value) => {
+                    note.inline_class = config_str("inline-class", value)?;
+                }
+            }
+            match nop_cfg.get("inline-link-text") {
+                None => {}
+                Some(value) => {
+                    note.inline_link_text = config_str("inline-link-text", value)?;
+                }
+            }
+            match nop_cfg.get("paragraph-mode") {
+                None => {}
+                Some(value) => {
+                    note.paragraph_mode = config_bool("paragraph-mode", value)?;
+                }
+            }
+            match nop_cfg.get("normalize-keys") {
+                None => {}
+                Some(value) => {
+                    note.normalize_keys = match value.as_str() {
+                        Some("lowercase") => true,
+                        Some("preserve") => false,
+                        _ => {
+                            return Err(Error::msg(format!(
+                                "preprocessor.note.normalize-keys must be \"lowercase\" or \"preserve\", found {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+            }
+            match nop_cfg.get("strip-excluded") {
+                None => {}
+                Some(value) => {
+                    note.strip_excluded = config_bool("strip-excluded", value)?;
+                }
+            }
+            match nop_cfg.get("uncategorized") {
+                None => {}
+                Some(value) => {
+                    note.uncategorized = Some(config_str("uncategorized", value)?);
+                }
+            }
+            match nop_cfg.get("max-depth") {
+                None => {}
+                Some(value) => {
+                    note.max_depth = match value.as_integer() {
+                        Some(depth) if depth >= 1 => Some(depth as usize),
+                        _ => {
+                            return Err(Error::msg(format!(
+                                "preprocessor.note.max-depth must be a positive integer, found {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+            }
+            match nop_cfg.get("collect-hashtags") {
+                None => {}
+                Some(value) => {
+                    note.collect_hashtags = config_bool("collect-hashtags", value)?;
+                }
+            }
+            match nop_cfg.get("dedupe") {
+                None => {}
+                Some(value) => {
+                    note.dedupe = config_bool("dedupe", value)?;
+                }
+            }
+            match nop_cfg.get("aliases") {
+                None => {}
+                Some(value) => {
+                    let table = value.as_table().ok_or_else(|| {
+                        Error::msg(format!(
+                            "preprocessor.note.aliases must be a table, found {}",
+                            value.type_str()
+                        ))
+                    })?;
+                    for (alias, key) in table {
+                        let key = config_str(&format!("aliases.{}", alias), key)?;
+                        note.aliases.insert(alias.clone(), key);
+                    }
+                }
+            }
+            match nop_cfg.get("order") {
+                None => {}
+                Some(value) => {
+                    let table = value.as_table().ok_or_else(|| {
+                        Error::msg(format!(
+                            "preprocessor.note.order must be a table, found {}",
+                            value.type_str()
+                        ))
+                    })?;
+                    for (key, weight) in table {
+                        let weight = weight.as_integer().ok_or_else(|| {
+                            Error::msg(format!(
+                                "preprocessor.note.order.{} must be an integer, found {}",
+                                key,
+                                weight.type_str()
+                            ))
+                        })?;
+                        note.order.push((key.clone(), weight));
+                    }
+                }
+            }
+            match nop_cfg.get("order-default") {
+                None => {}
+                Some(value) => {
+                    note.order_default = value.as_integer().ok_or_else(|| {
+                        Error::msg(format!(
+                            "preprocessor.note.order-default must be an integer, found {}",
+                            value.type_str()
+                        ))
+                    })?;
+                }
+            }
+            match nop_cfg.get("provenance") {
+                None => {}
+                Some(value) => {
+                    note.provenance = config_bool("provenance", value)?;
+                }
+            }
+            note.read_more = nop_cfg.contains_key("max-inline-length")
+                || nop_cfg.contains_key("max-inline-lines");
+            match nop_cfg.get("descriptions") {
+                None => {}
+                Some(value) => {
+                    let table = value.as_table().ok_or_else(|| {
+                        Error::msg(format!(
+                            "preprocessor.note.descriptions must be a table, found {}",
+                            value.type_str()
+                        ))
+                    })?;
+                    for (key, description) in table {
+                        let description =
+                            config_str(&format!("descriptions.{}", key), description)?;
+                        note.descriptions.push((key.clone(), description));
+                    }
+                }
+            }
+            match nop_cfg.get("icons") {
+                None => {}
+                Some(value) => {
+                    let table = value.as_table().ok_or_else(|| {
+                        Error::msg(format!(
+                            "preprocessor.note.icons must be a table, found {}",
+                            value.type_str()
+                        ))
+                    })?;
+                    for (key, icon) in table {
+                        let icon = config_str(&format!("icons.{}", key), icon)?;
+                        note.icons.push((key.clone(), icon));
+                    }
+                }
+            }
+            match nop_cfg.get("key-modes") {
+                None => {}
+                Some(value) => {
+                    let table = value.as_table().ok_or_else(|| {
+                        Error::msg(format!(
+                            "preprocessor.note.key-modes must be a table, found {}",
+                            value.type_str()
+                        ))
+                    })?;
+                    for (key, mode) in table {
+                        match mode.as_str() {
+                            Some("glossary") => note.glossaries.push(key.clone()),
+                            Some("notes") => {}
+                            _ => {
+                                return Err(Error::msg(format!(
+                                    "preprocessor.note.key-modes.{} must be \"notes\" or \"glossary\", found {}",
+                                    key, mode
+                                )))
+                            }
+                        }
+                    }
+                }
+            }
+            match nop_cfg.get("deep-links") {
+                None => {}
+                Some(value) => {
+                    note.deep_links = config_bool("deep-links", value)?;
+                }
+            }
+            match nop_cfg.get("backlinks") {
+                None => {}
+                Some(value) => {
+                    note.backlinks = config_bool("backlinks", value)?;
+                }
+            }
+            match nop_cfg.get("backlink-text") {
+                None => {}
+                Some(value) => {
+                    note.backlink_text = config_str("backlink-text", value)?;
+                }
+            }
+            match nop_cfg.get("only-keys") {
+                None => {}
+                Some(value) => {
+                    note.only_keys = Some(config_strings("only-keys", value)?);
+                }
+            }
+            match nop_cfg.get("exclude-keys") {
+                None => {}
+                Some(value) => {
+                    note.exclude_keys = config_strings("exclude-keys", value)?;
+                }
+            }
+            match nop_cfg.get("include") {
+                None => {}
+                Some(value) => {
+                    note.include = Some(config_patterns("include", value)?);
+                }
+            }
+            match nop_cfg.get("ignore") {
+                None => {}
+                Some(value) => {
+                    note.ignore = config_patterns("ignore", value)?;
+                }
+            }
+            match nop_cfg.get("syntax") {
+                None => {}
+                Some(value) => {
+                    note.syntaxes = vec![];
+                    for syntax in config_strings("syntax", value)? {
+                        match syntax.as_str() {
+                            "braces" | "comments" => note.syntaxes.push(syntax),
+                            _ => {
+                                return Err(Error::msg(format!(
+                                    "preprocessor.note.syntax must only contain \"braces\" or \"comments\", found {:?}",
+                                    syntax
+                                )))
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        note.set_marker(&marker)?;
+
+        Ok(note)
+    }
+
+    /// Use another marker word, `todo` matching `{{#todo key}}`.
+    pub(super) fn set_marker(&mut self, marker: &str) -> Result<(), Error> {
+        if marker.is_empty()
+            || !marker
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(Error::msg(format!(
+                "note marker must only contain letters, digits, \"-\" or \"_\", found {:?}",
+                marker
+            )));
+        }
+
+        self.marker = marker.to_string();
+        self.regexes = self
+            .syntaxes
+            .iter()
+            .map(|syntax| syntax_regex(syntax, marker).unwrap())
+            .collect();
+        self.exclude_regex = exclude_regex(marker);
+        self.ref_regex = ref_regex(marker);
+        self.almost_regex = almost_regex(marker);
+
+        Ok(())
+    }
+}
+
+/// Options accepted in `[preprocessor.note]`, the first ones being read by
+/// mdbook itself.
+const CONFIG_KEYS: [&str; 93] = [
+    "command",
+    "renderers",
+    "before",
+    "after",
+    "marker",
+    "name",
+    "title",
+    "path",
+    "intro",
+    "intro-file",
+    "template",
+    "chapter-template",
+    "target-chapter",
+    "cleanup_only",
+    "markers",
+    "strings",
+    "mode",
+    "collect-renderers",
+    "generate-for",
+    "clean-other-renderers",
+    "fail-on-zero-notes",
+    "min-notes",
+    "section",
+    "numbered",
+    "layout",
+    "table-source",
+    "collapsible",
+    "html-wrappers",
+    "entry-style",
+    "provenance",
+    "provenance-template",
+    "toc",
+    "list-children",
+    "collapse-single-chains",
+    "max-chapter-depth",
+    "pinned-prefix",
+    "chapter-index",
+    "stats",
+    "rollup",
+    "git-dates",
+    "show-counts",
+    "count-descendants",
+    "split-top-level",
+    "per-part",
+    "group-by",
+    "path-prefix",
+    "insert-after",
+    "insert-before",
+    "quiet",
+    "breadcrumb",
+    "breadcrumb-separator",
+    "breadcrumb-skip-root",
+    "breadcrumb-level",
+    "source-heading-level",
+    "source-headings",
+    "note-separator",
+    "entry-separator",
+    "show-section-numbers",
+    "shift-headings",
+    "max-inline-length",
+    "max-inline-lines",
+    "sort",
+    "collation",
+    "strict",
+    "key-separator",
+    "hidden-by-default",
+    "local-class",
+    "inline-style",
+    "inline-class",
+    "inline-link-text",
+    "paragraph-mode",
+    "syntax",
+    "normalize-keys",
+    "uncategorized",
+    "max-depth",
+    "strip-excluded",
+    "collect-hashtags",
+    "dedupe",
+    "aliases",
+    "order",
+    "order-default",
+    "descriptions",
+    "icons",
+    "icons-in-breadcrumb",
+    "key-modes",
+    "deep-links",
+    "backlinks",
+    "backlink-text",
+    "only-keys",
+    "exclude-keys",
+    "include",
+    "ignore",
+    "allow-unknown-config",
+];
+
+/// What a run does with the notes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(super) enum Mode {
+    /// Notes collected into the notes chapters and cleaned from the chapters.
+    Aggregate,
+    /// Markers removed from the chapters, without generating the notes
+    /// chapters.
+    CleanOnly,
+    /// Notes collected into the notes chapters, the chapters being left as
+    /// they are.
+    CollectOnly,
+}
+
+/// Options of the whole run, the other ones being read per marker by
+/// `Note::from_config`.
+#[derive(Debug, PartialEq, Clone)]
+pub(super) struct NoteConfig {
+    /// Marker words with the name of the chapter generated for each of them.
+    pub(super) markers: Vec<(String, String)>,
+    /// Mode of the renderers without a mode of their own.
+    pub(super) mode: Mode,
+    /// Mode of the renderers listed in a `mode` table.
+    pub(super) renderer_modes: HashMap<String, Mode>,
+    /// Renderers for which the notes are collected, all of them when unset,
+    /// set by `collect-renderers` or `generate-for`.
+    pub(super) renderers: Option<Vec<String>>,
+    /// Whether the other renderers get the chapters cleaned of their markers.
+    pub(super) clean_other_renderers: bool,
+    /// Number of notes under which the build fails, to catch renamed markers.
+    pub(super) min_notes: usize,
+    /// Whether the summary of the collected notes is not logged.
+    pub(super) quiet: bool,
+    /// Section number of the first generated chapter, the one following the
+    /// last numbered chapter of the book when unset.
+    pub(super) section: Option<u32>,
+    /// Top level chapter next to which the generated chapters are inserted,
+    /// instead of being appended to the book.
+    pub(super) insert: Option<Insert>,
+    /// Directory of the paths of the generated chapters.
+    pub(super) path_prefix: Option<String>,
+    /// Path of the generated chapter of the single marker, the slug of its
+    /// title when unset.
+    pub(super) path: Option<String>,
+    /// Markdown starting the generated chapter of the single marker.
+    pub(super) intro: Option<String>,
+    /// File of `intro`, relative to the book root, read by `read_intro`.
+    pub(super) intro_file: Option<PathBuf>,
+    /// Files of the templates of the notes and of the generated chapters,
+    /// relative to the book root, read by `read_templates`.
+    pub(super) template_files: (Option<PathBuf>, Option<PathBuf>),
+    /// Chapter of the book getting the generated chapter of the single
+    /// marker, at its `{{#note-output}}` placeholder or at its end, instead
+    /// of a chapter of its own.
+    pub(super) target_chapter: Option<PathBuf>,
+    /// Whether the generated chapter of each marker has a sub chapter listing
+    /// the keys each source chapter has notes under.
+    pub(super) chapter_index: bool,
+    /// Whether the generated chapter of each marker has a sub chapter giving
+    /// the number of notes per key and per source chapter.
+    pub(super) stats: bool,
+    /// Whether the generated chapter of each marker has a sub chapter with
+    /// all of its notes in book order, labelled with their key.
+    pub(super) rollup: bool,
+    /// Source directory of the book with `git-dates`, its chapters giving the
+    /// date of their last commit to their headings in the generated chapters.
+    pub(super) git_dates: Option<PathBuf>,
+    /// Whether each part of the book has its own generated chapters, at its
+    /// end, the ones of the chapters before any part title preceding it.
+    pub(super) per_part: bool,
+    pub(super) layout: Layout,
+}
+
+/// Position of the generated chapters relative to a chapter, given by its
+/// name or its path.
+#[derive(Debug, PartialEq, Clone)]
+pub(super) enum Insert {
+    After(String),
+    Before(String),
+}
+
+/// Heading levels and order of the generated chapters.
+#[derive(Debug, PartialEq, Clone)]
+pub(super) struct Layout {
+    /// Whether a generated chapter starts with a heading giving its path.
+    pub(super) breadcrumb: bool,
+    /// Separator of the names in the path heading.
+    pub(super) breadcrumb_separator: String,
+    /// Whether the path heading leaves out the name of the marker chapter,
+    /// except in that chapter itself.
+    pub(super) breadcrumb_skip_root: bool,
+    /// Level of the heading giving the path of a generated chapter.
+    pub(super) breadcrumb_level: usize,
+    /// Level of the headings naming the source chapter of the next notes.
+    pub(super) source_level: usize,
+    /// Whether the notes follow the headings of their source chapter, rather
+    /// than all flowing together.
+    pub(super) source_headings: bool,
+    /// Markdown between two consecutive notes, such as `---`.
+    pub(super) separator: Option<String>,
+    /// Whether the headings of the source chapters start with their section
+    /// number.
+    pub(super) section_numbers: bool,
+    pub(super) sort: Sort,
+    /// Weights of the keys, as levels from the top one, sorting the sub
+    /// chapters before `sort` does.
+    pub(super) weights: HashMap<Vec<String>, i64>,
+    /// Weight of the keys missing from `weights`.
+    pub(super) default_weight: i64,
+    /// Markdown following the path heading of the chapters of the keys, as
+    /// levels from the top one.
+    pub(super) descriptions: HashMap<Vec<String>, String>,
+    /// Keys whose notes are sorted by term under letter headings, as levels
+    /// from the top one.
+    pub(super) glossaries: HashSet<Vec<String>>,
+    pub(super) key_layout: KeyLayout,
+    /// Whether the tables of notes have a column of their source chapter.
+    pub(super) table_source: bool,
+    /// Whether the headings of the notes are demoted below the headings of
+    /// their source chapters.
+    pub(super) shift_headings: bool,
+    /// Number of characters beyond which a note is shortened to its leading
+    /// paragraphs, followed by a link to its source chapter.
+    pub(super) max_length: Option<usize>,
+    /// Number of lines beyond which a note is shortened alike.
+    pub(super) max_lines: Option<usize>,
+    /// Line following each note with `provenance`, `{path}` and `{line}`
+    /// standing for the position of its marker.
+    pub(super) provenance: Option<String>,
+    /// Whether the notes of each source chapter are folded in a `<details>`
+    /// element instead of following a heading, for the html renderer.
+    pub(super) collapsible: bool,
+    /// Whether the notes, and the notes of each source chapter, are wrapped
+    /// in `<div>` elements with data attributes, for the html renderer.
+    pub(super) html_wrappers: bool,
+    /// How each note is written in the generated chapters.
+    pub(super) entry_style: EntryStyle,
+    /// Whether the chapters of the keys without notes of their own list the
+    /// chapters under them.
+    pub(super) list_children: bool,
+    /// Whether a key without notes of its own and with a single sub key is
+    /// left out, its sub key chapter taking its place.
+    pub(super) collapse_chains: bool,
+    /// Levels of sub chapters of the generated chapters, the deeper keys
+    /// being headings of their ancestor at this level, unlimited when unset.
+    pub(super) max_chapter_depth: Option<usize>,
+    /// Text before the pinned notes.
+    pub(super) pinned_prefix: String,
+    /// Icons before the names of the generated chapters of keys.
+    pub(super) icons: HashMap<Vec<String>, String>,
+    /// Whether the notes start with a label of their key, in the `rollup`
+    /// sub chapter.
+    pub(super) key_labels: bool,
+    /// Whether the icons are also before the levels of the breadcrumbs.
+    pub(super) icons_in_breadcrumb: bool,
+    /// Locale of `collation`, in whose order the keys and glossary terms are
+    /// sorted when built with the `collation` feature.
+    pub(super) collation: Option<String>,
+    /// Whether a generated top level chapter lists the chapters under it.
+    pub(super) toc: bool,
+    /// Whether the names of the chapters of the keys end with their number
+    /// of notes.
+    pub(super) show_counts: bool,
+    /// Whether these numbers include the notes of the keys under them.
+    pub(super) count_descendants: bool,
+    /// Whether each top level key has its own generated chapter, instead of
+    /// a sub chapter of the one of the marker.
+    pub(super) split_top_level: bool,
+    /// Whether the sub chapters of a generated chapter are its source
+    /// chapters, their notes being under a heading per key, rather than its
+    /// keys.
+    pub(super) group_by_chapter: bool,
+    /// Whether the generated chapters have a section number, unnumbered
+    /// ones being shown as suffix chapters.
+    pub(super) numbered: bool,
+    /// Text written in the generated chapters.
+    pub(super) strings: Strings,
+    /// Handlebars template of the notes, read from the `template` file.
+    pub(super) entry_template: Option<String>,
+    /// Handlebars template of the generated chapters, read from the
+    /// `chapter-template` file.
+    pub(super) chapter_template: Option<String>,
+}
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout {
+            breadcrumb: true,
+            breadcrumb_separator: " / ".to_string(),
+            breadcrumb_skip_root: false,
+            breadcrumb_level: 2,
+            source_level: 3,
+            source_headings: true,
+            separator: None,
+            section_numbers: false,
+            sort: Sort::Alpha,
+            weights: HashMap::new(),
+            default_weight: 0,
+            descriptions: HashMap::new(),
+            glossaries: HashSet::new(),
+            key_layout: KeyLayout::Nested,
+            table_source: false,
+            shift_headings: false,
+            max_length: None,
+            max_lines: None,
+            provenance: None,
+            collapsible: false,
+            html_wrappers: false,
+            entry_style: EntryStyle::Plain,
+            list_children: false,
+            collapse_chains: false,
+            max_chapter_depth: None,
+            pinned_prefix: "📌 ".to_string(),
+            icons: HashMap::new(),
+            key_labels: false,
+            icons_in_breadcrumb: true,
+            collation: None,
+            toc: false,
+            show_counts: false,
+            count_descendants: true,
+            split_top_level: false,
+            group_by_chapter: false,
+            numbered: true,
+            strings: Strings::default(),
+            entry_template: None,
+            chapter_template: None,
+        }
+    }
+}
+
+/// Text of the generated chapters, set in `[preprocessor.note.strings]` and
+/// its table for the language of the book.
+#[derive(Debug, PartialEq, Clone)]
+pub(super) struct Strings {
+    /// Link to the chapter of a shortened note.
+    pub(super) read_more: String,
+    /// Line under the source chapter names with `git-dates`, `{date}` being
+    /// the date of their last commit.
+    pub(super) last_modified: String,
+    /// Name of the `chapter-index` sub chapter.
+    pub(super) index_title: String,
+    /// Name of the `stats` sub chapter.
+    pub(super) stats_title: String,
+    /// Name of the `rollup` sub chapter.
+    pub(super) rollup_title: String,
+    /// First line of the `stats` sub chapter, `{notes}` and `{words}` being
+    /// the numbers of notes and of their words.
+    pub(super) stats_totals: String,
+    /// Headers of the tables of the `table` layout and of the statistics.
+    pub(super) term: String,
+    pub(super) definition: String,
+    pub(super) source: String,
+    pub(super) key: String,
+    pub(super) chapter: String,
+    pub(super) notes: String,
+}
+
+impl Default for Strings {
+    fn default() -> Strings {
+        Strings {
+            read_more: "… read more".to_string(),
+            last_modified: "Last modified {date}".to_string(),
+            index_title: "Index by chapter".to_string(),
+            stats_title: "Statistics".to_string(),
+            rollup_title: "All notes".to_string(),
+            stats_totals: "{notes} notes, {words} words.".to_string(),
+            term: "Term".to_string(),
+            definition: "Definition".to_string(),
+            source: "Source".to_string(),
+            key: "Key".to_string(),
+            chapter: "Chapter".to_string(),
+            notes: "Notes".to_string(),
+        }
+    }
+}
+
+/// Keys of `[preprocessor.note.strings]`.
+const STRING_KEYS: [&str; 12] = [
+    "read-more",
+    "last-modified",
+    "index-title",
+    "stats-title",
+    "rollup-title",
+    "stats-totals",
+    "term",
+    "definition",
+    "source",
+    "key",
+    "chapter",
+    "notes",
+];
+
+impl Strings {
+    /// Set the string of `key`, `table` being the path of its table below
+    /// `preprocessor.note` for the error messages.
+    fn set(&mut self, table: &str, key: &str, value: &toml::Value) -> Result<(), Error> {
+        let value = config_str(&format!("{}.{}", table, key), value)?;
+        let field = match key {
+            "read-more" => &mut self.read_more,
+            "last-modified" => &mut self.last_modified,
+            "index-title" => &mut self.index_title,
+            "stats-title" => &mut self.stats_title,
+            "rollup-title" => &mut self.rollup_title,
+            "stats-totals" => &mut self.stats_totals,
+            "term" => &mut self.term,
+            "definition" => &mut self.definition,
+            "source" => &mut self.source,
+            "key" => &mut self.key,
+            "chapter" => &mut self.chapter,
+            "notes" => &mut self.notes,
+            _ => {
+                return Err(Error::msg(format!(
+                    "unknown preprocessor.note.{}.{}, the strings are: {}",
+                    table,
+                    key,
+                    STRING_KEYS.join(", ")
+                )))
+            }
+        };
+        *field = value;
+
+        Ok(())
+    }
+}
+
+/// Where the notes of the keys go in the generated chapters.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(super) enum KeyLayout {
+    /// A sub chapter per key.
+    Nested,
+    /// A heading per key in a single chapter, one level deeper per key level.
+    Flat,
+    /// A sub chapter per key, its notes being rows of a table.
+    Table,
+    /// A single chapter holding a nested list of the keys, with their notes
+    /// as items under them.
+    Outline,
+}
+
+/// How the notes are written in the generated chapters.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(super) enum EntryStyle {
+    /// Their body, as in their chapter.
+    Plain,
+    /// A blockquote of their body, ending with a citation of their source
+    /// chapter.
+    Quote,
+}
+
+/// Order of the sub chapters of a generated chapter.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(super) enum Sort {
+    /// Sorted on their key.
+    Alpha,
+    /// In the order their key first appears in the book.
+    Appearance,
+    /// Sorted on their key, digit runs being compared as numbers.
+    Natural,
+}
+
+impl NoteConfig {
+    pub(super) fn from_context(ctx: &PreprocessorContext) -> Result<NoteConfig, Error> {
+        let mut note_config = NoteConfig::from_config(&ctx.config)?;
+        note_config.read_intro(&ctx.root)?;
+        note_config.read_templates(&ctx.root)?;
+        if let Some(src) = note_config.git_dates.take() {
+            note_config.git_dates = Some(ctx.root.join(src));
+        }
+
+        Ok(note_config)
+    }
+
+    /// Read the templates of the layout from their files, if set, relative to
+    /// the book `root`, failing on the ones which can't be compiled.
+    pub(super) fn read_templates(&mut self, root: &Path) -> Result<(), Error> {
+        let (entry, chapter) = self.template_files.clone();
+        for (key, file, template) in [
+            ("template", entry, &mut self.layout.entry_template),
+            (
+                "chapter-template",
+                chapter,
+                &mut self.layout.chapter_template,
+            ),
+        ] {
+            let Some(file) = file else {
+                continue;
+            };
+            let path = root.join(file);
+            let source = std::fs::read_to_string(&path).map_err(|e| {
+                Error::msg(format!(
+                    "preprocessor.note.{} {} can't be read: {}",
+                    key,
+                    path.display(),
+                    e
+                ))
+            })?;
+            Template::compile(&source).map_err(|e| {
+                Error::msg(format!(
+                    "preprocessor.note.{} {} is not a valid template: {}",
+                    key,
+                    path.display(),
+                    e
+                ))
+            })?;
+            *template = Some(source);
+        }
+
+        Ok(())
+    }
+
+    /// Read `intro` from `intro_file`, if set, relative to the book `root`.
+    pub(super) fn read_intro(&mut self, root: &Path) -> Result<(), Error> {
+        if let Some(file) = &self.intro_file {
+            let path = root.join(file);
+            let intro = std::fs::read_to_string(&path).map_err(|e| {
+                Error::msg(format!(
+                    "preprocessor.note.intro-file {} can't be read: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+            self.intro = Some(intro);
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn from_config(config: &Config) -> Result<NoteConfig, Error> {
+        let note = Note::from_config(config)?;
+        let mut note_config = NoteConfig {
+            markers: vec![(note.marker.clone(), note.marker)],
+            mode: Mode::Aggregate,
+            renderer_modes: HashMap::new(),
+            renderers: None,
+            clean_other_renderers: true,
+            min_notes: 0,
+            quiet: false,
+            section: None,
+            insert: None,
+            path_prefix: None,
+            path: None,
+            intro: None,
+            intro_file: None,
+            template_files: (None, None),
+            target_chapter: None,
+            chapter_index: false,
+            stats: false,
+            rollup: false,
+            git_dates: None,
+            per_part: false,
+            layout: Layout::default(),
+        };
+
+        // an unknown option is a typo unless allow-unknown-config is set,
+        // the known ones being read below
+        if let Some(nop_cfg) = config.get_preprocessor("note") {
+            let unknown: Vec<&str> = nop_cfg
+                .keys()
+                .map(String::as_str)
+                .filter(|key| !CONFIG_KEYS.contains(key))
+                .collect();
+            let allow_unknown = match nop_cfg.get("allow-unknown-config") {
+                None => false,
+                Some(value) => config_bool("allow-unknown-config", value)?,
+            };
+            if !unknown.is_empty() && !allow_unknown {
+                return Err(Error::msg(format!(
+                    "unknown preprocessor.note options: {}, accepted options are: {}",
+                    unknown.join(", "),
+                    CONFIG_KEYS.join(", ")
+                )));
+            }
+
+            match nop_cfg.get("name") {
+                None => {}
+                Some(value) => {
+                    note_config.markers[0].1 = config_str("name", value)?;
+                }
+            }
+            match nop_cfg.get("title") {
+                None => {}
+                Some(value) => {
+                    note_config.markers[0].1 = config_str("title", value)?;
+                }
+            }
+            match nop_cfg.get("path") {
+                None => {}
+                Some(value) => {
+                    let path = config_str("path", value)?;
+                    let path = path.trim_matches('/');
+                    chapter_path(path)?;
+                    note_config.path = Some(path.to_string());
+                }
+            }
+            match nop_cfg.get("intro") {
+                None => {}
+                Some(value) => {
+                    note_config.intro = Some(config_str("intro", value)?);
+                }
+            }
+            match nop_cfg.get("intro-file") {
+                None => {}
+                Some(value) => {
+                    if note_config.intro.is_some() {
+                        return Err(Error::msg(
+                            "preprocessor.note.intro and intro-file are mutually exclusive",
+                        ));
+                    }
+                    note_config.intro_file = Some(PathBuf::from(config_str("intro-file", value)?));
+                }
+            }
+            match nop_cfg.get("template") {
+                None => {}
+                Some(value) => {
+                    note_config.template_files.0 =
+                        Some(PathBuf::from(config_str("template", value)?));
+                }
+            }
+            match nop_cfg.get("chapter-template") {
+                None => {}
+                Some(value) => {
+                    note_config.template_files.1 =
+                        Some(PathBuf::from(config_str("chapter-template", value)?));
+                }
+            }
+            match nop_cfg.get("target-chapter") {
+                None => {}
+                Some(value) => {
+                    if note_config.path.is_some() {
+                        return Err(Error::msg(
+                            "preprocessor.note.path and target-chapter are mutually exclusive",
+                        ));
+                    }
+                    let target = config_str("target-chapter", value)?;
+                    note_config.target_chapter = Some(chapter_path(target.trim_matches('/'))?);
+                }
+            }
+            match nop_cfg.get("cleanup_only") {
+                None => {}
+                Some(value) => {
+                    let cleanup_only = config_bool("cleanup_only", value)?;
+                    if cleanup_only {
+                        note_config.mode = Mode::CleanOnly;
+                    }
+                }
+            }
+            match nop_cfg.get("mode") {
+                None => {}
+                Some(value) => {
+                    let mut modes = vec![];
+                    match value.as_table() {
+                        Some(table) => {
+                            for (renderer, mode) in table.iter() {
+                                let mode = config_mode(&format!("mode.{}", renderer), mode)?;
+                                note_config.renderer_modes.insert(renderer.clone(), mode);
+                                modes.push(mode);
+                            }
+                        }
+                        None => modes.push(config_mode("mode", value)?),
+                    }
+
+                    if note_config.mode == Mode::CleanOnly && modes.contains(&Mode::CollectOnly) {
+                        return Err(Error::msg(
+                            "preprocessor.note.mode \"collect-only\" and cleanup_only = true are mutually exclusive",
+                        ));
+                    }
+                    if value.as_table().is_none() {
+                        note_config.mode = modes[0];
+                    }
+                }
+            }
+            // generate-for is another name of collect-renderers, the other
+            // renderers always getting the chapters cleaned of their markers
+            let renderers = match (
+                nop_cfg.get("collect-renderers"),
+                nop_cfg.get("generate-for"),
+            ) {
+                (Some(_), Some(_)) => return Err(Error::msg(
+                    "preprocessor.note.collect-renderers and generate-for are mutually exclusive",
+                )),
+                (Some(value), None) => Some(("collect-renderers", value)),
+                (None, Some(value)) => Some(("generate-for", value)),
+                (None, None) => None,
+            };
+            match renderers {
+                None => {}
+                Some((key, value)) => {
+                    note_config.renderers = Some(config_strings(key, value)?);
+                }
+            }
+            match nop_cfg.get("clean-other-renderers") {
+                None => {}
+                Some(value) => {
+                    note_config.clean_other_renderers =
+                        config_bool("clean-other-renderers", value)?;
+                    if !note_config.clean_other_renderers && nop_cfg.contains_key("generate-for") {
+                        return Err(Error::msg(
+                            "preprocessor.note.generate-for always cleans the chapters of the other renderers, clean-other-renderers = false is not allowed with it",
+                        ));
+                    }
+                }
+            }
+            match nop_cfg.get("quiet") {
+                None => {}
+                Some(value) => {
+                    note_config.quiet = config_bool("quiet", value)?;
+                }
+            }
+            match nop_cfg.get("chapter-index") {
+                None => {}
+                Some(value) => {
+                    note_config.chapter_index = config_bool("chapter-index", value)?;
+                }
+            }
+            match nop_cfg.get("stats") {
+                None => {}
+                Some(value) => {
+                    note_config.stats = config_bool("stats", value)?;
+                }
+            }
+            match nop_cfg.get("rollup") {
+                None => {}
+                Some(value) => {
+                    note_config.rollup = config_bool("rollup", value)?;
+                }
+            }
+            match nop_cfg.get("git-dates") {
+                None => {}
+                Some(value) => {
+                    let git_dates = config_bool("git-dates", value)?;
+                    if git_dates {
+                        note_config.git_dates = Some(config.book.src.clone());
+                    }
+                }
+            }
+            match nop_cfg.get("breadcrumb") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.breadcrumb = config_bool("breadcrumb", value)?;
+                }
+            }
+            match nop_cfg.get("breadcrumb-separator") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.breadcrumb_separator =
+                        config_str("breadcrumb-separator", value)?;
+                }
+            }
+            match nop_cfg.get("breadcrumb-skip-root") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.breadcrumb_skip_root =
+                        config_bool("breadcrumb-skip-root", value)?;
+                }
+            }
+            match nop_cfg.get("breadcrumb-level") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.breadcrumb_level = config_level("breadcrumb-level", value)?;
+                }
+            }
+            match nop_cfg.get("source-heading-level") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.source_level = config_level("source-heading-level", value)?;
+                }
+            }
+            match nop_cfg.get("group-by") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.group_by_chapter = match value.as_str() {
+                        Some("key") => false,
+                        Some("chapter") => true,
+                        _ => {
+                            return Err(Error::msg(format!(
+                            "preprocessor.note.group-by must be \"key\" or \"chapter\", found {}",
+                            value
+                        )))
+                        }
+                    };
+                }
+            }
+            match nop_cfg.get("source-headings") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.source_headings = config_bool("source-headings", value)?;
+                }
+            }
+            match nop_cfg.get("show-section-numbers") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.section_numbers =
+                        config_bool("show-section-numbers", value)?;
+                }
+            }
+            // entry-separator is another name of note-separator
+            let separator = match (
+                nop_cfg.get("note-separator"),
+                nop_cfg.get("entry-separator"),
+            ) {
+                (Some(_), Some(_)) => return Err(Error::msg(
+                    "preprocessor.note.note-separator and entry-separator are mutually exclusive",
+                )),
+                (Some(value), None) => Some(("note-separator", value)),
+                (None, Some(value)) => Some(("entry-separator", value)),
+                (None, None) => None,
+            };
+            match separator {
+                None => {}
+                Some((key, value)) => {
+                    // an empty separator would add blank lines between the notes
+                    note_config.layout.separator =
+                        Some(config_str(key, value)?).filter(|separator| !separator.is_empty());
+                }
+            }
+            match nop_cfg.get("insert-after") {
+                None => {}
+                Some(value) => {
+                    note_config.insert = Some(Insert::After(config_str("insert-after", value)?));
+                }
+            }
+            match nop_cfg.get("insert-before") {
+                None => {}
+                Some(value) => {
+                    if note_config.insert.is_some() {
+                        return Err(Error::msg(
+                            "preprocessor.note.insert-after and insert-before are mutually exclusive",
+                        ));
+                    }
+                    note_config.insert = Some(Insert::Before(config_str("insert-before", value)?));
+                }
+            }
+            match nop_cfg.get("layout") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.key_layout = match value.as_str() {
+                        Some("nested") => KeyLayout::Nested,
+                        Some("flat") => KeyLayout::Flat,
+                        Some("table") => KeyLayout::Table,
+                        Some("outline") => KeyLayout::Outline,
+                        _ => {
+                            return Err(Error::msg(format!(
+                                "preprocessor.note.layout must be \"nested\", \"flat\", \"table\" or \"outline\", found {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+            }
+            match nop_cfg.get("shift-headings") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.shift_headings = config_bool("shift-headings", value)?;
+                }
+            }
+            match nop_cfg.get("table-source") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.table_source = config_bool("table-source", value)?;
+                }
+            }
+            for (key, max) in [
+                ("max-inline-length", &mut note_config.layout.max_length),
+                ("max-inline-lines", &mut note_config.layout.max_lines),
+            ] {
+                if let Some(value) = nop_cfg.get(key) {
+                    *max = match value.as_integer() {
+                        Some(n) if n >= 1 => Some(n as usize),
+                        _ => {
+                            return Err(Error::msg(format!(
+                                "preprocessor.note.{} must be a positive integer, found {}",
+                                key, value
+                            )))
+                        }
+                    };
+                }
+            }
+            if let Some(true) = nop_cfg.get("provenance").and_then(toml::Value::as_bool) {
+                note_config.layout.provenance = Some(match nop_cfg.get("provenance-template") {
+                    None => "— {path}:{line}".to_string(),
+                    Some(value) => config_str("provenance-template", value)?,
+                });
+            }
+            match nop_cfg.get("collapsible") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.collapsible = config_bool("collapsible", value)?;
+                }
+            }
+            match nop_cfg.get("html-wrappers") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.html_wrappers = config_bool("html-wrappers", value)?;
+                }
+            }
+            match nop_cfg.get("entry-style") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.entry_style = match value.as_str() {
+                        Some("plain") => EntryStyle::Plain,
+                        Some("quote") => EntryStyle::Quote,
+                        _ => {
+                            return Err(Error::msg(format!(
+                                "preprocessor.note.entry-style must be \"plain\" or \"quote\", found {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+            }
+            match nop_cfg.get("list-children") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.list_children = config_bool("list-children", value)?;
+                }
+            }
+            match nop_cfg.get("collapse-single-chains") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.collapse_chains =
+                        config_bool("collapse-single-chains", value)?;
+                }
+            }
+            match nop_cfg.get("max-chapter-depth") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.max_chapter_depth = match value.as_integer() {
+                        Some(depth) if depth >= 1 => Some(depth as usize),
+                        _ => {
+                            return Err(Error::msg(format!(
+                                "preprocessor.note.max-chapter-depth must be a positive integer, found {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+            }
+            match nop_cfg.get("collation") {
+                None => {}
+                Some(value) => {
+                    let locale = config_str("collation", value)?;
+                    note_config.layout.collation = Some(locale.clone());
+                    if collator(&note_config.layout, false).is_none() {
+                        match cfg!(feature = "collation") {
+                            true => warn!(
+                                "preprocessor.note.collation {:?} is not a known locale, sorting by code point",
+                                locale
+                            ),
+                            false => warn!(
+                                "preprocessor.note.collation {:?} needs mdbook-note to be built with the collation feature, sorting by code point",
+                                locale
+                            ),
+                        }
+                        note_config.layout.collation = None;
+                    }
+                }
+            }
+            match nop_cfg.get("pinned-prefix") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.pinned_prefix = config_str("pinned-prefix", value)?;
+                }
+            }
+            match nop_cfg.get("icons-in-breadcrumb") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.icons_in_breadcrumb =
+                        config_bool("icons-in-breadcrumb", value)?;
+                }
+            }
+            match nop_cfg.get("toc") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.toc = config_bool("toc", value)?;
+                }
+            }
+            match nop_cfg.get("show-counts") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.show_counts = config_bool("show-counts", value)?;
+                }
+            }
+            match nop_cfg.get("count-descendants") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.count_descendants = config_bool("count-descendants", value)?;
+                }
+            }
+            match nop_cfg.get("split-top-level") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.split_top_level = config_bool("split-top-level", value)?;
+                }
+            }
+            match nop_cfg.get("per-part") {
+                None => {}
+                Some(value) => {
+                    note_config.per_part = config_bool("per-part", value)?;
+                }
+            }
+            match nop_cfg.get("path-prefix") {
+                None => {}
+                Some(value) => {
+                    let prefix = config_str("path-prefix", value)?;
+                    let prefix = prefix.trim_matches('/');
+                    chapter_path(prefix)?;
+                    note_config.path_prefix = Some(prefix.to_string());
+                }
+            }
+            match nop_cfg.get("numbered") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.numbered = config_bool("numbered", value)?;
+                }
+            }
+            match nop_cfg.get("sort") {
+                None => {}
+                Some(value) => {
+                    note_config.layout.sort = match value.as_str() {
+                        Some("alpha") => Sort::Alpha,
+                        Some("appearance") => Sort::Appearance,
+                        Some("natural") => Sort::Natural,
+                        _ => return Err(Error::msg(format!(
+                            "preprocessor.note.sort must be \"alpha\", \"appearance\" or \"natural\", found {}",
+                            value
+                        ))),
+                    };
+                }
+            }
+            match nop_cfg.get("fail-on-zero-notes") {
+                None => {}
+                Some(value) => {
+                    let fail = config_bool("fail-on-zero-notes", value)?;
+                    note_config.min_notes = note_config.min_notes.max(fail as usize);
+                }
+            }
+            match nop_cfg.get("min-notes") {
+                None => {}
+                Some(value) => match value.as_integer() {
+                    Some(min) if min >= 0 => {
+                        note_config.min_notes = note_config.min_notes.max(min as usize);
+                    }
+                    _ => {
+                        return Err(Error::msg(format!(
+                            "preprocessor.note.min-notes must be a non-negative integer, found {}",
+                            value
+                        )))
+                    }
+                },
+            }
+            match nop_cfg.get("section") {
+                None => {}
+                Some(value) => match value.as_integer() {
+                    Some(section) if section >= 1 && section <= u32::MAX as i64 => {
+                        note_config.section = Some(section as u32);
+                    }
+                    _ => {
+                        return Err(Error::msg(format!(
+                            "preprocessor.note.section must be a positive integer, found {}",
+                            value
+                        )))
+                    }
+                },
+            }
+            match nop_cfg.get("strings") {
+                None => {}
+                Some(value) => {
+                    let table = value.as_table().ok_or_else(|| {
+                        Error::msg(format!(
+                            "preprocessor.note.strings must be a table, found {}",
+                            value.type_str()
+                        ))
+                    })?;
+
+                    // the table of the book language overrides the others,
+                    // those of the other languages being only checked
+                    let strings = &mut note_config.layout.strings;
+                    for (key, value) in table.iter().filter(|(_, value)| !value.is_table()) {
+                        strings.set("strings", key, value)?;
+                    }
+                    for (language, value) in table.iter() {
+                        let Some(language_table) = value.as_table() else {
+                            continue;
+                        };
+                        let mut other = strings.clone();
+                        let target = match config.book.language.as_deref() == Some(language) {
+                            true => &mut *strings,
+                            false => &mut other,
+                        };
+                        for (key, value) in language_table.iter() {
+                            target.set(&format!("strings.{}", language), key, value)?;
+                        }
+                    }
+                }
+            }
+            match nop_cfg.get("markers") {
+                None => {}
+                Some(value) => {
+                    let table = value.as_table().ok_or_else(|| {
+                        Error::msg(format!(
+                            "preprocessor.note.markers must be a table, found {}",
+                            value.type_str()
+                        ))
+                    })?;
+
+                    note_config.markers = vec![];
+                    for (marker, name) in table.iter() {
+                        let name = config_str(&format!("markers.{}", marker), name)?;
+                        note_config.markers.push((marker.clone(), name));
+                    }
+
+                    for (key, set) in [
+                        ("path", note_config.path.is_some()),
+                        ("intro", note_config.intro.is_some()),
+                        ("intro-file", note_config.intro_file.is_some()),
+                        ("target-chapter", note_config.target_chapter.is_some()),
+                    ] {
+                        if set {
+                            return Err(Error::msg(format!(
+                                "preprocessor.note.{} can't be set with preprocessor.note.markers",
+                                key
+                            )));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(note_config)
+    }
+}
+
+/// Heading level of the `key` option, clamped between 1 and 6.
+fn config_level(key: &str, value: &toml::Value) -> Result<usize, Error> {
+    let level = value.as_integer().ok_or_else(|| {
+        Error::msg(format!(
+            "preprocessor.note.{} must be an integer, found {}",
+            key,
+            value.type_str()
+        ))
+    })?;
+    let clamped = level.clamp(1, 6);
+
+    if clamped != level {
+        warn!(
+            "preprocessor.note.{} must be between 1 and 6, found {}, using {}",
+            key, level, clamped
+        );
+    }
+
+    Ok(clamped as usize)
+}
+
+/// Mode of the `key` option.
+fn config_mode(key: &str, value: &toml::Value) -> Result<Mode, Error> {
+    match value.as_str() {
+        Some("aggregate") => Ok(Mode::Aggregate),
+        Some("clean-only") => Ok(Mode::CleanOnly),
+        Some("collect-only") => Ok(Mode::CollectOnly),
+        _ => Err(Error::msg(format!(
+            "preprocessor.note.{} must be \"aggregate\", \"clean-only\" or \"collect-only\", found {}",
+            key, value
+        ))),
+    }
+}
+
+/// Glob patterns of the `key` option, an array of strings.
+fn config_patterns(key: &str, value: &toml::Value) -> Result<Vec<Pattern>, Error> {
+    let mut patterns = vec![];
+    for pattern in config_strings(key, value)? {
+        patterns.push(Pattern::new(&pattern).map_err(|e| {
+            Error::msg(format!(
+                "preprocessor.note.{} pattern {:?} is invalid: {}",
+                key, pattern, e
+            ))
+        })?);
+    }
+
+    Ok(patterns)
+}
+
+/// Strings of the `key` option, an array of strings.
+fn config_strings(key: &str, value: &toml::Value) -> Result<Vec<String>, Error> {
+    let array = value.as_array().ok_or_else(|| {
+        Error::msg(format!(
+            "preprocessor.note.{} must be an array, found {}",
+            key,
+            value.type_str()
+        ))
+    })?;
+
+    array.iter().map(|item| config_str(key, item)).collect()
+}
+
+/// Boolean value of the `key` option.
+fn config_bool(key: &str, value: &toml::Value) -> Result<bool, Error> {
+    value.as_bool().ok_or_else(|| {
+        Error::msg(format!(
+            "preprocessor.note.{} must be a boolean, found {}",
+            key,
+            value.type_str()
+        ))
+    })
+}
+
+/// String value of the `key` option.
+fn config_str(key: &str, value: &toml::Value) -> Result<String, Error> {
+    match value.as_str() {
+        Some(s) => Ok(s.to_string()),
+        None => Err(Error::msg(format!(
+            "preprocessor.note.{} must be a string, found {}",
+            key,
+            value.type_str()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note::fixture::{chapter, config_error, configs, note_config, preprocessor};
+    use crate::note::generate::note_chapters;
+    use mdbook::book::Book;
+    use mdbook::BookItem;
+
+    #[test]
+    fn test_process_note_config() {
+        assert_eq!(
+            note_config("[book]\ntitle = \"x\""),
+            NoteConfig {
+                markers: vec![("note".to_string(), "note".to_string())],
+                mode: Mode::Aggregate,
+                renderer_modes: HashMap::new(),
+                renderers: None,
+                clean_other_renderers: true,
+                min_notes: 0,
+                quiet: false,
+                section: None,
+                insert: None,
+                path_prefix: None,
+                path: None,
+                intro: None,
+                intro_file: None,
+                template_files: (None, None),
+                target_chapter: None,
+                chapter_index: false,
+                stats: false,
+                rollup: false,
+                git_dates: None,
+                per_part: false,
+                layout: Layout::default(),
+            }
+        );
+
+        assert_eq!(
+            note_config(
+                "[preprocessor.note]
+name = \"Annexe\"
+cleanup_only = true"
+            ),
+            NoteConfig {
+                markers: vec![("note".to_string(), "Annexe".to_string())],
+                mode: Mode::CleanOnly,
+                renderer_modes: HashMap::new(),
+                renderers: None,
+                clean_other_renderers: true,
+                min_notes: 0,
+                quiet: false,
+                section: None,
+                insert: None,
+                path_prefix: None,
+                path: None,
+                intro: None,
+                intro_file: None,
+                template_files: (None, None),
+                target_chapter: None,
+                chapter_index: false,
+                stats: false,
+                rollup: false,
+                git_dates: None,
+                per_part: false,
+                layout: Layout::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_process_note_config_title_path() {
+        let (_, note_config) = configs("[preprocessor.note]\nname = \"Notes de lecture\"");
+        assert_eq!(
+            note_config.markers,
+            vec![("note".to_string(), "Notes de lecture".to_string())]
+        );
+        assert_eq!(note_config.path, None);
+
+        let (config, note_config) = configs(
+            "[preprocessor.note]
+name = \"Old\"
+title = \"Notes de lecture\"
+path = \"lecture/notes/\"",
+        );
+        assert_eq!(
+            note_config.markers,
+            vec![("note".to_string(), "Notes de lecture".to_string())]
+        );
+        assert_eq!(note_config.path, Some("lecture/notes".to_string()));
+
+        let mut book = Book::new();
+        book.push_item(chapter("ch", "{{#note a}}x{{#note end}}", vec![]));
+        let chapters = note_chapters(&config, &note_config, &mut book).unwrap();
+        assert_eq!(chapters[0].name, "Notes de lecture");
+        assert_eq!(chapters[0].path, Some(PathBuf::from("lecture/notes")));
+        match &chapters[0].sub_items[0] {
+            BookItem::Chapter(a) => {
+                assert_eq!(a.path, Some(PathBuf::from("lecture/notes/a")));
+                assert_eq!(a.content, "## Notes de lecture / a\n\n### ch\n\nx");
+            }
+            _ => unreachable!(),
+        }
+
+        assert_eq!(
+            config_error(
+                "[preprocessor.note]
+path = \"notes\"
+
+[preprocessor.note.markers]
+note = \"Notes\""
+            ),
+            "preprocessor.note.path can't be set with preprocessor.note.markers"
+        );
+    }
+
+    #[test]
+    fn test_process_note_config_wrong_types() {
+        let error = |toml: &str| config_error(toml);
+
+        assert_eq!(
+            error("[preprocessor.note]\nname = 42"),
+            "preprocessor.note.name must be a string, found integer"
+        );
+        assert_eq!(
+            error("[preprocessor.note.name]\nx = 1"),
+            "preprocessor.note.name must be a string, found table"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\ncleanup_only = \"yes\""),
+            "preprocessor.note.cleanup_only must be a boolean, found string"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\nmarkers = [\"todo\"]"),
+            "preprocessor.note.markers must be a table, found array"
+        );
+        assert_eq!(
+            error("[preprocessor.note.markers]\ntodo = true"),
+            "preprocessor.note.markers.todo must be a string, found boolean"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\nstrict = \"yes\""),
+            "preprocessor.note.strict must be a boolean, found string"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\nhidden-by-default = 1"),
+            "preprocessor.note.hidden-by-default must be a boolean, found integer"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\nlocal-class = false"),
+            "preprocessor.note.local-class must be a string, found boolean"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\nparagraph-mode = \"on\""),
+            "preprocessor.note.paragraph-mode must be a boolean, found string"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\nmarker = 1"),
+            "preprocessor.note.marker must be a string, found integer"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\nsyntax = \"braces\""),
+            "preprocessor.note.syntax must be an array, found string"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\nsyntax = [1]"),
+            "preprocessor.note.syntax must be a string, found integer"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\nsyntax = [\"xml\"]"),
+            "preprocessor.note.syntax must only contain \"braces\" or \"comments\", found \"xml\""
+        );
+        assert_eq!(
+            error("[preprocessor.note]\naliases = [\"perf\"]"),
+            "preprocessor.note.aliases must be a table, found array"
+        );
+        assert_eq!(
+            error("[preprocessor.note.aliases]\nperf = 1"),
+            "preprocessor.note.aliases.perf must be a string, found integer"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\nuncategorized = true"),
+            "preprocessor.note.uncategorized must be a string, found boolean"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\nstrip-excluded = \"no\""),
+            "preprocessor.note.strip-excluded must be a boolean, found string"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\ncollect-hashtags = 1"),
+            "preprocessor.note.collect-hashtags must be a boolean, found integer"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\ndedupe = \"yes\""),
+            "preprocessor.note.dedupe must be a boolean, found string"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\ndeep-links = 1"),
+            "preprocessor.note.deep-links must be a boolean, found integer"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\nbacklinks = \"yes\""),
+            "preprocessor.note.backlinks must be a boolean, found string"
+        );
+        assert_eq!(
+            error("[preprocessor.note]\nbacklink-text = 1"),
+            "preprocessor.note.backlink-text must be a string, found integer"
+        );
+    }
+
+    #[test]
+    fn test_process_note_options_wrong_types() {
+        let options = [
+            ("marker", "1"),
+            ("strict", "\"yes\""),
+            ("key-separator", "1"),
+            ("hidden-by-default", "\"yes\""),
+            ("local-class", "true"),
+            ("inline-style", "1"),
+            ("inline-class", "true"),
+            ("inline-link-text", "1"),
+            ("paragraph-mode", "\"yes\""),
+            ("normalize-keys", "true"),
+            ("strip-excluded", "\"yes\""),
+            ("uncategorized", "1"),
+            ("max-depth", "\"2\""),
+            ("collect-hashtags", "\"yes\""),
+            ("dedupe", "\"yes\""),
+            ("aliases", "1"),
+            ("order", "1"),
+            ("order-default", "\"1\""),
+            ("provenance", "\"yes\""),
+            ("descriptions", "1"),
+            ("icons", "1"),
+            ("key-modes", "1"),
+            ("deep-links", "\"yes\""),
+            ("backlinks", "\"yes\""),
+            ("backlink-text", "1"),
+            ("only-keys", "1"),
+            ("exclude-keys", "1"),
+            ("include", "1"),
+            ("ignore", "1"),
+            ("syntax", "1"),
+        ];
+
+        for (option, value) in options {
+            let toml = format!("[preprocessor.note]\n{} = {}", option, value);
+            let error = match preprocessor(&toml) {
+                Ok(_) => panic!("preprocessor.note.{} = {} accepted", option, value),
+                Err(e) => e.to_string(),
+            };
+            assert!(
+                error.starts_with(&format!("preprocessor.note.{} must ", option)),
+                "{}",
+                error
+            );
+            assert_eq!(config_error(&toml), error);
+        }
+    }
+
+    #[test]
+    fn test_process_note_config_collation() {
+        let collation = |locale: &str| {
+            note_config(&format!("[preprocessor.note]\ncollation = {:?}", locale))
+                .layout
+                .collation
+        };
+
+        // left out with a warning without the collation feature
+        assert_eq!(
+            collation("sv-SE"),
+            cfg!(feature = "collation").then(|| "sv-SE".to_string())
+        );
+        assert_eq!(collation("not a locale"), None);
+    }
+
+    #[test]
+    fn test_process_note_config_heading_levels() {
+        assert_eq!(
+            note_config("[preprocessor.note]\nbreadcrumb-level = 1\nsource-heading-level = 4")
+                .layout,
+            Layout {
+                breadcrumb_level: 1,
+                source_level: 4,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            note_config("[preprocessor.note]\nbreadcrumb-level = 0\nsource-heading-level = 9")
+                .layout,
+            Layout {
+                breadcrumb_level: 1,
+                source_level: 6,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            config_error("[preprocessor.note]\nbreadcrumb-level = \"2\""),
+            "preprocessor.note.breadcrumb-level must be an integer, found string"
+        );
+    }
+
+    #[test]
+    fn test_process_note_config_unknown_keys() {
+        let error =
+            config_error("[preprocessor.note]\nnmae = \"annexe\"\ncommand = \"mdbook-note\"");
+        assert!(
+            error.starts_with("unknown preprocessor.note options: nmae, accepted options are: ")
+        );
+        assert!(error.contains("name"));
+
+        let config: Config = "[preprocessor.note]\nnmae = \"annexe\"\nallow-unknown-config = true"
+            .parse()
+            .unwrap();
+        assert!(NoteConfig::from_config(&config).is_ok());
+    }
+}
@@ -0,0 +1,64 @@
+use mdbook::book::{Book, Chapter};
+use mdbook::errors::Error;
+use mdbook::{BookItem, Config};
+
+use super::config::NoteConfig;
+use super::generate::note_chapters;
+use super::{render_book, Note};
+
+/// Chapter of a test book, without number nor path.
+pub(super) fn chapter(name: &str, content: &str, sub_items: Vec<BookItem>) -> Chapter {
+    Chapter {
+        name: name.to_string(),
+        content: content.to_string(),
+        number: None,
+        sub_items,
+        path: None,
+        source_path: None,
+        parent_names: vec![],
+    }
+}
+
+/// Configuration of a test book, with the preprocessor configuration read
+/// from it.
+pub(super) fn configs(toml: &str) -> (Config, NoteConfig) {
+    let config: Config = toml.parse().unwrap();
+    let note_config = NoteConfig::from_config(&config).unwrap();
+
+    (config, note_config)
+}
+
+/// Preprocessor configuration of a test book.
+pub(super) fn note_config(toml: &str) -> NoteConfig {
+    configs(toml).1
+}
+
+/// Error reading the preprocessor configuration of a test book.
+pub(super) fn config_error(toml: &str) -> String {
+    let config: Config = toml.parse().unwrap();
+
+    NoteConfig::from_config(&config).unwrap_err().to_string()
+}
+
+/// Notes chapters generated for a test book with its configuration.
+pub(super) fn notes_chapters(toml: &str, book: &mut Book) -> Result<Vec<Chapter>, Error> {
+    let config: Config = toml.parse().unwrap();
+    let note_config = NoteConfig::from_config(&config)?;
+
+    note_chapters(&config, &note_config, book)
+}
+
+/// A test book processed for `renderer` with its configuration.
+pub(super) fn render(toml: &str, renderer: &str, book: Book) -> Result<Book, Error> {
+    let config: Config = toml.parse().unwrap();
+    let note_config = NoteConfig::from_config(&config)?;
+
+    render_book(&config, &note_config, renderer, book)
+}
+
+/// Preprocessor built from the configuration of a test book.
+pub(super) fn preprocessor(toml: &str) -> Result<Note, Error> {
+    let config: Config = toml.parse().unwrap();
+
+    Note::from_config(&config)
+}
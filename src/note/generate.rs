@@ -0,0 +1,5171 @@
+use handlebars::Handlebars;
+use log::{info, warn};
+use mdbook::book::{Book, Chapter, SectionNumber};
+use mdbook::errors::Error;
+use mdbook::{BookItem, Config};
+use std::cmp::{Ordering, Reverse};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+
+use super::clean::{anchor, heading_ids, InlineStyle};
+use super::config::{EntryStyle, KeyLayout, Layout, Mode, NoteConfig, Sort, Strings};
+use super::util::{
+    count, escape_html, for_each_chapter_mut, page_link, rewrite_links, shift_headings, shorten,
+    table_cell,
+};
+use super::{chapter_directive, drop_empty_headings, Extract, Note};
+
+impl Extract {
+    /// Markdown of the extract in the generated chapter, `root` being the
+    /// relative link from that chapter to the book root.
+    fn content(&self, root: &str, layout: &Layout) -> String {
+        if self.heading {
+            let name = self.heading_name(layout);
+            let hashes = "#".repeat(layout.source_level);
+
+            let heading = match &self.source {
+                Some(source) => format!("{} [{}]({}{})", hashes, name, root, page_link(source)),
+                None => format!("{} {}", hashes, name),
+            };
+
+            return match &self.date {
+                Some(date) => format!(
+                    "{}\n\n*{}*",
+                    heading,
+                    layout.strings.last_modified.replace("{date}", date)
+                ),
+                None => heading,
+            };
+        }
+
+        let val = self.note_body(root, layout);
+        let content = match &self.title {
+            Some(title) => format!("**{}**\n\n{}", title, val),
+            None => val,
+        };
+        let content = match self.pinned() {
+            true => format!("{}{}", layout.pinned_prefix, content),
+            false => content,
+        };
+        let content = match (&layout.provenance, &self.source, self.line) {
+            (Some(template), Some(source), Some(line)) => format!(
+                "{}\n\n{}",
+                content,
+                template
+                    .replace("{path}", &source.display().to_string())
+                    .replace("{line}", &line.to_string())
+            ),
+            _ => content,
+        };
+        let content = match (&self.source, self.site) {
+            (Some(source), Some(site)) => format!(
+                "{}\n\n[↩]({}{}#note-{})",
+                content,
+                root,
+                page_link(source),
+                site
+            ),
+            _ => content,
+        };
+
+        match self.attributes.get("id") {
+            Some(id) if !self.heading => format!("{}\n\n{}", anchor(id), content),
+            _ => content,
+        }
+    }
+
+    /// Body of a note in the generated chapter, its headings shifted and its
+    /// text shortened as set in the layout.
+    fn note_body(&self, root: &str, layout: &Layout) -> String {
+        let val = match layout.shift_headings {
+            true => shift_headings(&self.body(root), layout.source_level + 1),
+            false => self.body(root),
+        };
+        match shorten(&val, layout.max_length, layout.max_lines) {
+            None => val,
+            Some(short) => match &self.source {
+                Some(source) => format!(
+                    "{}\n\n[{}]({}{}{})",
+                    short,
+                    layout.strings.read_more,
+                    root,
+                    page_link(source),
+                    self.site
+                        .map(|site| format!("#note-{}", site))
+                        .unwrap_or_default()
+                ),
+                None => format!("{}\n\n…", short),
+            },
+        }
+    }
+
+    /// Markdown of a note through the entry template of the layout, `parent`
+    /// being the key of its generated chapter, `chapter` the heading of its
+    /// source chapter and `index` its position in the generated chapter,
+    /// from 1.
+    fn render(
+        &self,
+        template: &str,
+        parent: &[String],
+        chapter: Option<&Extract>,
+        index: usize,
+        root: &str,
+        layout: &Layout,
+    ) -> Result<String, Error> {
+        let mut data = BTreeMap::new();
+        data.insert("key", self.key_path(parent).join(" / "));
+        data.insert("title", self.title.clone().unwrap_or_default());
+        data.insert("body", self.note_body(root, layout));
+        data.insert("note", self.content(root, layout));
+        data.insert(
+            "chapter_name",
+            chapter.map(|c| c.heading_name(layout)).unwrap_or_default(),
+        );
+        data.insert(
+            "chapter_path",
+            match chapter.and_then(|c| c.source.as_ref()) {
+                Some(source) => format!("{}{}", root, page_link(source)),
+                None => String::new(),
+            },
+        );
+        data.insert("index", index.to_string());
+
+        render_template(template, &data)
+    }
+
+    /// Levels of the key of a note, from the top one, `parent` being the key
+    /// of its generated chapter.
+    fn key_path<'a>(&'a self, parent: &'a [String]) -> Vec<&'a str> {
+        parent
+            .iter()
+            .chain(self.key.iter().rev())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Identifier of the source chapter of a heading, its path without
+    /// extension, or its name for a draft.
+    fn chapter_id(&self) -> String {
+        match &self.source {
+            Some(source) => source.with_extension("").display().to_string(),
+            None => self.val.trim_start_matches("### ").to_string(),
+        }
+    }
+
+    /// Name of the source chapter of a heading, after its section number with
+    /// `show-section-numbers`, unless it has it already to tell it apart.
+    fn heading_name(&self, layout: &Layout) -> String {
+        let name = self.val.trim_start_matches("### ");
+
+        match &self.number {
+            Some(number)
+                if layout.section_numbers && !name.starts_with(&format!("{} ", number)) =>
+            {
+                format!("{} {}", number, name)
+            }
+            _ => name.to_string(),
+        }
+    }
+
+    /// Body of a note, its relative links being from the book root, as links
+    /// from a chapter `root` away from it.
+    fn body(&self, root: &str) -> String {
+        match root {
+            "" => self.val.clone(),
+            _ => rewrite_links(&self.val, |url| format!("{}{}", root, url)),
+        }
+    }
+
+    /// Cells of the row of a note in a table: its title, or the first line
+    /// of its body, then the rest of its body.
+    fn cells(&self, root: &str) -> (String, String) {
+        let body = self.body(root);
+        let body = body.trim();
+        let (term, definition) = match &self.title {
+            Some(title) => (title.as_str(), body),
+            None => body.split_once('\n').unwrap_or((body, "")),
+        };
+
+        (table_cell(term), table_cell(definition))
+    }
+
+    /// Term of a note in a glossary: its title, its leading bold text, or
+    /// its first word.
+    fn term(&self) -> String {
+        if let Some(title) = &self.title {
+            return title.clone();
+        }
+
+        let body = self.val.trim_start();
+        let bold = ["**", "__"]
+            .iter()
+            .find_map(|b| body.strip_prefix(b).and_then(|rest| rest.split_once(b)));
+
+        match bold {
+            Some((term, _)) => term.trim().to_string(),
+            None => body
+                .split_whitespace()
+                .next()
+                .unwrap_or("")
+                .trim_matches(|c: char| !c.is_alphanumeric())
+                .to_string(),
+        }
+    }
+
+    fn order(&self) -> Option<i64> {
+        self.attributes.get("order").and_then(|o| o.parse().ok())
+    }
+
+    /// Whether a note is marked `pinned`, to come first in its chapter.
+    fn pinned(&self) -> bool {
+        !self.heading && self.attributes.get("pinned").map(String::as_str) == Some("true")
+    }
+}
+
+/// Source chapters, as their name and path, with the keys they have notes
+/// under, as levels from the top one, and the number of these notes.
+type Contributions = Vec<(String, Option<PathBuf>, Vec<(Vec<String>, usize)>)>;
+
+struct Extracts {
+    name: String,
+    /// Index of the first extract of the key, in book order.
+    first: usize,
+    list: Vec<Extract>,
+}
+
+/// Prefix the headings of source chapters sharing their name with another
+/// one with their section number, or suffix them with their path.
+pub(super) fn disambiguate_headings(extracts: &mut [Extract]) {
+    let mut chapters: HashMap<String, HashSet<Option<PathBuf>>> = HashMap::new();
+    for extract in extracts.iter().filter(|e| e.heading) {
+        chapters
+            .entry(extract.val.clone())
+            .or_default()
+            .insert(extract.source.clone());
+    }
+
+    for extract in extracts.iter_mut().filter(|e| e.heading) {
+        if chapters[&extract.val].len() < 2 {
+            continue;
+        }
+
+        let name = extract.val.trim_start_matches("### ");
+        extract.val = match (&extract.number, &extract.source) {
+            (Some(number), _) => format!("### {} {}", number, name),
+            (None, Some(source)) => format!("### {} ({})", name, source.display()),
+            (None, None) => continue,
+        };
+    }
+}
+
+/// Process the book once per marker, returning the chapters generated for
+/// each of them.
+pub(super) fn note_chapters(
+    config: &Config,
+    note_config: &NoteConfig,
+    book: &mut Book,
+) -> Result<Vec<Chapter>, Error> {
+    let mut note_chapters = vec![];
+    // the part of each generated chapter, with per-part
+    let mut chapter_parts = vec![];
+    let part_titles: Vec<String> = book
+        .sections
+        .iter()
+        .filter_map(|item| match item {
+            BookItem::PartTitle(title) => Some(title.clone()),
+            _ => None,
+        })
+        .collect();
+    let per_part = note_config.per_part && !part_titles.is_empty();
+    let mut notes = 0;
+    let mut chapters: HashSet<String> = HashSet::new();
+    let mut keys: HashSet<Vec<String>> = HashSet::new();
+    let first_section = note_config.section.unwrap_or_else(|| next_section(book));
+    let mut slugs: Vec<String> = vec![];
+    let mut pages = chapter_pages(&mut book.sections)?;
+    // the generated chapter merged into the target chapter takes its page
+    let target_page = note_config
+        .target_chapter
+        .as_ref()
+        .map(|target| target.with_extension(""));
+    if let Some(target_page) = &target_page {
+        pages.remove(target_page);
+    }
+    let mut original = note_config.layout.provenance.as_ref().map(|_| book.clone());
+    // git is run once per source chapter, and not anymore once it failed
+    let mut git_dates = note_config.git_dates.clone();
+    let mut dates: HashMap<PathBuf, Option<String>> = HashMap::new();
+
+    for (marker, name) in note_config.markers.iter() {
+        let mut note = Note::from_config(config)?;
+        note.set_marker(marker)?;
+
+        let (mut extracts, ids) = note.collect_book(book, note_config.mode == Mode::CleanOnly)?;
+
+        // the markers of the previous markers are cleaned by now, so the
+        // lines are taken from the book as it was
+        if let Some(original) = original.as_mut().filter(|_| note.provenance) {
+            let mut lines = note.note_lines(original)?;
+            for extract in extracts.iter_mut().filter(|e| !e.heading) {
+                let key = extract.key.iter().map(|k| note.fold(k)).collect();
+                extract.line = lines
+                    .get_mut(&(extract.source.clone(), key, extract.val.clone()))
+                    .and_then(VecDeque::pop_front);
+            }
+        }
+        if let Some(src) = git_dates.clone() {
+            for extract in extracts.iter_mut().filter(|e| e.heading) {
+                let Some(source) = extract.source.clone() else {
+                    continue;
+                };
+                if let Entry::Vacant(entry) = dates.entry(source.clone()) {
+                    match git_date(&src.join(&source)) {
+                        Ok(date) => {
+                            entry.insert(date);
+                        }
+                        Err(e) => {
+                            warn!(
+                                "preprocessor.note.git-dates skipped, git failed in {}: {}",
+                                src.display(),
+                                e
+                            );
+                            git_dates = None;
+                            break;
+                        }
+                    }
+                }
+                extract.date = dates[&source].clone();
+            }
+        }
+        for extract in extracts.iter() {
+            if extract.heading {
+                chapters.insert(extract.val.clone());
+            } else {
+                notes += 1;
+                keys.insert(extract.key.clone());
+            }
+        }
+
+        let mut backlinks = HashMap::new();
+        // the pages of the keys from the book root, for the chapter index
+        // and the statistics
+        let mut key_links: HashMap<Vec<String>, String> = HashMap::new();
+        let contributions = contributions(&extracts);
+        let rollup: Vec<Extract> = match note_config.rollup {
+            true => source_chapters(extracts.clone())
+                .into_iter()
+                .flat_map(|(heading, notes)| std::iter::once(heading).chain(notes))
+                .collect(),
+            false => vec![],
+        };
+        let words: usize = extracts
+            .iter()
+            .filter(|extract| !extract.heading)
+            .map(|extract| extract.val.split_whitespace().count())
+            .sum();
+        let first_chapter = note_chapters.len();
+
+        let mut layout = Layout {
+            weights: note.weights(&extracts),
+            default_weight: note.order_default,
+            descriptions: note.key_values("descriptions", &note.descriptions, &extracts),
+            icons: note.key_values("icons", &note.icons, &extracts),
+            glossaries: note.glossary_keys(&extracts),
+            ..note_config.layout.clone()
+        };
+        // the sub chapters of the source chapters hold all of their keys
+        if layout.group_by_chapter {
+            layout.key_layout = KeyLayout::Nested;
+            layout.split_top_level = false;
+        }
+
+        // with per-part, the chapters of each part have their own generated
+        // chapters, named after the part
+        let parts = match per_part {
+            false => vec![(None, extracts)],
+            true => part_extracts(extracts),
+        };
+        let mut groups = vec![];
+        for (part, extracts) in parts {
+            let part_name = |name: &str| match part {
+                Some(part) => format!("{} — {}", name, part_titles[part]),
+                None => name.to_string(),
+            };
+            // with split-top-level, the notes without key have their own
+            // chapter, named as the root one would be
+            match layout.split_top_level {
+                false if !extracts.is_empty() => {
+                    groups.push((part, None, part_name(name), extracts))
+                }
+                false => {}
+                true => {
+                    let (own_extracts, sub_keys) = sub_keys(extracts, &[], &layout);
+                    if !own_extracts.is_empty() {
+                        groups.push((part, None, part_name(name), own_extracts));
+                    }
+                    for sub_key in sub_keys {
+                        let key = Some(note.fold(&sub_key.name));
+                        groups.push((part, key, part_name(&sub_key.name), sub_key.list));
+                    }
+                }
+            }
+        }
+
+        for (part, key, name, extracts) in groups {
+            let section = vec![first_section + note_chapters.len() as u32];
+            let slug = match (&part, &key, &note_config.path) {
+                (None, None, Some(path)) => {
+                    slugs.push(path.clone());
+                    path.clone()
+                }
+                _ => unique_slug(&name, &mut slugs),
+            };
+            let path = match (&part, &key, &target_page, &note_config.path_prefix) {
+                (None, None, Some(target_page), _) => target_page.display().to_string(),
+                (_, _, _, Some(prefix)) => format!("{}/{}", prefix, slug),
+                _ => slug,
+            };
+            let counts = match layout.group_by_chapter {
+                true => chapter_counts(&extracts, &layout),
+                false => note_counts(&extracts, true),
+            };
+            let name_counts = match layout.group_by_chapter {
+                true => counts.clone(),
+                false => note_counts(&extracts, layout.count_descendants),
+            };
+            // an outline has no sub chapters, nor have the keys when grouped
+            // by chapter, all of them being on the top page
+            let mut outline_keys: HashSet<Vec<String>> = HashSet::new();
+            if layout.key_layout == KeyLayout::Outline || layout.group_by_chapter {
+                for extract in extracts.iter() {
+                    let levels: Vec<String> =
+                        extract.key.iter().rev().map(|k| note.fold(k)).collect();
+                    for depth in 0..=levels.len() {
+                        outline_keys.insert(levels[..depth].to_vec());
+                    }
+                }
+            }
+            let mut generated = match layout.group_by_chapter {
+                true => generate_by_chapter(extracts, name, path, section, &layout)?,
+                false => generate_chapter(extracts, name, path, vec![], section, &layout)?,
+            };
+
+            let mut generated_pages = chapter_pages(&mut generated.sub_items)?;
+            generated_pages.extend(generated.path.clone());
+            resolve_collisions(&mut generated, &pages, &mut generated_pages, note.strict)?;
+            pages.extend(generated_pages);
+
+            let top = generated.path.clone().unwrap_or_default();
+
+            if layout.toc && !generated.sub_items.is_empty() {
+                let dir = top.parent().unwrap_or_else(|| Path::new(""));
+                let link = |path: &Path| match layout.key_layout {
+                    KeyLayout::Flat => format!("#{}", key_anchor(&top, path)),
+                    _ => match chapter_page(&top, path, layout.max_chapter_depth) {
+                        (page, None) => page_link(page.strip_prefix(dir).unwrap_or(&page)),
+                        (page, Some(anchor)) => format!(
+                            "{}#{}",
+                            page_link(page.strip_prefix(dir).unwrap_or(&page)),
+                            anchor
+                        ),
+                    },
+                };
+                let mut lines = vec![];
+                toc(
+                    &generated.sub_items,
+                    &[],
+                    &counts,
+                    &link,
+                    &layout,
+                    0,
+                    &mut lines,
+                );
+                let toc = lines.join("\n");
+
+                // the list goes after the path heading, which has no blank line
+                generated.content = match generated.content.split_once("\n\n") {
+                    _ if !layout.breadcrumb => format!("{}\n\n{}", toc, generated.content)
+                        .trim_end()
+                        .to_string(),
+                    Some((heading, rest)) => format!("{}\n\n{}\n\n{}", heading, toc, rest),
+                    None => format!("{}\n\n{}", generated.content, toc),
+                };
+            }
+
+            let mut targets = HashMap::new();
+            if !layout.group_by_chapter {
+                note.backlink_targets(
+                    &generated,
+                    key.clone().into_iter().collect(),
+                    &layout,
+                    &mut targets,
+                );
+            }
+            for outline_key in outline_keys {
+                let mut title = vec![generated.name.clone()];
+                title.extend(outline_key.iter().skip(key.iter().count()).cloned());
+                targets
+                    .entry(outline_key)
+                    .or_insert_with(|| (title.join(" / "), top.clone()));
+            }
+
+            if note.backlinks || note.inline_style == InlineStyle::Link {
+                for (target, (title, path)) in targets.iter() {
+                    let path = match layout.key_layout {
+                        KeyLayout::Flat => top.clone(),
+                        _ => chapter_page(&top, path, layout.max_chapter_depth).0,
+                    };
+                    backlinks.insert(target.clone(), (title.clone(), path));
+                }
+            }
+
+            if note_config.chapter_index || note_config.stats {
+                for (target, (_, path)) in targets {
+                    let link = match layout.key_layout {
+                        KeyLayout::Flat if path != top => {
+                            format!("{}#{}", page_link(&top), key_anchor(&top, &path))
+                        }
+                        _ => match chapter_page(&top, &path, layout.max_chapter_depth) {
+                            (page, None) => page_link(&page),
+                            (page, Some(anchor)) => format!("{}#{}", page_link(&page), anchor),
+                        },
+                    };
+                    key_links.insert(target, link);
+                }
+            }
+
+            if layout.show_counts {
+                append_counts(&mut generated.sub_items, &[], &name_counts, &layout);
+                if key.is_some() {
+                    generated.name = format!(
+                        "{} ({})",
+                        generated.name,
+                        name_counts.get(&vec![]).copied().unwrap_or(0)
+                    );
+                }
+            }
+
+            if layout.key_layout == KeyLayout::Flat {
+                let sub_items = std::mem::take(&mut generated.sub_items);
+                let mut blocks = vec![generated.content.clone()];
+                flatten(
+                    sub_items,
+                    &top,
+                    layout.breadcrumb_level,
+                    &layout,
+                    &mut blocks,
+                );
+                blocks.retain(|block| !block.is_empty());
+                generated.content = blocks.join("\n\n");
+            } else if let Some(depth) = layout.max_chapter_depth {
+                flatten_deeper(&mut generated.sub_items, depth - 1, &layout);
+            }
+
+            if let (None, Some(intro)) = (&key, &note_config.intro) {
+                generated.content = match generated.content.is_empty() {
+                    true => intro.trim_end().to_string(),
+                    false => format!("{}\n\n{}", intro.trim_end(), generated.content),
+                };
+            }
+            note_chapters.push(generated);
+            chapter_parts.push(part);
+        }
+
+        if let Some(top) = note_chapters.get_mut(first_chapter) {
+            let fold = |key: &[String]| key.iter().map(|k| note.fold(k)).collect::<Vec<_>>();
+            let link = |key: &[String]| key_links.get(&fold(key)).cloned();
+            if note_config.chapter_index {
+                let index = chapter_index(&contributions, &link, name);
+                let title = layout.strings.index_title.clone();
+                append_page(top, &title, index, &layout)?;
+            }
+            if note_config.stats {
+                let stats = statistics(&contributions, &link, name, words, &layout.strings);
+                let title = layout.strings.stats_title.clone();
+                append_page(top, &title, stats, &layout)?;
+            }
+            if note_config.rollup {
+                // the root of the links is set by the page
+                let rollup_layout = Layout {
+                    key_labels: true,
+                    ..layout.clone()
+                };
+                let blocks = note_blocks(rollup, &[], "{root}", &rollup_layout)?;
+                let title = layout.strings.rollup_title.clone();
+                append_page(top, &title, vec![blocks.join("\n\n")], &layout)?;
+            }
+        }
+
+        let mut headings = HashMap::new();
+        if !backlinks.is_empty() {
+            for generated in note_chapters[first_chapter..].iter() {
+                heading_ids(generated, &mut headings);
+            }
+        }
+        match note_config.mode {
+            // the chapters are passed through as they are, only their
+            // references being checked
+            Mode::CollectOnly => {
+                for item in book.iter() {
+                    if let BookItem::Chapter(chapter) = item {
+                        if !note.is_skipped(chapter) {
+                            note.check_refs(chapter, &ids)?;
+                        }
+                    }
+                }
+            }
+            _ => note.clean_book(book, &ids, &backlinks, &headings)?,
+        }
+    }
+
+    if note_config.mode != Mode::CollectOnly {
+        for_each_chapter_mut(&mut book.sections, &mut |chapter| {
+            if let Some((line, _)) = chapter_directive(&chapter.content) {
+                chapter.content.replace_range(line, "");
+            }
+            Ok(())
+        })?;
+    }
+
+    if note_config.mode != Mode::CleanOnly && !note_config.quiet {
+        info!(
+            "collected {} across {} into {}",
+            count(notes, "note", "notes"),
+            count(chapters.len(), "chapter", "chapters"),
+            count(keys.len(), "key", "keys")
+        );
+    }
+
+    if note_config.mode != Mode::CleanOnly && notes < note_config.min_notes {
+        let note = Note::from_config(config)?;
+        let mut scanned = 0;
+        for_each_chapter_mut(&mut book.sections, &mut |chapter| {
+            if !note.is_skipped(chapter) {
+                scanned += 1;
+            }
+            Ok(())
+        })?;
+
+        return Err(Error::msg(format!(
+            "found {} in {}, preprocessor.note.min-notes requires at least {}",
+            count(notes, "note", "notes"),
+            count(scanned, "scanned chapter", "scanned chapters"),
+            note_config.min_notes
+        )));
+    }
+
+    if let (Some(target), Some(target_page)) = (&note_config.target_chapter, &target_page) {
+        let index = note_chapters
+            .iter()
+            .position(|chapter| chapter.path.as_ref() == Some(target_page));
+        let generated = index.map(|index| {
+            chapter_parts.remove(index);
+            note_chapters.remove(index)
+        });
+        let placeholder = format!("{{{{#{}-output}}}}", note_config.markers[0].0);
+        merge_into_target(&mut book.sections, target, &placeholder, generated)?;
+    }
+
+    if per_part {
+        insert_per_part(
+            &mut book.sections,
+            chapter_parts.into_iter().zip(note_chapters),
+        );
+        return Ok(vec![]);
+    }
+
+    Ok(note_chapters)
+}
+
+/// Write a generated chapter into the target chapter at `placeholder`, or at
+/// its end, its sub chapters becoming the ones of the target, renumbered
+/// under it.
+fn merge_into_target(
+    sections: &mut [BookItem],
+    target: &Path,
+    placeholder: &str,
+    mut generated: Option<Chapter>,
+) -> Result<(), Error> {
+    let mut found = false;
+
+    for_each_chapter_mut(sections, &mut |chapter| {
+        if found || chapter.path.as_deref() != Some(target) {
+            return Ok(());
+        }
+        found = true;
+
+        let content = generated.as_ref().map_or("", |g| g.content.as_str());
+        if chapter.content.contains(placeholder) {
+            chapter.content = chapter.content.replace(placeholder, content);
+        } else if !content.is_empty() {
+            chapter.content = format!("{}\n\n{}", chapter.content.trim_end(), content);
+        }
+
+        if let Some(generated) = generated.take() {
+            let mut parents = chapter.parent_names.clone();
+            parents.push(chapter.name.clone());
+            let mut sub_items = generated.sub_items;
+            adopt_sub_items(&mut sub_items, chapter.number.as_ref(), &parents);
+            chapter.sub_items.extend(sub_items);
+        }
+        Ok(())
+    })?;
+
+    match found {
+        true => Ok(()),
+        false => Err(Error::msg(format!(
+            "preprocessor.note.target-chapter {} not found",
+            target.display()
+        ))),
+    }
+}
+
+/// Number and parent the sub chapters of a generated chapter as the ones of
+/// the chapter numbered `number` with these parent names.
+fn adopt_sub_items(items: &mut [BookItem], number: Option<&SectionNumber>, parents: &[String]) {
+    for item in items.iter_mut() {
+        if let BookItem::Chapter(chapter) = item {
+            chapter.number = match (number, &chapter.number) {
+                (Some(number), Some(own)) => {
+                    let mut levels = number.0.clone();
+                    levels.extend(own.0.iter().skip(1));
+                    Some(SectionNumber(levels))
+                }
+                _ => None,
+            };
+            let mut parent_names = parents.to_vec();
+            parent_names.extend(chapter.parent_names.iter().skip(1).cloned());
+            chapter.parent_names = parent_names;
+            adopt_sub_items(&mut chapter.sub_items, number, parents);
+        }
+    }
+}
+
+/// Split extracts in book order by the part of their source chapter.
+fn part_extracts(extracts: Vec<Extract>) -> Vec<(Option<usize>, Vec<Extract>)> {
+    let mut parts: Vec<(Option<usize>, Vec<Extract>)> = vec![];
+
+    for extract in extracts {
+        match parts.last_mut() {
+            Some((part, list)) if *part == extract.part => list.push(extract),
+            _ => parts.push((extract.part, vec![extract])),
+        }
+    }
+
+    parts
+}
+
+/// Insert the generated chapters at the end of their part, the ones of the
+/// chapters before any part title before the first one, and renumber the
+/// chapters.
+fn insert_per_part(
+    sections: &mut Vec<BookItem>,
+    generated: impl Iterator<Item = (Option<usize>, Chapter)>,
+) {
+    let mut generated: Vec<_> = generated.collect();
+    let mut items = vec![];
+    let mut part = None;
+    let mut flush = |part: Option<usize>, items: &mut Vec<BookItem>| {
+        let (chapters, rest) = std::mem::take(&mut generated)
+            .into_iter()
+            .partition(|(p, _)| *p == part);
+        generated = rest;
+        items.extend(
+            chapters
+                .into_iter()
+                .map(|(_, chapter)| BookItem::Chapter(chapter)),
+        );
+    };
+
+    for item in std::mem::take(sections) {
+        if let BookItem::PartTitle(_) = item {
+            flush(part, &mut items);
+            part = Some(part.map_or(0, |p| p + 1));
+        }
+        items.push(item);
+    }
+    flush(part, &mut items);
+
+    *sections = items;
+    renumber(sections);
+}
+
+/// Date of the last commit of a file, as `2024-05-31`, `None` when it has
+/// none, failing when git can't be run in its directory.
+fn git_date(path: &Path) -> Result<Option<String>, Error> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let output = std::process::Command::new("git")
+        .args(["log", "-1", "--format=%cs", "--"])
+        .arg(path.file_name().unwrap_or_default())
+        .current_dir(dir)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(Error::msg(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    let date = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok(Some(date).filter(|date| !date.is_empty()))
+}
+
+/// Source chapters of the extracts, in book order, with the keys of their
+/// notes.
+fn contributions(extracts: &[Extract]) -> Contributions {
+    let mut res: Contributions = vec![];
+
+    for extract in extracts {
+        if extract.heading {
+            let name = extract.val.trim_start_matches("### ").to_string();
+            // a chapter has a heading per key, all of them before the next chapter
+            if !matches!(res.last(), Some((n, s, _)) if *n == name && *s == extract.source) {
+                res.push((name, extract.source.clone(), vec![]));
+            }
+            continue;
+        }
+
+        if let Some((_, _, keys)) = res.last_mut() {
+            let key: Vec<String> = extract.key.iter().rev().cloned().collect();
+            match keys.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, count)) => *count += 1,
+                None => keys.push((key, 1)),
+            }
+        }
+    }
+
+    res
+}
+
+/// Markdown list of the source chapters with the keys of their notes, linked
+/// with `link` as a path from the book root, `root` naming the notes without
+/// key.
+fn chapter_index(
+    contributions: &Contributions,
+    link: &dyn Fn(&[String]) -> Option<String>,
+    root: &str,
+) -> Vec<String> {
+    let mut lines = vec![];
+
+    for (name, source, keys) in contributions {
+        lines.push(match source {
+            Some(source) => format!("- [{}]({{root}}{})", name, page_link(source)),
+            None => format!("- {}", name),
+        });
+        for (key, count) in keys {
+            let title = match key.is_empty() {
+                true => root.to_string(),
+                false => key.join(" / "),
+            };
+            lines.push(match link(key) {
+                Some(page) => format!("  - [{}]({{root}}{}) ({})", title, page, count),
+                None => format!("  - {} ({})", title, count),
+            });
+        }
+    }
+
+    lines
+}
+
+/// Markdown of the number of notes and of their words, with the tables of the
+/// number of notes per key and per source chapter, the largest first.
+fn statistics(
+    contributions: &Contributions,
+    link: &dyn Fn(&[String]) -> Option<String>,
+    root: &str,
+    words: usize,
+    strings: &Strings,
+) -> Vec<String> {
+    let mut keys: Vec<(Vec<String>, usize)> = vec![];
+    let mut chapters: Vec<(String, usize)> = vec![];
+
+    for (name, source, counts) in contributions {
+        for (key, count) in counts {
+            match keys.iter_mut().find(|(k, _)| k == key) {
+                Some((_, total)) => *total += count,
+                None => keys.push((key.clone(), *count)),
+            }
+        }
+        let name = table_cell(name);
+        chapters.push((
+            match source {
+                Some(source) => format!("[{}]({{root}}{})", name, page_link(source)),
+                None => name,
+            },
+            counts.iter().map(|(_, count)| count).sum(),
+        ));
+    }
+    keys.sort_by_key(|(_, count)| Reverse(*count));
+    chapters.sort_by_key(|(_, count)| Reverse(*count));
+
+    let notes: usize = keys.iter().map(|(_, count)| count).sum();
+    let mut lines = vec![
+        strings
+            .stats_totals
+            .replace("{notes}", &format!("**{}**", notes))
+            .replace("{words}", &format!("**{}**", words)),
+        String::new(),
+        format!("| {} | {} |", strings.key, strings.notes),
+        "| --- | --- |".to_string(),
+    ];
+    for (key, count) in keys {
+        let title = match key.is_empty() {
+            true => table_cell(root),
+            false => table_cell(&key.join(" / ")),
+        };
+        lines.push(match link(&key) {
+            Some(page) => format!("| [{}]({{root}}{}) | {} |", title, page, count),
+            None => format!("| {} | {} |", title, count),
+        });
+    }
+    lines.extend([
+        String::new(),
+        format!("| {} | {} |", strings.chapter, strings.notes),
+        "| --- | --- |".to_string(),
+    ]);
+    for (chapter, count) in chapters {
+        lines.push(format!("| {} | {} |", chapter, count));
+    }
+
+    lines
+}
+
+/// Add a sub chapter named `name` to a generated top level chapter, with the
+/// lines of `lines`, where `{root}` stands for the link to the book root.
+fn append_page(
+    top: &mut Chapter,
+    name: &str,
+    lines: Vec<String>,
+    layout: &Layout,
+) -> Result<(), Error> {
+    let mut slugs: Vec<String> = top
+        .sub_items
+        .iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(chapter) => chapter.path.as_ref(),
+            _ => None,
+        })
+        .filter_map(|path| path.file_name())
+        .map(|slug| slug.to_string_lossy().to_string())
+        .collect();
+    let top_path = top.path.clone().unwrap_or_default();
+    let path = chapter_path(&format!(
+        "{}/{}",
+        top_path.display(),
+        unique_slug(name, &mut slugs)
+    ))?;
+    let root = "../".repeat(path.components().count() - 1);
+
+    let mut content = vec![];
+    if layout.breadcrumb {
+        content.push(format!(
+            "{} {}{}{}",
+            "#".repeat(layout.breadcrumb_level),
+            top.name,
+            layout.breadcrumb_separator,
+            name
+        ));
+    }
+    content.push(lines.join("\n").replace("{root}", &root));
+
+    let number = top.number.clone().map(|mut number| {
+        number.0.push(top.sub_items.len() as u32 + 1);
+        number
+    });
+    top.sub_items.push(BookItem::Chapter(Chapter {
+        name: name.to_string(),
+        content: content.join("\n\n"),
+        number,
+        sub_items: vec![],
+        path: Some(path),
+        source_path: None,
+        parent_names: vec![top.name.clone()],
+    }));
+
+    Ok(())
+}
+
+/// Number of notes of each key of the extracts, and of the keys under it
+/// with `descendants`, the key being its levels from the top one.
+fn note_counts(extracts: &[Extract], descendants: bool) -> HashMap<Vec<String>, usize> {
+    let mut counts = HashMap::new();
+
+    for extract in extracts.iter().filter(|e| !e.heading) {
+        let levels: Vec<String> = extract.key.iter().rev().cloned().collect();
+        let start = if descendants { 0 } else { levels.len() };
+        for depth in start..=levels.len() {
+            *counts.entry(levels[..depth].to_vec()).or_insert(0) += 1;
+        }
+    }
+
+    counts
+}
+
+/// Append to the name of the generated chapters their number of notes in
+/// `counts`, `key` being the levels of their parent.
+fn append_counts(
+    items: &mut [BookItem],
+    key: &[String],
+    counts: &HashMap<Vec<String>, usize>,
+    layout: &Layout,
+) {
+    for item in items.iter_mut() {
+        if let BookItem::Chapter(chapter) = item {
+            let mut sub_key = key.to_vec();
+            sub_key.extend(chapter_levels(chapter, key.len() + 1, layout));
+
+            append_counts(&mut chapter.sub_items, &sub_key, counts, layout);
+            chapter.name = format!(
+                "{} ({})",
+                chapter.name,
+                counts.get(&sub_key).copied().unwrap_or(0)
+            );
+        }
+    }
+}
+
+/// Nested list of links to the generated chapters, `key` being the levels
+/// of their parent and `link` the link to a chapter from its path.
+fn toc(
+    items: &[BookItem],
+    key: &[String],
+    counts: &HashMap<Vec<String>, usize>,
+    link: &dyn Fn(&Path) -> String,
+    layout: &Layout,
+    depth: usize,
+    lines: &mut Vec<String>,
+) {
+    for item in items {
+        if let BookItem::Chapter(chapter) = item {
+            let mut sub_key = key.to_vec();
+            sub_key.extend(chapter_levels(chapter, key.len() + 1, layout));
+
+            if let Some(path) = &chapter.path {
+                lines.push(format!(
+                    "{}- [{}]({}) ({})",
+                    "  ".repeat(depth),
+                    chapter.name,
+                    link(path),
+                    counts.get(&sub_key).copied().unwrap_or(0)
+                ));
+            }
+
+            toc(
+                &chapter.sub_items,
+                &sub_key,
+                counts,
+                link,
+                layout,
+                depth + 1,
+                lines,
+            );
+        }
+    }
+}
+
+/// Key levels a generated chapter adds to the ones of its parent, `depth`
+/// being the number of its parent names before them: its name, after the
+/// ones of the keys collapsed into it with `collapse-single-chains`.
+pub(super) fn chapter_levels(chapter: &Chapter, depth: usize, layout: &Layout) -> Vec<String> {
+    let mut levels = chapter
+        .parent_names
+        .get(depth..)
+        .unwrap_or_default()
+        .to_vec();
+    // the parent names are the levels themselves, without their icon
+    let name = layout
+        .icons
+        .values()
+        .find_map(|icon| chapter.name.strip_prefix(&format!("{} ", icon)))
+        .unwrap_or(&chapter.name);
+    levels.push(name.to_string());
+
+    levels
+}
+
+/// Id of the heading of a key in a flat generated chapter, from the path
+/// the chapter of the key would have under the `top` one.
+fn key_anchor(top: &Path, path: &Path) -> String {
+    let levels: Vec<String> = path
+        .strip_prefix(top)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+
+    format!("key-{}", levels.join("--"))
+}
+
+/// Blocks of the content of the generated chapters, each one starting with
+/// an anchored heading of their name at `level`, one deeper per key level,
+/// `top` being the path of the chapter they go in.
+fn flatten(
+    items: Vec<BookItem>,
+    top: &Path,
+    level: usize,
+    layout: &Layout,
+    blocks: &mut Vec<String>,
+) {
+    for item in items {
+        if let BookItem::Chapter(chapter) = item {
+            let path = chapter.path.clone().unwrap_or_default();
+            blocks.push(format!(
+                "<a id=\"{}\"></a>\n{} {}",
+                key_anchor(top, &path),
+                "#".repeat(level.min(6)),
+                chapter.name
+            ));
+
+            // the path heading is replaced by the key heading
+            let body = match layout.breadcrumb {
+                true => chapter
+                    .content
+                    .split_once("\n\n")
+                    .map_or("", |(_, body)| body),
+                false => &chapter.content,
+            };
+            blocks.push(body.to_string());
+
+            flatten(chapter.sub_items, top, level + 1, layout, blocks);
+        }
+    }
+}
+
+/// Turn the sub chapters of the chapters `depth` levels under `items` into
+/// headings of their content, anchored as in a flat chapter, the links of
+/// their lists of sub chapters pointing to these headings.
+fn flatten_deeper(items: &mut [BookItem], depth: usize, layout: &Layout) {
+    for item in items.iter_mut() {
+        let BookItem::Chapter(chapter) = item else {
+            continue;
+        };
+        if depth > 0 {
+            flatten_deeper(&mut chapter.sub_items, depth - 1, layout);
+            continue;
+        }
+
+        let path = chapter.path.clone().unwrap_or_default();
+        anchor_child_links(chapter, &path);
+        let sub_items = std::mem::take(&mut chapter.sub_items);
+
+        let mut blocks = vec![chapter.content.clone()];
+        flatten(
+            sub_items,
+            &path,
+            layout.breadcrumb_level + 1,
+            layout,
+            &mut blocks,
+        );
+        blocks.retain(|block| !block.is_empty());
+        chapter.content = blocks.join("\n\n");
+    }
+}
+
+/// Point the links of a chapter, and of the ones under it, to their sub
+/// chapters to the headings these become in the chapter at `top`.
+fn anchor_child_links(chapter: &mut Chapter, top: &Path) {
+    let path = chapter.path.clone().unwrap_or_default();
+    let page = PathBuf::from(path.file_name().unwrap_or_default());
+
+    for item in chapter.sub_items.iter_mut() {
+        if let BookItem::Chapter(sub_chapter) = item {
+            let sub_path = sub_chapter.path.clone().unwrap_or_default();
+            let link = page_link(&page.join(sub_path.file_name().unwrap_or_default()));
+            chapter.content = chapter.content.replace(
+                &format!("]({})", link),
+                &format!("](#{})", key_anchor(top, &sub_path)),
+            );
+            anchor_child_links(sub_chapter, top);
+        }
+    }
+}
+
+/// Page of the generated chapter at `path`, under the `top` one, and the
+/// anchor of its heading there when it is deeper than `max_depth` and so a
+/// heading of its ancestor at that depth.
+fn chapter_page(top: &Path, path: &Path, max_depth: Option<usize>) -> (PathBuf, Option<String>) {
+    let levels: Vec<_> = path
+        .strip_prefix(top)
+        .map(|rest| rest.components().collect())
+        .unwrap_or_default();
+
+    match max_depth {
+        Some(max_depth) if levels.len() > max_depth => {
+            let page: PathBuf = top.join(levels[..max_depth].iter().collect::<PathBuf>());
+            let anchor = key_anchor(&page, path);
+            (page, Some(anchor))
+        }
+        _ => (path.to_path_buf(), None),
+    }
+}
+
+/// Pages of the chapters, as their path without extension.
+fn chapter_pages(items: &mut [BookItem]) -> Result<HashSet<PathBuf>, Error> {
+    let mut pages = HashSet::new();
+    for_each_chapter_mut(items, &mut |chapter| {
+        if let Some(path) = &chapter.path {
+            pages.insert(path.with_extension(""));
+        }
+        Ok(())
+    })?;
+
+    Ok(pages)
+}
+
+/// Give the generated chapter, and its sub chapters, whose page is one of
+/// `pages` the first free path suffixed with `-1`, `-2`..., `generated`
+/// being the pages of the generated chapters, or fail in strict mode.
+fn resolve_collisions(
+    chapter: &mut Chapter,
+    pages: &HashSet<PathBuf>,
+    generated: &mut HashSet<PathBuf>,
+    strict: bool,
+) -> Result<(), Error> {
+    if let Some(path) = chapter.path.clone().filter(|path| pages.contains(path)) {
+        if strict {
+            return Err(Error::msg(format!(
+                "note chapter {:?} path {} collides with an existing chapter",
+                chapter.name,
+                path.display()
+            )));
+        }
+
+        let mut n = 1;
+        let renamed = loop {
+            let renamed = PathBuf::from(format!("{}-{}", path.display(), n));
+            if !pages.contains(&renamed) && !generated.contains(&renamed) {
+                break renamed;
+            }
+            n += 1;
+        };
+        warn!(
+            "note chapter {:?} path {} collides with an existing chapter, using {}",
+            chapter.name,
+            path.display(),
+            renamed.display()
+        );
+
+        generated.remove(&path);
+        generated.insert(renamed.clone());
+        chapter.path = Some(renamed.clone());
+        for_each_chapter_mut(&mut chapter.sub_items, &mut |sub_chapter| {
+            if let Some(sub_path) = &sub_chapter.path {
+                if let Ok(rest) = sub_path.strip_prefix(&path) {
+                    let sub_renamed = renamed.join(rest);
+                    generated.remove(sub_path);
+                    generated.insert(sub_renamed.clone());
+                    sub_chapter.path = Some(sub_renamed);
+                }
+            }
+            Ok(())
+        })?;
+    }
+
+    for item in chapter.sub_items.iter_mut() {
+        if let BookItem::Chapter(sub_chapter) = item {
+            resolve_collisions(sub_chapter, pages, generated, strict)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Number the numbered top level chapters in order from 1, their sub
+/// chapters following them.
+pub(super) fn renumber(sections: &mut [BookItem]) {
+    let mut next = 1;
+
+    for item in sections.iter_mut() {
+        if let BookItem::Chapter(chapter) = item {
+            if chapter.number.is_some() {
+                set_top_section(chapter, next);
+                next += 1;
+            }
+        }
+    }
+}
+
+/// Replace the first level of the section number of the chapter and of its
+/// sub chapters.
+fn set_top_section(chapter: &mut Chapter, number: u32) {
+    if let Some(top) = chapter.number.as_mut().and_then(|n| n.0.first_mut()) {
+        *top = number;
+    }
+
+    for item in chapter.sub_items.iter_mut() {
+        if let BookItem::Chapter(sub_chapter) = item {
+            set_top_section(sub_chapter, number);
+        }
+    }
+}
+
+/// Section number following the highest top level one of the book, 1 when
+/// no chapter is numbered.
+fn next_section(book: &Book) -> u32 {
+    book.sections
+        .iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(chapter) => chapter.number.as_ref()?.0.first().copied(),
+            _ => None,
+        })
+        .max()
+        .map_or(1, |max| max + 1)
+}
+
+/// Build the chapter of the extracts, `path` being its slugified location
+/// and `parent` the names of its ancestors.
+pub(super) fn generate_chapter(
+    extracts: Vec<Extract>,
+    name: String,
+    path: String,
+    parent: Vec<String>,
+    section: Vec<u32>,
+    layout: &Layout,
+) -> Result<Chapter, Error> {
+    let mut current_name = parent.clone();
+    current_name.push(name.clone());
+    if layout.icons_in_breadcrumb {
+        // the levels of the key start after the root unless it is split
+        let skip = usize::from(!layout.split_top_level);
+        let levels = current_name.clone();
+        for (i, level) in current_name.iter_mut().enumerate().skip(skip) {
+            if let Some(icon) = layout.icons.get(&levels[skip..=i]) {
+                *level = format!("{} {}", icon, level);
+            }
+        }
+    }
+    if layout.breadcrumb_skip_root && !layout.split_top_level && current_name.len() > 1 {
+        current_name.remove(0);
+    }
+
+    let mut chapter = Chapter {
+        name: name.clone(),
+        content: match layout.breadcrumb {
+            true => format!(
+                "{} {}",
+                "#".repeat(layout.breadcrumb_level),
+                current_name.join(&layout.breadcrumb_separator)
+            ),
+            false => String::new(),
+        },
+        number: match layout.numbered {
+            true => Some(SectionNumber(section.clone())),
+            false => None,
+        },
+        sub_items: vec![],
+        path: Some(chapter_path(&path)?),
+        source_path: None,
+        parent_names: parent.clone(),
+    };
+
+    let mut parent = parent;
+    parent.push(name);
+
+    let key = match layout.split_top_level {
+        true => &parent[..],
+        false => &parent[1..],
+    };
+    let (own_extracts, extract_to_sort) = sub_keys(extracts, key, layout);
+    let breadcrumb = chapter.content.clone();
+    if let Some(icon) = layout.icons.get(key) {
+        chapter.name = format!("{} {}", icon, chapter.name);
+    }
+
+    if let Some(description) = layout.descriptions.get(key) {
+        if !chapter.content.is_empty() {
+            chapter.content = format!("{}\n\n{}", chapter.content, description.trim_end());
+        } else {
+            chapter.content = description.trim_end().to_string();
+        }
+    }
+
+    // a flat chapter holds the content of its sub chapters, the top one
+    // being as deep as its path minus the levels in `parent`, itself included
+    let depth = match layout.key_layout {
+        KeyLayout::Flat => path.matches('/').count() + 1 - parent.len(),
+        _ => path.matches('/').count(),
+    };
+    let root = "../".repeat(depth);
+
+    if layout.key_layout == KeyLayout::Outline {
+        let mut lines = vec![];
+        outline(
+            own_extracts,
+            extract_to_sort,
+            key,
+            &root,
+            layout,
+            0,
+            &mut lines,
+        );
+        if !lines.is_empty() {
+            chapter.content = match chapter.content.is_empty() {
+                true => lines.join("\n"),
+                false => format!("{}\n\n{}", chapter.content, lines.join("\n")),
+            };
+        }
+
+        return Ok(chapter);
+    }
+
+    let blocks = match layout.key_layout {
+        _ if layout.glossaries.contains(key) => glossary(own_extracts, &root, layout),
+        KeyLayout::Table if !own_extracts.is_empty() => {
+            vec![table(sort_by_order(own_extracts), &root, layout)]
+        }
+        KeyLayout::Table => vec![],
+        _ if layout.collapsible && layout.source_headings => {
+            collapsible(sort_by_order(own_extracts), &root, layout)
+        }
+        _ => note_blocks(sort_by_order(own_extracts), key, &root, layout)?,
+    };
+
+    let notes = blocks.join("\n\n");
+    for block in blocks {
+        if !chapter.content.is_empty() {
+            chapter.content = format!("{}\n\n{}", chapter.content, block);
+        } else {
+            chapter.content = block;
+        }
+    }
+
+    let mut slugs: Vec<String> = vec![];
+    let mut children = vec![];
+    let mut counts = vec![];
+    let page = Path::new(&path).file_name().unwrap_or_default().to_owned();
+
+    for (i, extract) in extract_to_sort.into_iter().enumerate() {
+        let mut section = section.clone();
+        section.push(i as u32 + 1);
+
+        // the keys of a chain collapsed into its last one are parents of its
+        // chapter, which has a page of its own
+        let (mut levels, list) =
+            match layout.collapse_chains && layout.key_layout != KeyLayout::Flat {
+                true => collapse_chain(extract, key, layout),
+                false => (vec![extract.name], extract.list),
+            };
+        let unique = unique_slug(&levels.join(" "), &mut slugs);
+        children.push(format!(
+            "- [{}]({})",
+            levels.join(" / "),
+            page_link(&Path::new(&page).join(&unique))
+        ));
+        counts.push(list.iter().filter(|e| !e.heading).count());
+
+        let name = levels.pop().unwrap_or_default();
+        let mut sub_parent = parent.clone();
+        sub_parent.extend(levels);
+        let new_chapter = generate_chapter(
+            list,
+            name,
+            format!("{}/{}", path, unique),
+            sub_parent,
+            section,
+            layout,
+        )?;
+
+        chapter.sub_items.push(BookItem::Chapter(new_chapter));
+    }
+
+    // a chapter of a key with no notes of its own lists the ones under it,
+    // unless they are headings of its page or the top chapter has a toc
+    let empty = notes.is_empty() && !layout.descriptions.contains_key(key);
+    let toc = layout.toc && chapter.parent_names.is_empty();
+    if layout.list_children && empty && !toc && layout.key_layout != KeyLayout::Flat {
+        let list: Vec<String> = children
+            .iter()
+            .zip(counts)
+            .map(|(child, count)| format!("{} ({})", child, count))
+            .collect();
+        if !list.is_empty() {
+            chapter.content = match chapter.content.is_empty() {
+                true => list.join("\n"),
+                false => format!("{}\n\n{}", chapter.content, list.join("\n")),
+            };
+        }
+    }
+
+    if let Some(template) = &layout.chapter_template {
+        let mut data = BTreeMap::new();
+        data.insert("name", chapter.name.clone());
+        data.insert("key", key.join(" / "));
+        data.insert("breadcrumb", breadcrumb);
+        data.insert(
+            "intro",
+            layout
+                .descriptions
+                .get(key)
+                .map(|description| description.trim_end().to_string())
+                .unwrap_or_default(),
+        );
+        data.insert("notes", notes);
+        // the sub chapters of a flat chapter are not pages of their own
+        let children = match layout.key_layout {
+            KeyLayout::Flat => String::new(),
+            _ => children.join("\n"),
+        };
+        data.insert("children", children);
+        chapter.content = render_template(template, &data)?.trim().to_string();
+    }
+
+    Ok(chapter)
+}
+
+/// Levels of a sub key, `key` being the one of its parent, down to the first
+/// one with notes or a description of its own, or several sub keys, with the
+/// extracts under it.
+fn collapse_chain(
+    extracts: Extracts,
+    key: &[String],
+    layout: &Layout,
+) -> (Vec<String>, Vec<Extract>) {
+    let mut levels = vec![extracts.name];
+    let mut list = extracts.list;
+
+    loop {
+        let mut sub_key = key.to_vec();
+        sub_key.extend(levels.iter().cloned());
+        if layout.descriptions.contains_key(&sub_key) {
+            break;
+        }
+
+        let (own_extracts, mut sub_extracts) = sub_keys(list.clone(), &sub_key, layout);
+        if own_extracts.iter().any(|extract| !extract.heading) || sub_extracts.len() != 1 {
+            break;
+        }
+        let sub = sub_extracts.remove(0);
+        levels.push(sub.name);
+        list = sub.list;
+    }
+
+    (levels, list)
+}
+
+/// Source chapters of the extracts, in book order, with their notes.
+fn source_chapters(extracts: Vec<Extract>) -> Vec<(Extract, Vec<Extract>)> {
+    let mut chapters: Vec<(Extract, Vec<Extract>)> = vec![];
+
+    for extract in extracts {
+        if !extract.heading {
+            if let Some((_, notes)) = chapters.last_mut() {
+                notes.push(extract);
+            }
+            continue;
+        }
+
+        // a chapter has a heading per key, all of them before the next chapter
+        match chapters.last() {
+            Some((heading, _))
+                if heading.val == extract.val && heading.source == extract.source => {}
+            _ => chapters.push((extract, vec![])),
+        }
+    }
+
+    chapters
+}
+
+/// Number of notes of each source chapter, keyed on the name of its sub
+/// chapter with `group-by = "chapter"`, and of all of them.
+fn chapter_counts(extracts: &[Extract], layout: &Layout) -> HashMap<Vec<String>, usize> {
+    let mut counts = HashMap::new();
+
+    for (heading, notes) in source_chapters(extracts.to_vec()) {
+        *counts
+            .entry(vec![heading.heading_name(layout)])
+            .or_insert(0) += notes.len();
+        *counts.entry(vec![]).or_insert(0) += notes.len();
+    }
+
+    counts
+}
+
+/// Generated chapter with `group-by = "chapter"`: a sub chapter per source
+/// chapter, in book order, with its notes under a heading per key, the keys
+/// coming in the order of their first note.
+fn generate_by_chapter(
+    extracts: Vec<Extract>,
+    name: String,
+    path: String,
+    section: Vec<u32>,
+    layout: &Layout,
+) -> Result<Chapter, Error> {
+    let mut chapter = generate_chapter(
+        vec![],
+        name.clone(),
+        path.clone(),
+        vec![],
+        section.clone(),
+        layout,
+    )?;
+    let mut slugs: Vec<String> = vec![];
+
+    for (i, (heading, notes)) in source_chapters(extracts).into_iter().enumerate() {
+        let sub_name = heading.heading_name(layout);
+        let sub_path = chapter_path(&format!("{}/{}", path, unique_slug(&sub_name, &mut slugs)))?;
+        let root = "../".repeat(sub_path.components().count() - 1);
+
+        let mut keys: Vec<(Vec<String>, Vec<Extract>)> = vec![];
+        for note in notes {
+            match keys.iter_mut().find(|(key, _)| *key == note.key) {
+                Some((_, key_notes)) => key_notes.push(note),
+                None => keys.push((note.key.clone(), vec![note])),
+            }
+        }
+        // the notes without key come first, without a heading
+        keys.sort_by_key(|(key, _)| !key.is_empty());
+
+        let mut blocks = vec![];
+        if layout.breadcrumb {
+            let mut current_name = vec![name.clone(), sub_name.clone()];
+            if layout.breadcrumb_skip_root {
+                current_name.remove(0);
+            }
+            blocks.push(format!(
+                "{} {}",
+                "#".repeat(layout.breadcrumb_level),
+                current_name.join(&layout.breadcrumb_separator)
+            ));
+        }
+        for (key, key_notes) in keys {
+            if !key.is_empty() {
+                let levels: Vec<String> = key.into_iter().rev().collect();
+                blocks.push(format!(
+                    "{} {}",
+                    "#".repeat(layout.source_level),
+                    levels.join(" / ")
+                ));
+            }
+            blocks.extend(note_blocks(sort_by_order(key_notes), &[], &root, layout)?);
+        }
+
+        let mut number = section.clone();
+        number.push(i as u32 + 1);
+        chapter.sub_items.push(BookItem::Chapter(Chapter {
+            name: sub_name,
+            content: blocks.join("\n\n"),
+            number: match layout.numbered {
+                true => Some(SectionNumber(number)),
+                false => None,
+            },
+            sub_items: vec![],
+            path: Some(sub_path),
+            source_path: None,
+            parent_names: vec![name.clone()],
+        }));
+    }
+
+    Ok(chapter)
+}
+
+/// Lines of a markdown list of the notes of a key, each of them an item, then
+/// of its sub keys, each of them an item with a nested list of its own notes
+/// and sub keys, `depth` being the nesting of the list.
+fn outline(
+    own_extracts: Vec<Extract>,
+    sub_key_extracts: Vec<Extracts>,
+    key: &[String],
+    root: &str,
+    layout: &Layout,
+    depth: usize,
+    lines: &mut Vec<String>,
+) {
+    let indent = "  ".repeat(depth);
+
+    // the lines of a note are indented as its item, to stay inside it
+    for extract in sort_by_order(own_extracts).iter().filter(|e| !e.heading) {
+        for (i, line) in extract.content(root, layout).lines().enumerate() {
+            lines.push(match i {
+                0 => format!("{}- {}", indent, line),
+                _ if line.trim().is_empty() => String::new(),
+                _ => format!("{}  {}", indent, line),
+            });
+        }
+    }
+
+    for sub_key in sub_key_extracts {
+        lines.push(format!("{}- {}", indent, sub_key.name));
+
+        let mut sub_key_levels = key.to_vec();
+        sub_key_levels.push(sub_key.name);
+        let (own, subs) = sub_keys(sub_key.list, &sub_key_levels, layout);
+        outline(own, subs, &sub_key_levels, root, layout, depth + 1, lines);
+    }
+}
+
+/// Blocks of the notes of a glossary key, sorted by their term under a
+/// heading per initial, after an index of these initials.
+fn glossary(extracts: Vec<Extract>, root: &str, layout: &Layout) -> Vec<String> {
+    let mut entries: Vec<(String, String, String, Extract)> = extracts
+        .into_iter()
+        .filter(|extract| !extract.heading)
+        .map(|extract| {
+            let term = extract.term();
+            let initial = initial(&term);
+            // `éclair` sorts as `eclair`
+            let sort: String = initial.to_lowercase() + &term.chars().skip(1).collect::<String>();
+            (initial, sort.to_lowercase(), term, extract)
+        })
+        .collect();
+    match (collator(layout, false), collator(layout, true)) {
+        (Some(compare), Some(primary)) => {
+            // `#` sorts before the letters, which keep their accent in the
+            // index when the locale sorts them apart, as `Ö` in Swedish
+            entries.sort_by(|a, b| {
+                (a.0 != "#")
+                    .cmp(&(b.0 != "#"))
+                    .then_with(|| compare(&a.2, &b.2))
+            });
+            for entry in &mut entries {
+                let letter: String = entry
+                    .2
+                    .chars()
+                    .take(1)
+                    .flat_map(char::to_uppercase)
+                    .collect();
+                if entry.0 != "#" && primary(&entry.0, &letter) != Ordering::Equal {
+                    entry.0 = letter;
+                }
+            }
+        }
+        // `#` sorts before the letters
+        _ => entries.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1))),
+    }
+
+    let mut initials: Vec<String> = entries.iter().map(|entry| entry.0.clone()).collect();
+    initials.dedup();
+    if initials.is_empty() {
+        return vec![];
+    }
+
+    let letter_anchor = |letter: &str| match letter {
+        "#" => "glossary-0".to_string(),
+        _ => format!("glossary-{}", letter.to_lowercase()),
+    };
+    let index: Vec<String> = initials
+        .iter()
+        .map(|letter| format!("[{}](#{})", letter, letter_anchor(letter)))
+        .collect();
+
+    let mut blocks = vec![index.join(" · ")];
+    let mut current = None;
+
+    for (letter, _, _, extract) in entries {
+        if current.as_ref() != Some(&letter) {
+            blocks.push(format!(
+                "<a id=\"{}\"></a>\n\n{} {}",
+                letter_anchor(&letter),
+                "#".repeat(layout.source_level),
+                letter
+            ));
+            current = Some(letter);
+        }
+        blocks.push(extract.content(root, layout));
+    }
+
+    blocks
+}
+
+/// Heading of a glossary term by its first letter: the uppercase letter,
+/// without its accent for the latin ones, or `#` for digits and symbols.
+fn initial(term: &str) -> String {
+    const ACCENTED: [(&str, char); 13] = [
+        ("ÀÁÂÃÄÅĀĂĄ", 'A'),
+        ("ÇĆĈĊČ", 'C'),
+        ("ĎĐ", 'D'),
+        ("ÈÉÊËĒĔĖĘĚ", 'E'),
+        ("ĜĞĠĢ", 'G'),
+        ("ÌÍÎÏĨĪĬĮİ", 'I'),
+        ("ŁĹĻĽĿ", 'L'),
+        ("ÑŃŅŇ", 'N'),
+        ("ÒÓÔÕÖØŌŎŐ", 'O'),
+        ("ŚŜŞŠ", 'S'),
+        ("ÙÚÛÜŨŪŬŮŰŲ", 'U'),
+        ("ÝŸŶ", 'Y'),
+        ("ŹŻŽ", 'Z'),
+    ];
+
+    let first = match term.chars().next() {
+        Some(c) if c.is_alphabetic() => c.to_uppercase().next().unwrap_or(c),
+        _ => return "#".to_string(),
+    };
+
+    ACCENTED
+        .iter()
+        .find(|(accented, _)| accented.contains(first))
+        .map(|&(_, letter)| letter)
+        .unwrap_or(first)
+        .to_string()
+}
+
+/// Comparison of two texts in the order of the `collation` locale, numeric
+/// with `sort = "natural"`, and only by their base letters with `primary`.
+#[cfg(feature = "collation")]
+pub(super) fn collator(layout: &Layout, primary: bool) -> Option<impl Fn(&str, &str) -> Ordering> {
+    use icu::collator::{Collator, CollatorOptions, Numeric, Strength};
+
+    let locale: icu::locid::Locale = layout.collation.as_deref()?.parse().ok()?;
+    let mut options = CollatorOptions::new();
+    if layout.sort == Sort::Natural {
+        options.numeric = Some(Numeric::On);
+    }
+    if primary {
+        options.strength = Some(Strength::Primary);
+    }
+    let collator = Collator::try_new(&(&locale).into(), options).ok()?;
+
+    Some(move |a: &str, b: &str| collator.compare(a, b))
+}
+
+/// Without the `collation` feature, the texts are compared by code point.
+#[cfg(not(feature = "collation"))]
+pub(super) fn collator(_layout: &Layout, _primary: bool) -> Option<fn(&str, &str) -> Ordering> {
+    None
+}
+
+/// Blocks of the extracts of the key `key`, leaving out the source chapter
+/// headings with `source-headings = false`, with `separator` between
+/// consecutive notes, and wrapped in `<div>` elements with `html-wrappers`.
+fn note_blocks(
+    extracts: Vec<Extract>,
+    key: &[String],
+    root: &str,
+    layout: &Layout,
+) -> Result<Vec<String>, Error> {
+    let mut blocks = vec![];
+    let mut after_note = false;
+    let mut chapter: Option<&Extract> = None;
+    let mut index = 0;
+    let mut group = false;
+
+    for extract in extracts.iter() {
+        if extract.heading {
+            chapter = Some(extract);
+            if layout.html_wrappers {
+                if group {
+                    blocks.push("</div>".to_string());
+                }
+                blocks.push(format!(
+                    "<div class=\"mdbook-note-group\" data-note-chapter=\"{}\">",
+                    escape_html(&extract.chapter_id())
+                ));
+                group = true;
+                after_note = false;
+            }
+            if !layout.source_headings {
+                continue;
+            }
+        }
+        if let (true, false, Some(separator)) = (after_note, extract.heading, &layout.separator) {
+            blocks.push(separator.clone());
+        }
+        let block = match &layout.entry_template {
+            Some(template) if !extract.heading => {
+                index += 1;
+                extract.render(template, key, chapter, index, root, layout)?
+            }
+            _ if !extract.heading && layout.entry_style == EntryStyle::Quote => {
+                quote(&extract.content(root, layout), chapter, root, layout)
+            }
+            _ => extract.content(root, layout),
+        };
+        let block = match layout.key_labels && !extract.heading && !extract.key.is_empty() {
+            true => format!(
+                "<small class=\"mdbook-note-key\">{}</small>\n\n{}",
+                escape_html(&extract.key_path(&[]).join(" / ")),
+                block
+            ),
+            false => block,
+        };
+        blocks.push(match layout.html_wrappers && !extract.heading {
+            true => format!(
+                "<div class=\"mdbook-note\" data-note-key=\"{}\" data-note-chapter=\"{}\">\n\n{}\n\n</div>",
+                escape_html(&extract.key_path(key).join("/")),
+                escape_html(&chapter.map(Extract::chapter_id).unwrap_or_default()),
+                block
+            ),
+            false => block,
+        });
+        after_note = !extract.heading;
+    }
+
+    if group {
+        blocks.push("</div>".to_string());
+    }
+
+    Ok(blocks)
+}
+
+/// Blockquote of the markdown of a note, its own blockquotes being nested,
+/// followed by a citation of its source chapter, `chapter` being its heading.
+fn quote(content: &str, chapter: Option<&Extract>, root: &str, layout: &Layout) -> String {
+    let mut lines: Vec<String> = content
+        .lines()
+        .map(|line| match line.is_empty() {
+            true => ">".to_string(),
+            false => format!("> {}", line),
+        })
+        .collect();
+
+    if let Some(chapter) = chapter {
+        let name = chapter.heading_name(layout);
+        lines.push(">".to_string());
+        lines.push(match &chapter.source {
+            Some(source) => format!("> — [{}]({}{})", name, root, page_link(source)),
+            None => format!("> — {}", name),
+        });
+    }
+
+    lines.join("\n")
+}
+
+/// Render a handlebars template with `data`, its expressions being written
+/// as they are, without HTML escaping, as the output is markdown.
+fn render_template(template: &str, data: &BTreeMap<&str, String>) -> Result<String, Error> {
+    let mut handlebars = Handlebars::new();
+    handlebars.set_strict_mode(true);
+    handlebars.register_escape_fn(handlebars::no_escape);
+
+    handlebars
+        .render_template(template, data)
+        .map_err(|e| Error::msg(format!("preprocessor.note template failed: {}", e)))
+}
+
+/// Blocks of the extracts, each source chapter heading and the notes it
+/// introduces being turned into a `<details>` element summarized by the name
+/// of the chapter.
+fn collapsible(extracts: Vec<Extract>, root: &str, layout: &Layout) -> Vec<String> {
+    let mut blocks = vec![];
+    let mut open = false;
+
+    for extract in extracts {
+        if !extract.heading {
+            blocks.push(extract.content(root, layout));
+            continue;
+        }
+
+        if open {
+            blocks.push("</details>".to_string());
+        }
+        let name = escape_html(&extract.heading_name(layout));
+        blocks.push(format!("<details>\n<summary>{}</summary>", name));
+        open = true;
+    }
+
+    if open {
+        blocks.push("</details>".to_string());
+    }
+
+    blocks
+}
+
+/// Markdown table of the notes of the extracts, with a column of their
+/// source chapter with `table-source`.
+fn table(extracts: Vec<Extract>, root: &str, layout: &Layout) -> String {
+    let strings = &layout.strings;
+    let mut rows = match layout.table_source {
+        true => vec![
+            format!(
+                "| {} | {} | {} |",
+                strings.term, strings.definition, strings.source
+            ),
+            "| --- | --- | --- |".to_string(),
+        ],
+        false => vec![
+            format!("| {} | {} |", strings.term, strings.definition),
+            "| --- | --- |".to_string(),
+        ],
+    };
+    let mut source = String::new();
+
+    for extract in extracts {
+        if extract.heading {
+            let heading = extract.content(root, layout);
+            source = table_cell(heading.trim_start_matches('#').trim_start());
+            continue;
+        }
+
+        let (term, definition) = extract.cells(root);
+        rows.push(match layout.table_source {
+            true => format!("| {} | {} | {} |", term, definition, source),
+            false => format!("| {} | {} |", term, definition),
+        });
+    }
+
+    rows.join("\n")
+}
+
+/// Split the extracts between the ones without key and the ones of each
+/// sub key, sorted, `key` being the levels from the top one of the chapter
+/// they are in.
+fn sub_keys(
+    extracts: Vec<Extract>,
+    key: &[String],
+    layout: &Layout,
+) -> (Vec<Extract>, Vec<Extracts>) {
+    let mut extract_by_key = HashMap::new();
+    let mut own_extracts = vec![];
+
+    for (i, extract) in extracts.into_iter().enumerate() {
+        let mut local = extract.clone();
+
+        match local.key.pop() {
+            None => own_extracts.push(extract),
+            Some(k) => {
+                let val = extract_by_key.entry(k.clone()).or_insert_with(|| Extracts {
+                    name: k,
+                    first: i,
+                    list: vec![],
+                });
+                val.list.push(local);
+            }
+        }
+    }
+
+    let mut extract_to_sort: Vec<Extracts> = extract_by_key.into_values().collect();
+
+    let weight = |extract: &Extracts| {
+        let mut key = key.to_vec();
+        key.push(extract.name.clone());
+        layout
+            .weights
+            .get(&key)
+            .copied()
+            .unwrap_or(layout.default_weight)
+    };
+    let collator = collator(layout, false);
+    extract_to_sort.sort_by(|a, b| {
+        weight(a)
+            .cmp(&weight(b))
+            .then_with(|| match (layout.sort, &collator) {
+                (Sort::Appearance, _) => a.first.cmp(&b.first),
+                // the keys equal in the locale, if any, by code point
+                (_, Some(compare)) => compare(&a.name, &b.name).then_with(|| a.name.cmp(&b.name)),
+                (Sort::Alpha, None) => a.name.cmp(&b.name),
+                (Sort::Natural, None) => natural_cmp(&a.name, &b.name),
+            })
+    });
+
+    (own_extracts, extract_to_sort)
+}
+
+/// Slug of `name` not in `slugs`, numbered from 2 when taken, added to them.
+fn unique_slug(name: &str, slugs: &mut Vec<String>) -> String {
+    let slug = slugify(name);
+    let mut unique = slug.clone();
+    let mut n = 1;
+    while slugs.contains(&unique) {
+        n += 1;
+        unique = format!("{}-{}", slug, n);
+    }
+    slugs.push(unique.clone());
+
+    unique
+}
+
+/// Relative path of a generated chapter, made of non-empty `/` separated
+/// segments without characters reserved by file systems or links.
+pub(super) fn chapter_path(path: &str) -> Result<PathBuf, Error> {
+    let valid = path.split('/').all(|segment| {
+        !segment.is_empty()
+            && segment != "."
+            && segment != ".."
+            && !segment
+                .chars()
+                .any(|c| c.is_control() || "\\:*?\"<>|#".contains(c))
+    });
+
+    if !valid {
+        return Err(Error::msg(format!(
+            "note chapter path {:?} is not a valid relative path",
+            path
+        )));
+    }
+
+    Ok(PathBuf::from(path))
+}
+
+/// Path segment for a key: lowercased, spaces and path separators turned
+/// into single dashes and punctuation removed, as mdbook does for heading ids.
+pub(super) fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+
+    for c in name.trim().chars() {
+        if c.is_alphanumeric() || c == '_' {
+            slug.extend(c.to_lowercase());
+        } else if (c.is_whitespace() || c == '-' || c == '/' || c == '\\') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    let slug = slug.trim_matches('-');
+    if slug.is_empty() {
+        "untitled".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+/// Compare two keys as `sort -V` does, runs of digits being compared by
+/// value, and the keys themselves when that leaves them equal, as with
+/// `step 01` and `step 1`.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+
+    loop {
+        match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let mut x_digits = String::new();
+                while let Some(c) = a_chars.next_if(char::is_ascii_digit) {
+                    x_digits.push(c);
+                }
+                let mut y_digits = String::new();
+                while let Some(c) = b_chars.next_if(char::is_ascii_digit) {
+                    y_digits.push(c);
+                }
+
+                let x_digits = x_digits.trim_start_matches('0');
+                let y_digits = y_digits.trim_start_matches('0');
+                let ordering = x_digits
+                    .len()
+                    .cmp(&y_digits.len())
+                    .then_with(|| x_digits.cmp(y_digits));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.cmp(y);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
+
+/// Sort the extracts on their `order` attribute, lower first and unordered
+/// last, keeping each source chapter heading with the extracts it introduces.
+///
+/// A group of extracts is placed by the lowest order it contains, after the
+/// pinned notes, in book order under a copy of their heading.
+fn sort_by_order(extracts: Vec<Extract>) -> Vec<Extract> {
+    let mut pinned: Vec<Extract> = vec![];
+    let mut rest = vec![];
+    let mut heading = None;
+
+    for extract in extracts {
+        if extract.heading {
+            heading = Some(extract.clone());
+        } else if extract.pinned() {
+            if let Some(heading) = heading.take() {
+                pinned.push(heading);
+            }
+            pinned.push(extract);
+            continue;
+        }
+        rest.push(extract);
+    }
+
+    let mut groups: Vec<Vec<Extract>> = vec![];
+
+    for extract in drop_empty_headings(rest) {
+        match groups.last_mut() {
+            Some(group) if !extract.heading => group.push(extract),
+            _ => groups.push(vec![extract]),
+        }
+    }
+
+    for group in groups.iter_mut() {
+        let start = if group[0].heading { 1 } else { 0 };
+        group[start..].sort_by_key(|e| (e.order().is_none(), e.order()));
+    }
+
+    groups.sort_by_key(|group| {
+        let order = group.iter().filter_map(Extract::order).min();
+        (order.is_none(), order)
+    });
+
+    pinned.extend(groups.into_iter().flatten());
+
+    pinned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note::fixture::{
+        chapter, config_error, configs, notes_chapters, preprocessor, render,
+    };
+    use crate::note::render_book;
+
+    #[test]
+    fn test_generate_chapter_sort_appearance() {
+        let extract = |key: &[&str], val: &str| Extract {
+            key: key.iter().rev().map(|k| k.to_string()).collect(),
+            val: val.to_string(),
+            ..Default::default()
+        };
+        let extracts = vec![
+            extract(&["Week 1", "b"], "w1 b"),
+            extract(&["Week 2"], "w2"),
+            extract(&["Week 1", "a"], "w1 a"),
+            extract(&["Week 10"], "w10"),
+            extract(&["Week 1", "b"], "w1 b 2"),
+        ];
+        let names = |chapter: &Chapter| -> Vec<String> {
+            chapter
+                .sub_items
+                .iter()
+                .map(|item| match item {
+                    BookItem::Chapter(c) => c.name.clone(),
+                    _ => unreachable!(),
+                })
+                .collect()
+        };
+        let first = |chapter: &Chapter| match &chapter.sub_items[0] {
+            BookItem::Chapter(c) => c.clone(),
+            _ => unreachable!(),
+        };
+
+        let layout = Layout {
+            sort: Sort::Appearance,
+            ..Default::default()
+        };
+        let chapter = generate_chapter(
+            extracts.clone(),
+            "note".to_string(),
+            "note".to_string(),
+            vec![],
+            vec![1],
+            &layout,
+        )
+        .unwrap();
+        assert_eq!(names(&chapter), vec!["Week 1", "Week 2", "Week 10"]);
+        assert_eq!(names(&first(&chapter)), vec!["b", "a"]);
+        assert_eq!(
+            first(&first(&chapter)).content,
+            "## note / Week 1 / b\n\nw1 b\n\nw1 b 2"
+        );
+
+        let chapter = generate_chapter(
+            extracts,
+            "note".to_string(),
+            "note".to_string(),
+            vec![],
+            vec![1],
+            &Layout::default(),
+        )
+        .unwrap();
+        assert_eq!(names(&chapter), vec!["Week 1", "Week 10", "Week 2"]);
+        assert_eq!(names(&first(&chapter)), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut keys = vec![
+            "step 10", "step 2", "step 100", "step 02", "step", "step 2b", "step 2a", "Step 1",
+            "10", "9",
+        ];
+        keys.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            keys,
+            vec![
+                "9", "10", "Step 1", "step", "step 02", "step 2", "step 2a", "step 2b", "step 10",
+                "step 100",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generate_chapter_sort_natural() {
+        let extract = |key: &[&str]| Extract {
+            key: key.iter().rev().map(|k| k.to_string()).collect(),
+            val: "content".to_string(),
+            ..Default::default()
+        };
+        let extracts = vec![
+            extract(&["step 10", "part 10"]),
+            extract(&["step 2", "part 10"]),
+            extract(&["step 100"]),
+            extract(&["step 2", "part 9"]),
+        ];
+        let names = |chapter: &Chapter| -> Vec<String> {
+            chapter
+                .sub_items
+                .iter()
+                .map(|item| match item {
+                    BookItem::Chapter(c) => c.name.clone(),
+                    _ => unreachable!(),
+                })
+                .collect()
+        };
+
+        let layout = Layout {
+            sort: Sort::Natural,
+            ..Default::default()
+        };
+        let chapter = generate_chapter(
+            extracts,
+            "note".to_string(),
+            "note".to_string(),
+            vec![],
+            vec![1],
+            &layout,
+        )
+        .unwrap();
+        assert_eq!(names(&chapter), vec!["step 2", "step 10", "step 100"]);
+        match &chapter.sub_items[0] {
+            BookItem::Chapter(c) => assert_eq!(names(c), vec!["part 9", "part 10"]),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_generate_chapter_breadcrumb() {
+        let extracts = vec![
+            Extract {
+                key: vec!["a1".to_string(), "a".to_string()],
+                val: "content a1".to_string(),
+                ..Default::default()
+            },
+            Extract {
+                key: vec![],
+                val: "note content".to_string(),
+                ..Default::default()
+            },
+        ];
+        let contents = |layout: &Layout| -> Vec<String> {
+            let chapter = generate_chapter(
+                extracts.clone(),
+                "note".to_string(),
+                "note".to_string(),
+                vec![],
+                vec![1],
+                layout,
+            )
+            .unwrap();
+            let mut items = vec![BookItem::Chapter(chapter)];
+            let mut contents = vec![];
+            for_each_chapter_mut(&mut items, &mut |chapter| {
+                contents.push(chapter.content.clone());
+                Ok(())
+            })
+            .unwrap();
+            contents
+        };
+
+        let layout = Layout {
+            breadcrumb_separator: " › ".to_string(),
+            breadcrumb_skip_root: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            contents(&layout),
+            vec!["## note\n\nnote content", "## a", "## a › a1\n\ncontent a1"]
+        );
+
+        let layout = Layout {
+            breadcrumb: false,
+            ..Default::default()
+        };
+        assert_eq!(contents(&layout), vec!["note content", "", "content a1"]);
+    }
+
+    #[test]
+    fn test_generate_chapter_table() {
+        let extracts = vec![
+            Extract {
+                key: vec!["glossary".to_string()],
+                val: "### Intro".to_string(),
+                heading: true,
+                source: Some("intro.md".into()),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["glossary".to_string()],
+                val: "crate\nA compilation unit.\n\nEither a binary or a library.".to_string(),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["glossary".to_string()],
+                val: "Splits `a|b` on the pipe.".to_string(),
+                title: Some("split".to_string()),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["glossary".to_string()],
+                val: "### Draft".to_string(),
+                heading: true,
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["glossary".to_string()],
+                val: "trait".to_string(),
+                ..Default::default()
+            },
+        ];
+        let glossary = |layout: &Layout| {
+            let chapter = generate_chapter(
+                extracts.clone(),
+                "note".to_string(),
+                "note".to_string(),
+                vec![],
+                vec![1],
+                layout,
+            )
+            .unwrap();
+            match &chapter.sub_items[0] {
+                BookItem::Chapter(c) => c.content.clone(),
+                _ => unreachable!(),
+            }
+        };
+
+        let layout = Layout {
+            key_layout: KeyLayout::Table,
+            ..Default::default()
+        };
+        assert_eq!(
+            glossary(&layout),
+            "## note / glossary
+
+| Term | Definition |
+| --- | --- |
+| crate | A compilation unit.<br><br>Either a binary or a library. |
+| split | Splits `a\\|b` on the pipe. |
+| trait |  |"
+        );
+
+        let layout = Layout {
+            key_layout: KeyLayout::Table,
+            table_source: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            glossary(&layout),
+            "## note / glossary
+
+| Term | Definition | Source |
+| --- | --- | --- |
+| crate | A compilation unit.<br><br>Either a binary or a library. | [Intro](../intro.html) |
+| split | Splits `a\\|b` on the pipe. | [Intro](../intro.html) |
+| trait |  | Draft |"
+        );
+    }
+
+    #[test]
+    fn test_generate_chapter_heading_levels() {
+        let extracts = vec![
+            Extract {
+                key: vec!["b".to_string(), "a".to_string()],
+                val: "### Intro".to_string(),
+                heading: true,
+                source: Some("intro.md".into()),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["b".to_string(), "a".to_string()],
+                val: "content b".to_string(),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a".to_string()],
+                val: "### Draft".to_string(),
+                heading: true,
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a".to_string()],
+                val: "content a".to_string(),
+                ..Default::default()
+            },
+        ];
+        let layout = Layout {
+            breadcrumb_level: 1,
+            source_level: 4,
+            ..Default::default()
+        };
+
+        let chapter = generate_chapter(
+            extracts,
+            "note".to_string(),
+            "note".to_string(),
+            vec![],
+            vec![1],
+            &layout,
+        )
+        .unwrap();
+        assert_eq!(chapter.content, "# note");
+
+        let a = match &chapter.sub_items[0] {
+            BookItem::Chapter(a) => a,
+            _ => unreachable!(),
+        };
+        assert_eq!(a.content, "# note / a\n\n#### Draft\n\ncontent a");
+
+        let b = match &a.sub_items[0] {
+            BookItem::Chapter(b) => b,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            b.content,
+            "# note / a / b\n\n#### [Intro](../../intro.html)\n\ncontent b"
+        );
+    }
+
+    #[test]
+    fn test_generate_chapter_shift_headings() {
+        let extracts = vec![
+            Extract {
+                key: vec!["a".to_string()],
+                val: "### Draft".to_string(),
+                heading: true,
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a".to_string()],
+                val: "## Sub\n\n#### Deeper\n\n```\n## code\n```\n\nSetext\n---\n\n#hashtag\n\n##### Last"
+                    .to_string(),
+                ..Default::default()
+            },
+        ];
+        let layout = Layout {
+            shift_headings: true,
+            ..Default::default()
+        };
+
+        let chapter = generate_chapter(
+            extracts,
+            "note".to_string(),
+            "note".to_string(),
+            vec![],
+            vec![1],
+            &layout,
+        )
+        .unwrap();
+        match &chapter.sub_items[0] {
+            BookItem::Chapter(a) => assert_eq!(
+                a.content,
+                "## note / a\n\n### Draft\n\n#### Sub\n\n###### Deeper\n\n```\n## code\n```\n\n\
+                 Setext\n---\n\n#hashtag\n\n###### Last"
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_generate_chapter_max_inline() {
+        let extracts = vec![
+            Extract {
+                key: vec!["a".to_string()],
+                val: "### Intro".to_string(),
+                heading: true,
+                source: Some("intro.md".into()),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a".to_string()],
+                val: "first\n\n```\nlong\n\ncode\n```\n\nlast".to_string(),
+                source: Some("intro.md".into()),
+                site: Some(2),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a".to_string()],
+                val: "short".to_string(),
+                source: Some("intro.md".into()),
+                ..Default::default()
+            },
+        ];
+        let content = |layout: &Layout| {
+            let chapter = generate_chapter(
+                extracts.clone(),
+                "note".to_string(),
+                "note".to_string(),
+                vec![],
+                vec![1],
+                layout,
+            )
+            .unwrap();
+            match &chapter.sub_items[0] {
+                BookItem::Chapter(a) => a.content.clone(),
+                _ => unreachable!(),
+            }
+        };
+
+        let layout = Layout {
+            max_lines: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(
+            content(&layout),
+            "## note / a\n\n### [Intro](../intro.html)\n\n\
+             first\n\n[… read more](../intro.html#note-2)\n\n[↩](../intro.html#note-2)\n\nshort"
+        );
+
+        let layout = Layout {
+            max_length: Some(26),
+            ..Default::default()
+        };
+        assert!(content(&layout).contains("first\n\n```\nlong\n\ncode\n```\n\n[… read more]"));
+    }
+
+    #[test]
+    fn test_generate_chapter_git_dates() {
+        let extracts = vec![
+            Extract {
+                key: vec!["a".to_string()],
+                val: "### Intro".to_string(),
+                heading: true,
+                source: Some("intro.md".into()),
+                date: Some("2024-05-31".to_string()),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a".to_string()],
+                val: "content".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        let chapter = generate_chapter(
+            extracts,
+            "note".to_string(),
+            "note".to_string(),
+            vec![],
+            vec![1],
+            &Layout::default(),
+        )
+        .unwrap();
+        match &chapter.sub_items[0] {
+            BookItem::Chapter(a) => assert_eq!(
+                a.content,
+                "## note / a\n\n### [Intro](../intro.html)\n\n*Last modified 2024-05-31*\n\ncontent"
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_generate_chapter_source_headings() {
+        let extracts = vec![
+            Extract {
+                key: vec!["a".to_string()],
+                val: "### Intro".to_string(),
+                heading: true,
+                source: Some("intro.md".into()),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a".to_string()],
+                val: "one".to_string(),
+                source: Some("intro.md".into()),
+                site: Some(1),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a".to_string()],
+                val: "two".to_string(),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a".to_string()],
+                val: "### Draft".to_string(),
+                heading: true,
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a".to_string()],
+                val: "three".to_string(),
+                ..Default::default()
+            },
+        ];
+        let content = |layout: &Layout| {
+            let chapter = generate_chapter(
+                extracts.clone(),
+                "note".to_string(),
+                "note".to_string(),
+                vec![],
+                vec![1],
+                layout,
+            )
+            .unwrap();
+            match &chapter.sub_items[0] {
+                BookItem::Chapter(a) => a.content.clone(),
+                _ => unreachable!(),
+            }
+        };
+
+        let layout = Layout {
+            source_headings: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            content(&layout),
+            "## note / a\n\none\n\n[↩](../intro.html#note-1)\n\ntwo\n\nthree"
+        );
+
+        let layout = Layout {
+            separator: Some("---".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            content(&layout),
+            "## note / a\n\n### [Intro](../intro.html)\n\none\n\n[↩](../intro.html#note-1)\n\n---\n\n\
+             two\n\n### Draft\n\nthree"
+        );
+    }
+
+    #[test]
+    fn test_generate_chapter_templates() {
+        let extracts = vec![
+            Extract {
+                key: vec!["a".to_string()],
+                val: "### Intro".to_string(),
+                heading: true,
+                source: Some(PathBuf::from("intro.md")),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a".to_string()],
+                val: "first".to_string(),
+                title: Some("One".to_string()),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a".to_string()],
+                val: "second".to_string(),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["b".to_string()],
+                val: "third".to_string(),
+                ..Default::default()
+            },
+        ];
+        let layout = Layout {
+            entry_template: Some(
+                "{{index}}. {{key}} {{title}}: {{body}} ({{chapter_name}}, {{chapter_path}})"
+                    .to_string(),
+            ),
+            chapter_template: Some("{{breadcrumb}}\n\n{{children}}\n\n{{notes}}".to_string()),
+            ..Default::default()
+        };
+
+        let chapter = generate_chapter(
+            extracts.clone(),
+            "note".to_string(),
+            "note".to_string(),
+            vec![],
+            vec![1],
+            &layout,
+        )
+        .unwrap();
+        assert_eq!(
+            chapter.content,
+            "## note\n\n- [a](note/a.html)\n- [b](note/b.html)"
+        );
+        let contents: Vec<String> = chapter
+            .sub_items
+            .iter()
+            .map(|item| match item {
+                BookItem::Chapter(c) => c.content.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                "## note / a\n\n\n\n### [Intro](../intro.html)\n\n\
+                 1. a One: first (Intro, ../intro.html)\n\n\
+                 2. a : second (Intro, ../intro.html)",
+                "## note / b\n\n\n\n1. b : third (, )",
+            ]
+        );
+
+        let layout = Layout {
+            entry_template: Some("{{nope}}".to_string()),
+            ..Default::default()
+        };
+        let error = generate_chapter(
+            extracts,
+            "note".to_string(),
+            "note".to_string(),
+            vec![],
+            vec![1],
+            &layout,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.starts_with("preprocessor.note template failed: "));
+    }
+
+    #[test]
+    fn test_generate_chapter_list_children() {
+        let extracts = vec![
+            Extract {
+                key: vec!["performance".to_string(), "engineering".to_string()],
+                val: "p1".to_string(),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["performance".to_string(), "engineering".to_string()],
+                val: "p2".to_string(),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["testing".to_string(), "engineering".to_string()],
+                val: "t".to_string(),
+                ..Default::default()
+            },
+            Extract {
+                key: vec![],
+                val: "loose".to_string(),
+                ..Default::default()
+            },
+        ];
+        let layout = Layout {
+            list_children: true,
+            ..Default::default()
+        };
+        let chapter = generate_chapter(
+            extracts,
+            "note".to_string(),
+            "note".to_string(),
+            vec![],
+            vec![1],
+            &layout,
+        )
+        .unwrap();
+
+        // the top chapter has a note of its own
+        assert_eq!(chapter.content, "## note\n\nloose");
+        match &chapter.sub_items[0] {
+            BookItem::Chapter(engineering) => {
+                assert_eq!(
+                    engineering.content,
+                    "## note / engineering
+
+- [performance](engineering/performance.html) (2)
+- [testing](engineering/testing.html) (1)"
+                );
+                match &engineering.sub_items[0] {
+                    BookItem::Chapter(performance) => {
+                        assert_eq!(
+                            performance.content,
+                            "## note / engineering / performance\n\np1\n\np2"
+                        )
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_generate_chapter_collation() {
+        let extract = |key: &str| Extract {
+            key: vec![key.to_string()],
+            val: key.to_string(),
+            ..Default::default()
+        };
+        let extracts = vec![
+            extract("Équipe"),
+            extract("Zèbre"),
+            extract("avion"),
+            extract("École"),
+            extract("Ecole"),
+            extract("Œuvre"),
+        ];
+        let names = |layout: &Layout| -> Vec<String> {
+            generate_chapter(
+                extracts.clone(),
+                "note".to_string(),
+                "note".to_string(),
+                vec![],
+                vec![1],
+                layout,
+            )
+            .unwrap()
+            .sub_items
+            .iter()
+            .map(|item| match item {
+                BookItem::Chapter(c) => c.name.clone(),
+                _ => unreachable!(),
+            })
+            .collect()
+        };
+
+        assert_eq!(
+            names(&Layout::default()),
+            vec!["Ecole", "Zèbre", "avion", "École", "Équipe", "Œuvre"]
+        );
+        // by code point without the collation feature
+        let expected = match cfg!(feature = "collation") {
+            true => vec!["avion", "Ecole", "École", "Équipe", "Œuvre", "Zèbre"],
+            false => vec!["Ecole", "Zèbre", "avion", "École", "Équipe", "Œuvre"],
+        };
+        let layout = Layout {
+            collation: Some("fr".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(names(&layout), expected);
+        let layout = Layout {
+            collation: Some("fr".to_string()),
+            sort: Sort::Natural,
+            ..Default::default()
+        };
+        assert_eq!(names(&layout), expected);
+    }
+
+    #[test]
+    fn test_generate_chapter() {
+        let extracts = vec![
+            Extract {
+                key: vec!["b".to_string()],
+                val: "content b".to_string(),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a1".to_string(), "a".to_string()],
+                val: "content a1".to_string(),
+                ..Default::default()
+            },
+            Extract {
+                key: vec![],
+                val: "note content".to_string(),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a2".to_string(), "a".to_string()],
+                val: "content a2".to_string(),
+                ..Default::default()
+            },
+            Extract {
+                key: vec!["a2".to_string(), "a".to_string()],
+                val: "content a2 2".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        let chapter = Chapter {
+            number: Some(SectionNumber(vec![1])),
+            path: Some("note".parse().unwrap()),
+            ..chapter(
+                "note",
+                "## note\n\nnote content",
+                vec![
+                    BookItem::Chapter(Chapter {
+                        number: Some(SectionNumber(vec![1, 1])),
+                        path: Some("note/a".parse().unwrap()),
+                        parent_names: vec!["note".to_string()],
+                        ..chapter(
+                            "a",
+                            "## note / a",
+                            vec![
+                                BookItem::Chapter(Chapter {
+                                    number: Some(SectionNumber(vec![1, 1, 1])),
+                                    path: Some("note/a/a1".parse().unwrap()),
+                                    parent_names: vec!["note".to_string(), "a".to_string()],
+                                    ..chapter("a1", "## note / a / a1\n\ncontent a1", vec![])
+                                }),
+                                BookItem::Chapter(Chapter {
+                                    number: Some(SectionNumber(vec![1, 1, 2])),
+                                    path: Some("note/a/a2".parse().unwrap()),
+                                    parent_names: vec!["note".to_string(), "a".to_string()],
+                                    ..chapter(
+                                        "a2",
+                                        "## note / a / a2\n\ncontent a2\n\ncontent a2 2",
+                                        vec![],
+                                    )
+                                }),
+                            ],
+                        )
+                    }),
+                    BookItem::Chapter(Chapter {
+                        number: Some(SectionNumber(vec![1, 2])),
+                        path: Some("note/b".parse().unwrap()),
+                        parent_names: vec!["note".to_string()],
+                        ..chapter("b", "## note / b\n\ncontent b", vec![])
+                    }),
+                ],
+            )
+        };
+
+        assert_eq!(
+            generate_chapter(
+                extracts,
+                "note".to_string(),
+                "note".to_string(),
+                vec![],
+                vec![1],
+                &Layout::default(),
+            )
+            .unwrap(),
+            chapter
+        )
+    }
+
+    #[test]
+    fn test_generate_chapter_title() {
+        let extracts = vec![
+            Extract {
+                key: vec![],
+                val: "### source".to_string(),
+                heading: true,
+                ..Default::default()
+            },
+            Extract {
+                key: vec![],
+                val: "no more than 10 calls".to_string(),
+                title: Some("Rate limiting".to_string()),
+                ..Default::default()
+            },
+        ];
+
+        assert_eq!(
+            generate_chapter(
+                extracts,
+                "note".to_string(),
+                "note".to_string(),
+                vec![],
+                vec![1],
+                &Layout::default(),
+            )
+            .unwrap()
+            .content,
+            "## note\n\n### source\n\n**Rate limiting**\n\nno more than 10 calls"
+        )
+    }
+
+    #[test]
+    fn test_generate_chapter_order() {
+        let extract = |val: &str, heading: bool, order: Option<&str>| {
+            let mut attributes = HashMap::new();
+            if let Some(order) = order {
+                attributes.insert("order".to_string(), order.to_string());
+            }
+            Extract {
+                key: vec![],
+                val: val.to_string(),
+                title: None,
+                attributes,
+                heading,
+                source: None,
+                site: None,
+                number: None,
+                line: None,
+                date: None,
+                part: None,
+            }
+        };
+
+        let extracts = vec![
+            extract("### one", true, None),
+            extract("a", false, None),
+            extract("b", false, Some("5")),
+            extract("### two", true, None),
+            extract("c", false, None),
+            extract("### three", true, None),
+            extract("d", false, Some("7")),
+            extract("e", false, Some("1")),
+        ];
+
+        assert_eq!(
+            generate_chapter(
+                extracts,
+                "note".to_string(),
+                "note".to_string(),
+                vec![],
+                vec![1],
+                &Layout::default(),
+            )
+            .unwrap()
+            .content,
+            "## note\n\n### three\n\ne\n\nd\n\n### one\n\nb\n\na\n\n### two\n\nc"
+        )
+    }
+
+    #[test]
+    fn test_generate_chapter_slugs() {
+        let extracts: Vec<Extract> = ["Error handling & panics", "Überblick", "C++", "C#"]
+            .iter()
+            .map(|key| Extract {
+                key: vec![key.to_string()],
+                val: "content".to_string(),
+                ..Default::default()
+            })
+            .collect();
+
+        let chapter = generate_chapter(
+            extracts,
+            "My Notes".to_string(),
+            "my-notes".to_string(),
+            vec![],
+            vec![1],
+            &Layout::default(),
+        )
+        .unwrap();
+
+        let chapters: Vec<(String, String)> = chapter
+            .sub_items
+            .iter()
+            .map(|item| match item {
+                BookItem::Chapter(c) => (
+                    c.name.clone(),
+                    c.path.clone().unwrap().display().to_string(),
+                ),
+                _ => unreachable!(),
+            })
+            .collect();
+
+        assert_eq!(
+            chapters,
+            vec![
+                ("C#".to_string(), "my-notes/c".to_string()),
+                ("C++".to_string(), "my-notes/c-2".to_string()),
+                (
+                    "Error handling & panics".to_string(),
+                    "my-notes/error-handling-panics".to_string()
+                ),
+                ("Überblick".to_string(), "my-notes/überblick".to_string()),
+            ]
+        );
+
+        assert_eq!(slugify("&&"), "untitled");
+    }
+
+    #[test]
+    fn test_generate_chapter_source_links() {
+        let heading = |name: &str, source: Option<&str>| Extract {
+            key: vec!["a".to_string()],
+            val: format!("### {}", name),
+            heading: true,
+            source: source.map(PathBuf::from),
+            ..Default::default()
+        };
+        let extracts = vec![
+            heading("Some name", Some("some/path name.md")),
+            Extract {
+                key: vec!["a".to_string()],
+                val: "linked".to_string(),
+                ..Default::default()
+            },
+            heading("Draft", None),
+            Extract {
+                key: vec!["a".to_string()],
+                val: "plain".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        let chapter = generate_chapter(
+            extracts,
+            "note".to_string(),
+            "note".to_string(),
+            vec![],
+            vec![1],
+            &Layout::default(),
+        )
+        .unwrap();
+
+        match &chapter.sub_items[0] {
+            BookItem::Chapter(c) => assert_eq!(
+                c.content,
+                "## note / a\n\n### [Some name](../some/path%20name.html)\n\nlinked\n\n### Draft\n\nplain"
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_generate_chapter_reserved_path() {
+        // unquoted, `what?: yes/no` is a single-line note of the key `what?`
+        // whose body is `yes/no`, and a leading `/` is an empty key level
+        let chapter = chapter(
+            "some name",
+            "{{#note what?: yes/no}} {{#note \"what?: yes/no\"}}quoted{{#note end}}
+{{#note /why?}}leading{{#note end}}",
+            vec![],
+        );
+
+        let chapter = generate_chapter(
+            Note::new().parse_chapter(&chapter),
+            "note".to_string(),
+            "note".to_string(),
+            vec![],
+            vec![1],
+            &Layout::default(),
+        )
+        .unwrap();
+
+        let sub_chapters: Vec<(&str, Option<&Path>, &str)> = chapter
+            .sub_items
+            .iter()
+            .map(|item| match item {
+                BookItem::Chapter(c) => (c.name.as_str(), c.path.as_deref(), c.content.as_str()),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            sub_chapters,
+            vec![
+                (
+                    "what?",
+                    Some(Path::new("note/what")),
+                    "## note / what?\n\n### some name\n\nyes/no"
+                ),
+                (
+                    "what?: yes/no",
+                    Some(Path::new("note/what-yes-no")),
+                    "## note / what?: yes/no\n\n### some name\n\nquoted"
+                ),
+                (
+                    "why?",
+                    Some(Path::new("note/why")),
+                    "## note / why?\n\n### some name\n\nleading"
+                ),
+            ]
+        );
+
+        assert!(generate_chapter(
+            vec![],
+            "x".to_string(),
+            "/x?".to_string(),
+            vec![],
+            vec![1],
+            &Layout::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_process_intro() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "ch",
+            "{{#note}}loose{{#note end}} {{#note a}}x{{#note end}}",
+            vec![],
+        ));
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]\nintro = \"Notes taken while reading.\\n\"",
+            &mut book.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            chapters[0].content,
+            "Notes taken while reading.\n\n## note\n\n### ch\n\nloose"
+        );
+
+        let root = std::env::temp_dir().join(format!("mdbook-note-intro-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("src/notes-intro.md"), "# Reading notes\n").unwrap();
+
+        let (config, mut note_config) =
+            configs("[preprocessor.note]\nintro-file = \"src/notes-intro.md\"");
+        note_config.read_intro(&root).unwrap();
+        let chapters = note_chapters(&config, &note_config, &mut book).unwrap();
+        assert!(chapters[0]
+            .content
+            .starts_with("# Reading notes\n\n## note\n\n"));
+
+        let mut note_config = NoteConfig::from_config(&config).unwrap();
+        let error = note_config
+            .read_intro(&root.join("missing"))
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("preprocessor.note.intro-file "));
+        assert!(error.contains("notes-intro.md can't be read: "));
+
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            config_error("[preprocessor.note]\nintro = \"x\"\nintro-file = \"x.md\""),
+            "preprocessor.note.intro and intro-file are mutually exclusive"
+        );
+    }
+
+    #[test]
+    fn test_process_section() {
+        let numbered = |name: &str, number: u32, content: &str| Chapter {
+            number: Some(SectionNumber(vec![number])),
+            ..chapter(name, content, vec![])
+        };
+        let mut book = Book::new();
+        book.push_item(chapter("prefix", "", vec![]));
+        book.push_item(numbered("one", 1, "{{#note a}}x{{#note end}}"));
+        book.push_item(BookItem::Separator);
+        book.push_item(numbered("three", 3, "{{#todo a}}y{{#todo end}}"));
+        book.push_item(numbered("two", 2, ""));
+        book.push_item(chapter("suffix", "", vec![]));
+
+        let chapters = notes_chapters(
+            "[preprocessor.note.markers]
+note = \"Notes\"
+empty = \"Empty\"
+todo = \"TODO\"",
+            &mut book.clone(),
+        )
+        .unwrap();
+        let numbers: Vec<_> = chapters.iter().map(|c| c.number.clone()).collect();
+        assert_eq!(
+            numbers,
+            vec![Some(SectionNumber(vec![4])), Some(SectionNumber(vec![5]))]
+        );
+        match &chapters[0].sub_items[0] {
+            BookItem::Chapter(c) => assert_eq!(c.number, Some(SectionNumber(vec![4, 1]))),
+            _ => unreachable!(),
+        }
+
+        let chapters =
+            notes_chapters("[preprocessor.note]\nsection = 99", &mut book.clone()).unwrap();
+        assert_eq!(chapters[0].number, Some(SectionNumber(vec![99])));
+
+        let chapters =
+            notes_chapters("[preprocessor.note]\nnumbered = false", &mut book.clone()).unwrap();
+        assert_eq!(chapters[0].number, None);
+        match &chapters[0].sub_items[0] {
+            BookItem::Chapter(c) => assert_eq!(c.number, None),
+            _ => unreachable!(),
+        }
+
+        assert_eq!(
+            config_error("[preprocessor.note]\nsection = 0"),
+            "preprocessor.note.section must be a positive integer, found 0"
+        );
+    }
+
+    #[test]
+    fn test_process_insert() {
+        let numbered = |name: &str, number: Vec<u32>, sub_items: Vec<BookItem>| Chapter {
+            number: Some(SectionNumber(number)),
+            path: Some(PathBuf::from(format!("{}.md", name.to_lowercase()))),
+            ..chapter(name, "{{#note a}}x{{#note end}}", sub_items)
+        };
+        let mut book = Book::new();
+        book.push_item(numbered("Intro", vec![1], vec![]));
+        book.push_item(numbered("Glossary", vec![2], vec![]));
+        book.push_item(numbered(
+            "Appendix",
+            vec![3],
+            vec![BookItem::Chapter(numbered("Vendor", vec![3, 1], vec![]))],
+        ));
+        let numbers = |book: &Book| -> Vec<(String, Option<SectionNumber>)> {
+            let mut numbers = vec![];
+            for item in book.iter() {
+                if let BookItem::Chapter(chapter) = item {
+                    numbers.push((chapter.name.clone(), chapter.number.clone()));
+                }
+            }
+            numbers
+        };
+        let expected = |list: &[(&str, Vec<u32>)]| -> Vec<(String, Option<SectionNumber>)> {
+            list.iter()
+                .map(|(name, number)| (name.to_string(), Some(SectionNumber(number.clone()))))
+                .collect()
+        };
+
+        let rendered = render(
+            "[preprocessor.note]\ninsert-after = \"Glossary\"",
+            "html",
+            book.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            numbers(&rendered),
+            expected(&[
+                ("Intro", vec![1]),
+                ("Glossary", vec![2]),
+                ("note", vec![3]),
+                ("a", vec![3, 1]),
+                ("Appendix", vec![4]),
+                ("Vendor", vec![4, 1]),
+            ])
+        );
+
+        let rendered = render(
+            "[preprocessor.note]\ninsert-before = \"intro.md\"",
+            "html",
+            book.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            numbers(&rendered),
+            expected(&[
+                ("note", vec![1]),
+                ("a", vec![1, 1]),
+                ("Intro", vec![2]),
+                ("Glossary", vec![3]),
+                ("Appendix", vec![4]),
+                ("Vendor", vec![4, 1]),
+            ])
+        );
+
+        assert_eq!(
+            render("[preprocessor.note]\ninsert-after = \"Glosary\"", "html", book.clone())
+                .unwrap_err()
+                .to_string(),
+            "preprocessor.note.insert-after chapter \"Glosary\" not found, chapters are: Intro, Glossary, Appendix"
+        );
+
+        assert_eq!(
+            config_error(
+                "[preprocessor.note]\ninsert-after = \"Intro\"\ninsert-before = \"Glossary\""
+            ),
+            "preprocessor.note.insert-after and insert-before are mutually exclusive"
+        );
+    }
+
+    #[test]
+    fn test_process_target_chapter() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            number: Some(SectionNumber(vec![1])),
+            path: Some(PathBuf::from("intro.md")),
+            ..chapter(
+                "Intro",
+                "{{#note a}}x{{#note end}}\n\n{{#note a|b}}y{{#note end}}",
+                vec![],
+            )
+        });
+        book.push_item(Chapter {
+            number: Some(SectionNumber(vec![2])),
+            path: Some(PathBuf::from("appendix/notes.md")),
+            ..chapter(
+                "Appendix",
+                "# Notes\n\nCurated.\n\n{{#note-output}}\n\nThe end.",
+                vec![],
+            )
+        });
+        let rendered = render(
+            "[preprocessor.note]\ntarget-chapter = \"appendix/notes.md\"",
+            "html",
+            book.clone(),
+        )
+        .unwrap();
+        assert_eq!(rendered.sections.len(), 2);
+        let BookItem::Chapter(target) = &rendered.sections[1] else {
+            panic!("not a chapter");
+        };
+        assert_eq!(target.content, "# Notes\n\nCurated.\n\n## note\n\nThe end.");
+        let BookItem::Chapter(a) = &target.sub_items[0] else {
+            panic!("not a chapter");
+        };
+        assert_eq!(a.name, "a");
+        assert_eq!(a.number, Some(SectionNumber(vec![2, 1])));
+        assert_eq!(a.path, Some(PathBuf::from("appendix/notes/a")));
+        assert_eq!(a.parent_names, vec!["Appendix"]);
+        let BookItem::Chapter(b) = &a.sub_items[0] else {
+            panic!("not a chapter");
+        };
+        assert_eq!(b.number, Some(SectionNumber(vec![2, 1, 1])));
+        assert_eq!(b.parent_names, vec!["Appendix", "a"]);
+
+        // without the placeholder, the notes go at the end
+        let mut no_placeholder = book.clone();
+        if let BookItem::Chapter(target) = &mut no_placeholder.sections[1] {
+            target.content = "Curated.".to_string();
+        }
+        let rendered = render(
+            "[preprocessor.note]\ntarget-chapter = \"appendix/notes.md\"",
+            "html",
+            no_placeholder,
+        )
+        .unwrap();
+        let BookItem::Chapter(target) = &rendered.sections[1] else {
+            panic!("not a chapter");
+        };
+        assert_eq!(target.content, "Curated.\n\n## note");
+
+        assert_eq!(
+            render(
+                "[preprocessor.note]\ntarget-chapter = \"notes.md\"",
+                "html",
+                book
+            )
+            .unwrap_err()
+            .to_string(),
+            "preprocessor.note.target-chapter notes.md not found"
+        );
+
+        assert_eq!(
+            config_error("[preprocessor.note]\ntarget-chapter = \"notes.md\"\n[preprocessor.note.markers]\ntodo = \"TODO\""),
+            "preprocessor.note.target-chapter can't be set with preprocessor.note.markers"
+        );
+    }
+
+    #[test]
+    fn test_process_templates() {
+        let root = std::env::temp_dir().join(format!("mdbook-note-hbs-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("theme")).unwrap();
+        std::fs::write(root.join("theme/note-entry.hbs"), "- {{body}}").unwrap();
+        std::fs::write(root.join("theme/note-chapter.hbs"), "{{notes").unwrap();
+
+        let (config, mut note_config) =
+            configs("[preprocessor.note]\ntemplate = \"theme/note-entry.hbs\"");
+        note_config.read_templates(&root).unwrap();
+        let mut book = Book::new();
+        book.push_item(chapter("ch", "{{#note}}x{{#note end}}", vec![]));
+        let chapters = note_chapters(&config, &note_config, &mut book).unwrap();
+        assert_eq!(chapters[0].content, "## note\n\n### ch\n\n- x");
+
+        let mut note_config = NoteConfig::from_config(&config).unwrap();
+        let error = note_config
+            .read_templates(&root.join("missing"))
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("preprocessor.note.template "));
+        assert!(error.contains("note-entry.hbs can't be read: "));
+
+        let (_, mut note_config) =
+            configs("[preprocessor.note]\nchapter-template = \"theme/note-chapter.hbs\"");
+        let error = note_config.read_templates(&root).unwrap_err().to_string();
+        assert!(error.starts_with("preprocessor.note.chapter-template "));
+        assert!(error.contains("note-chapter.hbs is not a valid template: "));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_process_per_part() {
+        let numbered = |name: &str, number: u32, content: &str| Chapter {
+            number: Some(SectionNumber(vec![number])),
+            path: Some(PathBuf::from(format!("{}.md", name.to_lowercase()))),
+            ..chapter(name, content, vec![])
+        };
+        let mut book = Book::new();
+        book.push_item(numbered("Intro", 1, "{{#note a}}x{{#note end}}"));
+        book.push_item(BookItem::PartTitle("Basics".to_string()));
+        book.push_item(numbered("One", 2, "{{#note a}}y{{#note end}}"));
+        book.push_item(numbered("Two", 3, "{{#note b}}z{{#note end}}"));
+        book.push_item(BookItem::PartTitle("Advanced".to_string()));
+        book.push_item(numbered("Three", 4, ""));
+        book.push_item(BookItem::PartTitle("Appendix".to_string()));
+        book.push_item(numbered("Four", 5, "{{#note a}}w{{#note end}}"));
+
+        let (config, note_config) = configs("[preprocessor.note]\nper-part = true");
+        let rendered = render_book(&config, &note_config, "html", book.clone()).unwrap();
+
+        let items: Vec<String> = rendered
+            .sections
+            .iter()
+            .map(|item| match item {
+                BookItem::Chapter(chapter) => {
+                    format!("{} {}", chapter.number.as_ref().unwrap(), chapter.name)
+                }
+                BookItem::PartTitle(title) => format!("# {}", title),
+                BookItem::Separator => "---".to_string(),
+            })
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                "1. Intro",
+                "2. note",
+                "# Basics",
+                "3. One",
+                "4. Two",
+                "5. note — Basics",
+                "# Advanced",
+                "6. Three",
+                "# Appendix",
+                "7. Four",
+                "8. note — Appendix",
+            ]
+        );
+
+        let BookItem::Chapter(basics) = &rendered.sections[5] else {
+            panic!("not a chapter");
+        };
+        let names: Vec<&str> = basics
+            .sub_items
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(chapter.name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(basics.path, Some(PathBuf::from("note-basics")));
+
+        // without part titles, the notes chapter is the usual one
+        let mut book = Book::new();
+        book.push_item(numbered("Intro", 1, "{{#note a}}x{{#note end}}"));
+        let rendered = render_book(&config, &note_config, "html", book).unwrap();
+        assert_eq!(rendered.sections.len(), 2);
+    }
+
+    #[test]
+    fn test_process_split_top_level() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("ch.md")),
+            ..chapter(
+                "ch",
+                "{{#note}}loose{{#note end}} {{#note TODO|a1}}x{{#note end}} \
+                 {{#note Glossary}}y{{#note end}} {{#note todo}}z{{#note end}}",
+                vec![],
+            )
+        });
+
+        let (config, note_config) = configs(
+            "[preprocessor.note]
+split-top-level = true
+section = 5
+backlinks = true",
+        );
+        let chapters = note_chapters(&config, &note_config, &mut book.clone()).unwrap();
+
+        let summary: Vec<(String, Option<SectionNumber>, Option<PathBuf>, String)> = chapters
+            .iter()
+            .map(|c| {
+                (
+                    c.name.clone(),
+                    c.number.clone(),
+                    c.path.clone(),
+                    c.content.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "note".to_string(),
+                    Some(SectionNumber(vec![5])),
+                    Some(PathBuf::from("note")),
+                    "## note\n\n### [ch](ch.html)\n\nloose".to_string()
+                ),
+                (
+                    "Glossary".to_string(),
+                    Some(SectionNumber(vec![6])),
+                    Some(PathBuf::from("glossary")),
+                    "## Glossary\n\n### [ch](ch.html)\n\ny".to_string()
+                ),
+                (
+                    "TODO".to_string(),
+                    Some(SectionNumber(vec![7])),
+                    Some(PathBuf::from("todo")),
+                    "## TODO".to_string()
+                ),
+                (
+                    "todo".to_string(),
+                    Some(SectionNumber(vec![8])),
+                    Some(PathBuf::from("todo-2")),
+                    "## todo\n\n### [ch](ch.html)\n\nz".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            chapters[2].sub_items,
+            vec![BookItem::Chapter(Chapter {
+                number: Some(SectionNumber(vec![7, 1])),
+                path: Some(PathBuf::from("todo/a1")),
+                parent_names: vec!["TODO".to_string()],
+                ..chapter("a1", "## TODO / a1\n\n### [ch](../ch.html)\n\nx", vec![])
+            })]
+        );
+
+        note_chapters(&config, &note_config, &mut book).unwrap();
+        match &book.sections[0] {
+            BookItem::Chapter(c) => assert!(c
+                .content
+                .contains("y\n\n[↪ collected in Glossary](glossary.html#a-hrefchhtmlcha)")),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_path_prefix() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("part/ch.md")),
+            ..chapter("ch", "{{#note a|b}}x{{#note end}}", vec![])
+        });
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+path-prefix = \"/generated/notes/\"
+backlinks = true",
+            &mut book,
+        )
+        .unwrap();
+
+        assert_eq!(chapters[0].name, "note");
+        assert_eq!(
+            chapters[0].path,
+            Some(PathBuf::from("generated/notes/note"))
+        );
+        let a = match &chapters[0].sub_items[0] {
+            BookItem::Chapter(a) => a,
+            _ => unreachable!(),
+        };
+        assert_eq!(a.path, Some(PathBuf::from("generated/notes/note/a")));
+        assert_eq!(
+            a.sub_items,
+            vec![BookItem::Chapter(Chapter {
+                number: Some(SectionNumber(vec![1, 1, 1])),
+                path: Some(PathBuf::from("generated/notes/note/a/b")),
+                parent_names: vec!["note".to_string(), "a".to_string()],
+                ..chapter(
+                    "b",
+                    "## note / a / b\n\n### [ch](../../../../part/ch.html)\n\nx",
+                    vec![]
+                )
+            })]
+        );
+        assert_eq!(
+            book.sections[0],
+            BookItem::Chapter(Chapter {
+                path: Some(PathBuf::from("part/ch.md")),
+                ..chapter(
+                    "ch",
+                    "x\n\n[↪ collected in note / a / b](../generated/notes/note/a/b.html#a-hrefpartchhtmlcha)",
+                    vec![]
+                )
+            })
+        );
+
+        assert_eq!(
+            config_error("[preprocessor.note]\npath-prefix = \"../out\""),
+            "note chapter path \"../out\" is not a valid relative path"
+        );
+    }
+
+    #[test]
+    fn test_process_path_collisions() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("note.md")),
+            ..chapter(
+                "note",
+                "{{#note a|b}}x{{#note end}} {{#note c}}y{{#note end}}",
+                vec![BookItem::Chapter(Chapter {
+                    path: Some(PathBuf::from("note-1/c.md")),
+                    ..chapter("c", "", vec![])
+                })],
+            )
+        });
+
+        let chapters = notes_chapters("[book]", &mut book.clone()).unwrap();
+
+        let mut items: Vec<BookItem> = chapters.into_iter().map(BookItem::Chapter).collect();
+        let mut paths = vec![];
+        for_each_chapter_mut(&mut items, &mut |chapter| {
+            paths.push(chapter.path.clone().unwrap());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("note-1"),
+                PathBuf::from("note-1/a"),
+                PathBuf::from("note-1/a/b"),
+                PathBuf::from("note-1/c-1"),
+            ]
+        );
+
+        let (config, note_config) = configs("[preprocessor.note]\nstrict = true");
+        assert_eq!(
+            note_chapters(&config, &note_config, &mut book)
+                .unwrap_err()
+                .to_string(),
+            "note chapter \"note\" path note collides with an existing chapter"
+        );
+    }
+
+    #[test]
+    fn test_process_descriptions() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "ch",
+            "{{#note breaking-changes}}x{{#note end}} {{#note api|v2}}y{{#note end}}",
+            vec![],
+        ));
+
+        let chapters = notes_chapters(
+            "[preprocessor.note.descriptions]
+breaking-changes = \"These notes feed the migration guide.\"
+\"api|v2\" = \"Second version.\"
+stale = \"Never used.\"",
+            &mut book,
+        )
+        .unwrap();
+
+        let mut items: Vec<BookItem> = chapters.into_iter().map(BookItem::Chapter).collect();
+        let mut contents = vec![];
+        for_each_chapter_mut(&mut items, &mut |chapter| {
+            contents.push(chapter.content.clone());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            contents,
+            vec![
+                "## note",
+                "## note / api",
+                "## note / api / v2\n\nSecond version.\n\n### ch\n\ny",
+                "## note / breaking-changes\n\nThese notes feed the migration guide.\n\n### ch\n\nx",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_icons() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "ch",
+            "{{#note warnings}}x{{#note end}} {{#note api|v2}}y{{#note end}}",
+            vec![],
+        ));
+        let chapters = |toml: &str| -> Vec<(String, String)> {
+            let chapters = notes_chapters(toml, &mut book.clone()).unwrap();
+            let mut items: Vec<BookItem> = chapters.into_iter().map(BookItem::Chapter).collect();
+            let mut chapters = vec![];
+            for_each_chapter_mut(&mut items, &mut |chapter| {
+                chapters.push((chapter.name.clone(), chapter.content.clone()));
+                Ok(())
+            })
+            .unwrap();
+            chapters
+        };
+        let expected = |list: &[(&str, &str)]| -> Vec<(String, String)> {
+            list.iter()
+                .map(|(name, content)| (name.to_string(), content.to_string()))
+                .collect()
+        };
+        let icons = "[preprocessor.note.icons]
+warnings = \"⚠️\"
+\"api|v2\" = \"🆕\"
+stale = \"🕸️\"";
+
+        assert_eq!(
+            chapters(&format!(
+                "[preprocessor.note]\nshow-counts = true\n{}",
+                icons
+            )),
+            expected(&[
+                ("note", "## note"),
+                ("api (1)", "## note / api"),
+                ("🆕 v2 (1)", "## note / api / 🆕 v2\n\n### ch\n\ny"),
+                ("⚠️ warnings (1)", "## note / ⚠️ warnings\n\n### ch\n\nx"),
+            ])
+        );
+        assert_eq!(
+            chapters(&format!(
+                "[preprocessor.note]\nicons-in-breadcrumb = false\nsplit-top-level = true\n{}",
+                icons
+            )),
+            expected(&[
+                ("api", "## api"),
+                ("🆕 v2", "## api / v2\n\n### ch\n\ny"),
+                ("⚠️ warnings", "## warnings\n\n### ch\n\nx"),
+            ])
+        );
+
+        assert_eq!(
+            config_error("[preprocessor.note.icons]\nwarnings = 1"),
+            "preprocessor.note.icons.warnings must be a string, found integer"
+        );
+    }
+
+    #[test]
+    fn test_process_glossary() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "One",
+            "{{#note glossary}}**trait** Shared behaviour.{{#note end}}
+{{#note glossary}}éclair is not rust{{#note end}}
+{{#note other}}**zed** stays in book order{{#note end}}",
+            vec![],
+        ));
+        book.push_item(chapter(
+            "Two",
+            "{{#note glossary :: 2FA}}Two factors.{{#note end}}
+{{#note glossary}}__Enum__ A sum type.{{#note end}}
+{{#note glossary}}**Error** A failure.{{#note end}}",
+            vec![],
+        ));
+
+        let chapters = notes_chapters(
+            "[preprocessor.note.key-modes]
+glossary = \"glossary\"
+other = \"notes\"",
+            &mut book,
+        )
+        .unwrap();
+        let contents: Vec<String> = chapters[0]
+            .sub_items
+            .iter()
+            .map(|item| match item {
+                BookItem::Chapter(c) => c.content.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                "## note / glossary
+
+[#](#glossary-0) · [E](#glossary-e) · [T](#glossary-t)
+
+<a id=\"glossary-0\"></a>
+
+### #
+
+**2FA**
+
+Two factors.
+
+<a id=\"glossary-e\"></a>
+
+### E
+
+éclair is not rust
+
+__Enum__ A sum type.
+
+**Error** A failure.
+
+<a id=\"glossary-t\"></a>
+
+### T
+
+**trait** Shared behaviour."
+                    .to_string(),
+                "## note / other\n\n### One\n\n**zed** stays in book order".to_string(),
+            ]
+        );
+
+        assert_eq!(
+            preprocessor("[preprocessor.note.key-modes]\nglossary = \"index\"").err().unwrap().to_string(),
+            "preprocessor.note.key-modes.glossary must be \"notes\" or \"glossary\", found \"index\""
+        );
+    }
+
+    #[test]
+    fn test_process_chapter_index() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("part/one.md")),
+            ..chapter(
+                "One",
+                "{{#note a}}x{{#note end}} {{#note a|b}}y{{#note end}} {{#note a}}z{{#note end}}",
+                vec![],
+            )
+        });
+        book.push_item(chapter("Draft", "{{#note}}root{{#note end}}", vec![]));
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+title = \"Notes\"
+chapter-index = true",
+            &mut book.clone(),
+        )
+        .unwrap();
+        match chapters[0].sub_items.last() {
+            Some(BookItem::Chapter(index)) => {
+                assert_eq!(index.name, "Index by chapter");
+                assert_eq!(index.path, Some(PathBuf::from("notes/index-by-chapter")));
+                assert_eq!(index.number, Some(SectionNumber(vec![1, 2])));
+                assert_eq!(
+                    index.content,
+                    "## Notes / Index by chapter
+
+- [One](../part/one.html)
+  - [a](../notes/a.html) (2)
+  - [a / b](../notes/a/b.html) (1)
+- Draft
+  - [Notes](../notes.html) (1)"
+                );
+            }
+            _ => unreachable!(),
+        }
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+title = \"Notes\"
+chapter-index = true
+layout = \"flat\"",
+            &mut book,
+        )
+        .unwrap();
+        match &chapters[0].sub_items[..] {
+            [BookItem::Chapter(index)] => assert!(index
+                .content
+                .contains("  - [a / b](../notes.html#key-a--b) (1)")),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_git_dates() {
+        // outside of a git repository the dates are left out
+        assert!(git_date(Path::new("/nonexistent/intro.md")).is_err());
+
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("intro.md")),
+            ..chapter("Intro", "{{#note a}}content{{#note end}}", vec![])
+        });
+        let (config, note_config) = configs(
+            "[book]
+src = \"/nonexistent\"
+
+[preprocessor.note]
+git-dates = true",
+        );
+        assert_eq!(note_config.git_dates, Some(PathBuf::from("/nonexistent")));
+        let chapters = note_chapters(&config, &note_config, &mut book).unwrap();
+        match &chapters[0].sub_items[0] {
+            BookItem::Chapter(a) => assert!(!a.content.contains("Last modified")),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_stats() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("one.md")),
+            ..chapter(
+                "One",
+                "{{#note a}}one two{{#note end}} {{#note a|b}}three{{#note end}}",
+                vec![],
+            )
+        });
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("two.md")),
+            ..chapter(
+                "Two",
+                "{{#note a|b}}four five{{#note end}} {{#note a|b}}six{{#note end}} {{#note c}}seven{{#note end}}",
+                vec![],
+            )
+        });
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+chapter-index = true
+stats = true",
+            &mut book,
+        )
+        .unwrap();
+        let names: Vec<String> = chapters[0]
+            .sub_items
+            .iter()
+            .map(|item| match item {
+                BookItem::Chapter(c) => c.name.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(names, vec!["a", "c", "Index by chapter", "Statistics"]);
+        match chapters[0].sub_items.last() {
+            Some(BookItem::Chapter(stats)) => assert_eq!(
+                stats.content,
+                "## note / Statistics
+
+**5** notes, **7** words.
+
+| Key | Notes |
+| --- | --- |
+| [a / b](../note/a/b.html) | 3 |
+| [a](../note/a.html) | 1 |
+| [c](../note/c.html) | 1 |
+
+| Chapter | Notes |
+| --- | --- |
+| [Two](../two.html) | 3 |
+| [One](../one.html) | 2 |"
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_strings() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("one.md")),
+            ..chapter("One", "{{#note a}}one two{{#note end}}", vec![])
+        });
+
+        let chapters = notes_chapters(
+            "[book]
+language = \"fr\"
+
+[preprocessor.note]
+stats = true
+
+[preprocessor.note.strings]
+stats-title = \"Stats\"
+notes = \"Count\"
+
+[preprocessor.note.strings.fr]
+stats-title = \"Statistiques\"
+stats-totals = \"{notes} notes, {words} mots.\"
+chapter = \"Chapitre\"
+
+[preprocessor.note.strings.de]
+stats-title = \"Statistik\"",
+            &mut book,
+        )
+        .unwrap();
+        match chapters[0].sub_items.last() {
+            Some(BookItem::Chapter(stats)) => {
+                assert_eq!(stats.name, "Statistiques");
+                assert_eq!(
+                    stats.content,
+                    "## note / Statistiques
+
+**1** notes, **2** mots.
+
+| Key | Count |
+| --- | --- |
+| [a](../note/a.html) | 1 |
+
+| Chapitre | Count |
+| --- | --- |
+| [One](../one.html) | 1 |"
+                );
+            }
+            _ => unreachable!(),
+        }
+
+        assert_eq!(
+            config_error("[preprocessor.note.strings.de]\nread-mor = \"mehr\""),
+            "unknown preprocessor.note.strings.de.read-mor, the strings are: read-more, \
+             last-modified, index-title, stats-title, rollup-title, stats-totals, term, definition, source, key, \
+             chapter, notes"
+        );
+        assert_eq!(
+            config_error("[preprocessor.note.strings]\nterm = 1"),
+            "preprocessor.note.strings.term must be a string, found integer"
+        );
+    }
+
+    #[test]
+    fn test_process_toc() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "ch",
+            "{{#note}}loose{{#note end}} {{#note api|v2}}a{{#note end}} \
+             {{#note api|v2}}b{{#note end}} {{#note api}}c{{#note end}} \
+             {{#note Getting started}}d{{#note end}}",
+            vec![],
+        ));
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+toc = true
+path-prefix = \"generated\"",
+            &mut book.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            chapters[0].content,
+            "## note
+
+- [Getting started](note/getting-started.html) (1)
+- [api](note/api.html) (3)
+  - [v2](note/api/v2.html) (2)
+
+### ch
+
+loose"
+        );
+        match &chapters[0].sub_items[1] {
+            BookItem::Chapter(api) => assert!(!api.content.contains("- [")),
+            _ => unreachable!(),
+        }
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+toc = true
+breadcrumb = false
+split-top-level = true",
+            &mut book,
+        )
+        .unwrap();
+        assert_eq!(chapters[2].name, "api");
+        assert_eq!(
+            chapters[2].content,
+            "- [v2](api/v2.html) (2)\n\n### ch\n\nc"
+        );
+    }
+
+    #[test]
+    fn test_process_show_counts() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "ch",
+            "{{#note engineering|performance}}a{{#note end}} \
+             {{#note engineering|performance}}b{{#note end}} {{#note engineering}}c{{#note end}}",
+            vec![],
+        ));
+        let summary = |toml: &str| -> Vec<(String, PathBuf)> {
+            let chapters = notes_chapters(toml, &mut book.clone()).unwrap();
+            let mut items: Vec<BookItem> = chapters.into_iter().map(BookItem::Chapter).collect();
+            let mut summary = vec![];
+            for_each_chapter_mut(&mut items, &mut |chapter| {
+                summary.push((chapter.name.clone(), chapter.path.clone().unwrap()));
+                Ok(())
+            })
+            .unwrap();
+            summary
+        };
+        let expected = |list: &[(&str, &str)]| -> Vec<(String, PathBuf)> {
+            list.iter()
+                .map(|(name, path)| (name.to_string(), PathBuf::from(path)))
+                .collect()
+        };
+
+        assert_eq!(
+            summary("[preprocessor.note]\nshow-counts = true"),
+            expected(&[
+                ("note", "note"),
+                ("engineering (3)", "note/engineering"),
+                ("performance (2)", "note/engineering/performance"),
+            ])
+        );
+        assert_eq!(
+            summary("[preprocessor.note]\nshow-counts = true\ncount-descendants = false"),
+            expected(&[
+                ("note", "note"),
+                ("engineering (1)", "note/engineering"),
+                ("performance (2)", "note/engineering/performance"),
+            ])
+        );
+        assert_eq!(
+            summary("[preprocessor.note]\nshow-counts = true\nsplit-top-level = true"),
+            expected(&[
+                ("engineering (3)", "engineering"),
+                ("performance (2)", "engineering/performance"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_process_homonym_chapters() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            number: Some(SectionNumber(vec![3, 4])),
+            path: Some(PathBuf::from("part-one/exercises.md")),
+            ..chapter("Exercises", "{{#note a}}x{{#note end}}", vec![])
+        });
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("part-two/exercises.md")),
+            ..chapter("Exercises", "{{#note a}}y{{#note end}}", vec![])
+        });
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("intro.md")),
+            ..chapter("Intro", "{{#note a}}z{{#note end}}", vec![])
+        });
+
+        let chapters = notes_chapters("[book]", &mut book).unwrap();
+        match &chapters[0].sub_items[0] {
+            BookItem::Chapter(a) => assert_eq!(
+                a.content,
+                "## note / a\n\n\
+                 ### [3.4 Exercises](../part-one/exercises.html)\n\nx\n\n\
+                 ### [Exercises (part-two/exercises.md)](../part-two/exercises.html)\n\ny\n\n\
+                 ### [Intro](../intro.html)\n\nz"
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_section_numbers() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            number: Some(SectionNumber(vec![12, 3])),
+            path: Some(PathBuf::from("advanced.md")),
+            ..chapter("Advanced topics", "{{#note a}}x{{#note end}}", vec![])
+        });
+        book.push_item(Chapter {
+            number: Some(SectionNumber(vec![1])),
+            path: Some(PathBuf::from("one/exercises.md")),
+            ..chapter("Exercises", "{{#note a}}y{{#note end}}", vec![])
+        });
+        book.push_item(Chapter {
+            number: Some(SectionNumber(vec![2])),
+            path: Some(PathBuf::from("two/exercises.md")),
+            ..chapter("Exercises", "{{#note a}}z{{#note end}}", vec![])
+        });
+        book.push_item(chapter("Appendix", "{{#note a}}w{{#note end}}", vec![]));
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]\nshow-section-numbers = true",
+            &mut book,
+        )
+        .unwrap();
+        match &chapters[0].sub_items[0] {
+            BookItem::Chapter(a) => assert_eq!(
+                a.content,
+                "## note / a\n\n\
+                 ### [12.3 Advanced topics](../advanced.html)\n\nx\n\n\
+                 ### [1 Exercises](../one/exercises.html)\n\ny\n\n\
+                 ### [2 Exercises](../two/exercises.html)\n\nz\n\n\
+                 ### Appendix\n\nw"
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_flat_layout() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("part/ch.md")),
+            ..chapter(
+                "ch",
+                "{{#note}}loose{{#note end}} {{#note api|v2}}a{{#note end}} \
+                 {{#note api}}b{{#note end}} {{#note a|b|c|d|e|f}}deep{{#note end}}",
+                vec![],
+            )
+        });
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+layout = \"flat\"
+toc = true
+backlinks = true
+path-prefix = \"generated\"",
+            &mut book,
+        )
+        .unwrap();
+
+        assert_eq!(chapters[0].sub_items, vec![]);
+        assert_eq!(
+            chapters[0].content,
+            "## note
+
+- [a](#key-a) (1)
+  - [b](#key-a--b) (1)
+    - [c](#key-a--b--c) (1)
+      - [d](#key-a--b--c--d) (1)
+        - [e](#key-a--b--c--d--e) (1)
+          - [f](#key-a--b--c--d--e--f) (1)
+- [api](#key-api) (2)
+  - [v2](#key-api--v2) (1)
+
+### [ch](../part/ch.html)
+
+loose
+
+<a id=\"key-a\"></a>
+## a
+
+<a id=\"key-a--b\"></a>
+### b
+
+<a id=\"key-a--b--c\"></a>
+#### c
+
+<a id=\"key-a--b--c--d\"></a>
+##### d
+
+<a id=\"key-a--b--c--d--e\"></a>
+###### e
+
+<a id=\"key-a--b--c--d--e--f\"></a>
+###### f
+
+### [ch](../part/ch.html)
+
+deep
+
+<a id=\"key-api\"></a>
+## api
+
+### [ch](../part/ch.html)
+
+b
+
+<a id=\"key-api--v2\"></a>
+### v2
+
+### [ch](../part/ch.html)
+
+a"
+        );
+
+        match &book.sections[0] {
+            BookItem::Chapter(c) => assert!(c.content.contains(
+                "a\n\n[↪ collected in note / api / v2](../generated/note.html#a-hrefpartchhtmlcha)"
+            )),
+            _ => unreachable!(),
+        }
+
+        assert_eq!(
+            config_error("[preprocessor.note]\nlayout = \"tree\""),
+            "preprocessor.note.layout must be \"nested\", \"flat\", \"table\" or \"outline\", found \"tree\""
+        );
+    }
+
+    #[test]
+    fn test_process_outline_layout() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("part/ch.md")),
+            ..chapter(
+                "ch",
+                "{{#note}}loose{{#note end}} {{#note performance|caching}}\nfirst line\n\n    code\n{{#note end}} \
+                 {{#note performance}}b{{#note end}}",
+                vec![],
+            )
+        });
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+layout = \"outline\"
+backlinks = true",
+            &mut book,
+        )
+        .unwrap();
+
+        assert_eq!(chapters[0].sub_items, vec![]);
+        assert_eq!(
+            chapters[0].content,
+            "## note
+
+- loose
+- performance
+  - b
+  - caching
+    - first line
+
+          code"
+        );
+
+        match &book.sections[0] {
+            BookItem::Chapter(c) => assert!(c
+                .content
+                .contains("[↪ collected in note / performance / caching](../note.html)")),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_group_by_chapter() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("lecture-1.md")),
+            ..chapter(
+                "Lecture 1",
+                "{{#note api|v2}}a{{#note end}} {{#note}}loose{{#note end}} \
+                 {{#note exam}}b{{#note end}} {{#note api|v2}}c{{#note end}}",
+                vec![],
+            )
+        });
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("lecture-2.md")),
+            ..chapter("Lecture 2", "{{#note exam}}d{{#note end}}", vec![])
+        });
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+title = \"Notes\"
+group-by = \"chapter\"
+toc = true
+backlinks = true",
+            &mut book,
+        )
+        .unwrap();
+
+        assert_eq!(
+            chapters[0].content,
+            "## Notes\n\n- [Lecture 1](notes/lecture-1.html) (4)\n- [Lecture 2](notes/lecture-2.html) (1)"
+        );
+        let contents: Vec<(String, String)> = chapters[0]
+            .sub_items
+            .iter()
+            .map(|item| match item {
+                BookItem::Chapter(c) => (c.name.clone(), c.content.clone()),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                (
+                    "Lecture 1".to_string(),
+                    "## Notes / Lecture 1\n\nloose\n\n### api / v2\n\na\n\nc\n\n### exam\n\nb"
+                        .to_string()
+                ),
+                (
+                    "Lecture 2".to_string(),
+                    "## Notes / Lecture 2\n\n### exam\n\nd".to_string()
+                ),
+            ]
+        );
+
+        match &book.sections[1] {
+            BookItem::Chapter(c) => {
+                assert_eq!(c.content, "d\n\n[↪ collected in Notes / exam](notes.html)")
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_html_wrappers() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("ch03.md")),
+            ..chapter(
+                "Three",
+                "{{#note performance|caching}}x{{#note end}} {{#note performance|caching}}y{{#note end}}",
+                vec![],
+            )
+        });
+        book.push_item(chapter(
+            "Draft",
+            "{{#note performance|caching}}z{{#note end}}",
+            vec![],
+        ));
+
+        let (config, note_config) = configs("[preprocessor.note]\nhtml-wrappers = true");
+        let sub_chapter = |renderer: &str| {
+            let rendered = render_book(&config, &note_config, renderer, book.clone()).unwrap();
+            match &rendered.sections[2] {
+                BookItem::Chapter(note) => match &note.sub_items[0] {
+                    BookItem::Chapter(performance) => match &performance.sub_items[0] {
+                        BookItem::Chapter(caching) => caching.content.clone(),
+                        _ => unreachable!(),
+                    },
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            }
+        };
+
+        assert_eq!(
+            sub_chapter("html"),
+            "## note / performance / caching
+
+<div class=\"mdbook-note-group\" data-note-chapter=\"ch03\">
+
+### [Three](../../ch03.html)
+
+<div class=\"mdbook-note\" data-note-key=\"performance/caching\" data-note-chapter=\"ch03\">
+
+x
+
+</div>
+
+<div class=\"mdbook-note\" data-note-key=\"performance/caching\" data-note-chapter=\"ch03\">
+
+y
+
+</div>
+
+</div>
+
+<div class=\"mdbook-note-group\" data-note-chapter=\"Draft\">
+
+### Draft
+
+<div class=\"mdbook-note\" data-note-key=\"performance/caching\" data-note-chapter=\"Draft\">
+
+z
+
+</div>
+
+</div>"
+        );
+        assert_eq!(
+            sub_chapter("epub"),
+            "## note / performance / caching\n\n### [Three](../../ch03.html)\n\nx\n\ny\n\n### Draft\n\nz"
+        );
+    }
+
+    #[test]
+    fn test_process_entry_style() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("part/one.md")),
+            ..chapter(
+                "One",
+                "{{#note a}}\nfirst line\n\n> quoted\n{{#note end}}",
+                vec![],
+            )
+        });
+        book.push_item(chapter("Two", "{{#note a}}short{{#note end}}", vec![]));
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]\nentry-style = \"quote\"",
+            &mut book.clone(),
+        )
+        .unwrap();
+        match &chapters[0].sub_items[0] {
+            BookItem::Chapter(a) => assert_eq!(
+                a.content,
+                "## note / a
+
+### [One](../part/one.html)
+
+> first line
+>
+> > quoted
+>
+> — [One](../part/one.html)
+
+### Two
+
+> short
+>
+> — Two"
+            ),
+            _ => unreachable!(),
+        }
+
+        assert_eq!(
+            config_error("[preprocessor.note]\nentry-style = \"pull\""),
+            "preprocessor.note.entry-style must be \"plain\" or \"quote\", found \"pull\""
+        );
+    }
+
+    #[test]
+    fn test_process_collapse_single_chains() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "ch",
+            "{{#note project|backend|database|indexes}}x{{#note end}} \
+             {{#note project|frontend}}y{{#note end}} {{#note solo|deep}}z{{#note end}}",
+            vec![],
+        ));
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+collapse-single-chains = true
+toc = true
+show-counts = true
+backlinks = true",
+            &mut book,
+        )
+        .unwrap();
+        let mut generated = vec![];
+        for_each_chapter_mut(&mut chapters[0].sub_items.clone(), &mut |c| {
+            generated.push((
+                c.number.as_ref().unwrap().to_string(),
+                c.name.clone(),
+                c.path.as_ref().unwrap().display().to_string(),
+                c.content.split("\n\n").next().unwrap().to_string(),
+            ));
+            Ok(())
+        })
+        .unwrap();
+        let expected = [
+            ("1.1.", "project (2)", "note/project", "## note / project"),
+            (
+                "1.1.1.",
+                "indexes (1)",
+                "note/project/backend-database-indexes",
+                "## note / project / backend / database / indexes",
+            ),
+            (
+                "1.1.2.",
+                "frontend (1)",
+                "note/project/frontend",
+                "## note / project / frontend",
+            ),
+            (
+                "1.2.",
+                "deep (1)",
+                "note/solo-deep",
+                "## note / solo / deep",
+            ),
+        ];
+        let expected: Vec<(String, String, String, String)> = expected
+            .iter()
+            .map(|(a, b, c, d)| (a.to_string(), b.to_string(), c.to_string(), d.to_string()))
+            .collect();
+        assert_eq!(generated, expected);
+
+        assert_eq!(
+            chapters[0].content,
+            "## note
+
+- [project](note/project.html) (2)
+  - [indexes](note/project/backend-database-indexes.html) (1)
+  - [frontend](note/project/frontend.html) (1)
+- [deep](note/solo-deep.html) (1)"
+        );
+        match &book.sections[0] {
+            BookItem::Chapter(ch) => assert!(ch.content.contains(
+                "x\n\n[↪ collected in note / project / backend / database / indexes]\
+                 (note/project/backend-database-indexes.html#ch)"
+            )),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_max_chapter_depth() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "ch",
+            "{{#note a|b|c|d}}deep{{#note end}} {{#note a|b}}shallow{{#note end}}",
+            vec![],
+        ));
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+max-chapter-depth = 2
+toc = true
+backlinks = true
+list-children = true",
+            &mut book,
+        )
+        .unwrap();
+        assert_eq!(
+            chapters[0].content,
+            "## note
+
+- [a](note/a.html) (2)
+  - [b](note/a/b.html) (2)
+    - [c](note/a/b.html#key-c) (1)
+      - [d](note/a/b.html#key-c--d) (1)"
+        );
+        let BookItem::Chapter(a) = &chapters[0].sub_items[0] else {
+            panic!("not a chapter");
+        };
+        let BookItem::Chapter(b) = &a.sub_items[0] else {
+            panic!("not a chapter");
+        };
+        assert!(b.sub_items.is_empty());
+        assert_eq!(
+            b.content,
+            "## note / a / b
+
+### ch
+
+shallow
+
+<a id=\"key-c\"></a>
+### c
+
+- [d](#key-c--d) (1)
+
+<a id=\"key-c--d\"></a>
+#### d
+
+### ch
+
+deep"
+        );
+        match &book.sections[0] {
+            BookItem::Chapter(ch) => assert!(ch
+                .content
+                .contains("deep\n\n[↪ collected in note / a / b / c / d](note/a/b.html#ch)")),
+            _ => unreachable!(),
+        }
+
+        assert_eq!(
+            config_error("[preprocessor.note]\nmax-chapter-depth = 0"),
+            "preprocessor.note.max-chapter-depth must be a positive integer, found 0"
+        );
+    }
+
+    #[test]
+    fn test_process_pinned() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "One",
+            "{{#note a}}first{{#note end}} {{#note a order=1}}ordered{{#note end}}",
+            vec![],
+        ));
+        book.push_item(chapter(
+            "Two",
+            "{{#note a pinned}}definition{{#note end}} {{#note a}}second{{#note end}}",
+            vec![],
+        ));
+        book.push_item(chapter(
+            "Three",
+            "{{#note a, pinned}}other definition{{#note end}}",
+            vec![],
+        ));
+
+        let chapters = notes_chapters("[preprocessor.note]", &mut book.clone()).unwrap();
+        match &chapters[0].sub_items[0] {
+            BookItem::Chapter(a) => assert_eq!(
+                a.content,
+                "## note / a
+
+### Two
+
+📌 definition
+
+### Three
+
+📌 other definition
+
+### One
+
+ordered
+
+first
+
+### Two
+
+second"
+            ),
+            _ => unreachable!(),
+        }
+
+        let chapters =
+            notes_chapters("[preprocessor.note]\npinned-prefix = \"\"", &mut book).unwrap();
+        match &chapters[0].sub_items[0] {
+            BookItem::Chapter(a) => assert!(a
+                .content
+                .starts_with("## note / a\n\n### Two\n\ndefinition")),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_entry_separator() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "One",
+            "{{#note}}a{{#note end}} {{#note}}b{{#note end}}",
+            vec![],
+        ));
+        book.push_item(chapter("Two", "{{#note}}c{{#note end}}", vec![]));
+        let content = |toml: &str| -> Result<String, Error> {
+            let chapters = notes_chapters(toml, &mut book.clone())?;
+            Ok(chapters[0].content.clone())
+        };
+
+        assert_eq!(
+            content("[preprocessor.note]\nentry-separator = \"---\"").unwrap(),
+            "## note\n\n### One\n\na\n\n---\n\nb\n\n### Two\n\nc"
+        );
+        assert_eq!(
+            content("[preprocessor.note]\nentry-separator = \"---\"").unwrap(),
+            content("[preprocessor.note]\nnote-separator = \"---\"").unwrap()
+        );
+        assert_eq!(
+            content("[preprocessor.note]\nentry-separator = \"\"").unwrap(),
+            content("[preprocessor.note]").unwrap()
+        );
+        assert_eq!(
+            content("[preprocessor.note]\nnote-separator = \"\"").unwrap(),
+            content("[preprocessor.note]").unwrap()
+        );
+        assert_eq!(
+            content("[preprocessor.note]\nnote-separator = \"***\"\nentry-separator = \"---\"")
+                .unwrap_err()
+                .to_string(),
+            "preprocessor.note.note-separator and entry-separator are mutually exclusive"
+        );
+    }
+
+    #[test]
+    fn test_process_rollup() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("part/one.md")),
+            ..chapter(
+                "One",
+                "{{#note b}}x{{#note end}} {{#note a|b}}y <{{#note end}} {{#note}}z{{#note end}}",
+                vec![],
+            )
+        });
+        book.push_item(chapter("Two", "{{#note a}}w{{#note end}}", vec![]));
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+title = \"Notes\"
+rollup = true
+entry-separator = \"---\"
+
+[preprocessor.note.strings]
+rollup-title = \"Read-through\"",
+            &mut book,
+        )
+        .unwrap();
+        match chapters[0].sub_items.last() {
+            Some(BookItem::Chapter(rollup)) => {
+                assert_eq!(rollup.name, "Read-through");
+                assert_eq!(rollup.path, Some(PathBuf::from("notes/read-through")));
+                assert_eq!(
+                    rollup.content,
+                    "## Notes / Read-through
+
+### [One](../part/one.html)
+
+<small class=\"mdbook-note-key\">b</small>
+
+x
+
+---
+
+<small class=\"mdbook-note-key\">a / b</small>
+
+y <
+
+---
+
+z
+
+### Two
+
+<small class=\"mdbook-note-key\">a</small>
+
+w"
+                );
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_collapsible() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("one.md")),
+            ..chapter(
+                "One & only",
+                "{{#note a}}x{{#note end}} {{#note a}}y{{#note end}}",
+                vec![],
+            )
+        });
+        book.push_item(chapter("Two", "{{#note a}}z{{#note end}}", vec![]));
+
+        let (config, note_config) = configs("[preprocessor.note]\ncollapsible = true");
+        let sub_chapter = |renderer: &str| {
+            let rendered = render_book(&config, &note_config, renderer, book.clone()).unwrap();
+            match &rendered.sections[2] {
+                BookItem::Chapter(note) => match &note.sub_items[0] {
+                    BookItem::Chapter(a) => a.content.clone(),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            }
+        };
+
+        assert_eq!(
+            sub_chapter("html"),
+            "## note / a
+
+<details>
+<summary>One &amp; only</summary>
+
+x
+
+y
+
+</details>
+
+<details>
+<summary>Two</summary>
+
+z
+
+</details>"
+        );
+        assert_eq!(
+            sub_chapter("epub"),
+            "## note / a\n\n### [One & only](../one.html)\n\nx\n\ny\n\n### Two\n\nz"
+        );
+    }
+
+    #[test]
+    fn test_process_provenance() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("ch03/async.md")),
+            ..chapter(
+                "Async",
+                "# Async\n\n{{#todo a}}\ntodo\n{{#todo end}}\n\n{{#note a}}\nfirst\n{{#note end}}\n\n{{#todo a}}todo{{#todo end}} {{#note a}}second{{#note end}}",
+                vec![],
+            )
+        });
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+provenance = true
+
+[preprocessor.note.markers]
+todo = \"TODO\"
+note = \"Notes\"",
+            &mut book.clone(),
+        )
+        .unwrap();
+        let contents: Vec<String> = chapters
+            .iter()
+            .map(|c| match &c.sub_items[0] {
+                BookItem::Chapter(a) => a.content.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                "## Notes / a\n\n### [Async](../ch03/async.html)\n\n\
+                 first\n\n— ch03/async.md:7\n\nsecond\n\n— ch03/async.md:11",
+                "## TODO / a\n\n### [Async](../ch03/async.html)\n\n\
+                 todo\n\n— ch03/async.md:3\n\ntodo\n\n— ch03/async.md:11",
+            ]
+        );
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+provenance = true
+provenance-template = \"<small>src/{path}, line {line}</small>\"",
+            &mut book,
+        )
+        .unwrap();
+        match &chapters[0].sub_items[0] {
+            BookItem::Chapter(a) => assert!(a
+                .content
+                .ends_with("second\n\n<small>src/ch03/async.md, line 11</small>")),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_relative_links() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("part1/chapter.md")),
+            ..chapter(
+                "Chapter",
+                "{{#note a}}
+![diagram](./img/flow.png) [intro](../intro.md#start) [next](next.md \"Next\")
+[site](https://example.com) [top](#top) [abs](/abs.html) `[code](code.md)`
+[reference][ref]
+
+[ref]: ../ref.md
+{{#note end}}",
+                vec![],
+            )
+        });
+
+        let chapters = notes_chapters("[preprocessor.note]", &mut book).unwrap();
+        match &chapters[0].sub_items[0] {
+            BookItem::Chapter(a) => assert_eq!(
+                a.content,
+                "## note / a
+
+### [Chapter](../part1/chapter.html)
+
+![diagram](../part1/img/flow.png) [intro](../intro.md#start) [next](../part1/next.md \"Next\")
+[site](https://example.com) [top](#top) [abs](/abs.html) `[code](code.md)`
+[reference][ref]
+
+[ref]: ../ref.md"
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_footnotes() {
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("ch03.md")),
+            ..chapter(
+                "Three",
+                "{{#note a}}\nx[^1] `[^2]`\n\n[^1]: inside\n{{#note end}}",
+                vec![],
+            )
+        });
+        book.push_item(Chapter {
+            path: Some(PathBuf::from("part/ch04.md")),
+            ..chapter(
+                "Four",
+                "{{#note a}}y[^1]{{#note end}}\n\n[^1]: outside\n    continued\n\nText",
+                vec![],
+            )
+        });
+
+        let chapters = notes_chapters("[preprocessor.note]", &mut book).unwrap();
+        match &chapters[0].sub_items[0] {
+            BookItem::Chapter(a) => assert_eq!(
+                a.content,
+                "## note / a
+
+### [Three](../ch03.html)
+
+x[^ch03-1] `[^2]`
+
+[^ch03-1]: inside
+
+### [Four](../part/ch04.html)
+
+y[^part-ch04-1]
+
+[^part-ch04-1]: outside
+    continued"
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process_order() {
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "ch",
+            "{{#note Misc}}m{{#note end}} {{#note Introduction}}i{{#note end}} \
+             {{#note Engineering|Performance}}p{{#note end}} {{#note engineering|build}}b{{#note end}} \
+             {{#note Basics}}c{{#note end}}",
+            vec![],
+        ));
+
+        let chapters = notes_chapters(
+            "[preprocessor.note]
+normalize-keys = \"lowercase\"
+order-default = 1
+
+[preprocessor.note.order]
+Introduction = -10
+misc = 100
+Basics = 1
+\"engineering|performance\" = -5
+typo = 3",
+            &mut book,
+        )
+        .unwrap();
+
+        let names = |chapter: &Chapter| -> Vec<String> {
+            chapter
+                .sub_items
+                .iter()
+                .map(|item| match item {
+                    BookItem::Chapter(c) => c.name.clone(),
+                    _ => unreachable!(),
+                })
+                .collect()
+        };
+        assert_eq!(
+            names(&chapters[0]),
+            vec!["Introduction", "Basics", "Engineering", "Misc"]
+        );
+        match &chapters[0].sub_items[2] {
+            BookItem::Chapter(c) => assert_eq!(names(c), vec!["Performance", "build"]),
+            _ => unreachable!(),
+        }
+
+        assert_eq!(
+            preprocessor("[preprocessor.note.order]\nIntroduction = \"first\"")
+                .err()
+                .unwrap()
+                .to_string(),
+            "preprocessor.note.order.Introduction must be an integer, found string"
+        );
+    }
+}
@@ -0,0 +1,2257 @@
+use log::warn;
+use mdbook::book::Chapter;
+use mdbook::errors::Error;
+use regex::{Captures, Regex, RegexBuilder};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+
+use super::clean::deep_link_offset;
+use super::generate::slugify;
+use super::util::{
+    capture, code_fences, code_spans, footnotes, heading_start, line_number, location,
+    namespace_footnotes, paragraph_end, rebase_link, rewrite_links, undefined_references,
+};
+use super::{Extract, Note};
+
+/// Attributes which can be written as a bare word after the key, `hidden`
+/// meaning `hidden=true`, the same words before the end of the key being part
+/// of it as in `{{#note hidden gems}}`.
+const FLAGS: [&str; 3] = ["hidden", "local", "pinned"];
+
+/// The content of an opening marker: `keys :: title`, the keys being
+/// separated by `,` or `||` and mixed with `name=value` attributes.
+pub(super) struct Marker {
+    pub(super) keys: Vec<String>,
+    pub(super) title: Option<String>,
+    pub(super) attributes: HashMap<String, String>,
+    /// Problems found while parsing the marker, reported by `check_chapter`.
+    warnings: Vec<String>,
+}
+
+/// A `{{#note key}}...{{#note end}}` block, or a single-line
+/// `{{#note key: body}}` note, as byte ranges of the chapter content.
+pub(super) struct Block {
+    pub(super) key: String,
+    pub(super) span: Range<usize>,
+    pub(super) body: Range<usize>,
+    /// Whether the opening marker is `{{#note! key}}`.
+    local: bool,
+}
+
+/// The markers of a chapter content, paired into blocks.
+#[derive(Default)]
+pub(super) struct Scan {
+    pub(super) blocks: Vec<Block>,
+    /// Opening markers not followed by an end marker before the next opening
+    /// marker or the end of the chapter.
+    unclosed: Vec<Range<usize>>,
+    /// End markers without an opening marker.
+    pub(super) orphans: Vec<Range<usize>>,
+    /// Offsets of the backslashes escaping a marker, as in `\{{#note key}}`.
+    pub(super) escapes: Vec<usize>,
+    /// `{{#note-exclude}}` and `{{#note-exclude end}}` markers.
+    pub(super) exclude_markers: Vec<Range<usize>>,
+    /// Markers inside exclude regions, left as they are.
+    pub(super) excluded: Vec<Range<usize>>,
+    /// Text looking like a marker but not matching any syntax.
+    malformed: Vec<Range<usize>>,
+    /// `{{#note-ref id}}` markers, with their id.
+    pub(super) refs: Vec<(Range<usize>, String)>,
+}
+
+impl Scan {
+    /// Text of a content range, without the backslashes escaping markers.
+    pub(super) fn text(&self, content: &str, range: Range<usize>) -> String {
+        let mut text = String::new();
+        let mut last = range.start;
+
+        for &escape in self.escapes.iter().filter(|e| range.contains(e)) {
+            text.push_str(&content[last..escape]);
+            last = escape + 1;
+        }
+        text.push_str(&content[last..range.end]);
+
+        text
+    }
+}
+
+impl Note {
+    /// Pair every opening marker with the next end marker, ignoring the
+    /// markers written inside fenced code blocks, inline code spans or exclude
+    /// regions and the ones escaped with a backslash.
+    pub(super) fn scan(&self, content: &str) -> Scan {
+        let mut scan = Scan::default();
+        let mut open: Option<(Range<usize>, String, bool)> = None;
+
+        let mut code = code_fences(content);
+        code.append(&mut code_spans(content, &code));
+
+        let mut regions: Vec<Range<usize>> = vec![];
+        let mut region_start: Option<usize> = None;
+
+        for cap in self.exclude_regex.captures_iter(content) {
+            let marker = cap.get(0).unwrap();
+            let marker = marker.start()..marker.end();
+
+            if code.iter().any(|range| range.contains(&marker.start)) {
+                continue;
+            }
+
+            if cap.name("end").is_some() {
+                if let Some(start) = region_start.take() {
+                    regions.push(start..marker.end);
+                }
+            } else if region_start.is_none() {
+                region_start = Some(marker.start);
+            }
+            scan.exclude_markers.push(marker);
+        }
+
+        if let Some(start) = region_start {
+            regions.push(start..content.len());
+        }
+
+        let mut caps: Vec<Captures> = self
+            .regexes
+            .iter()
+            .flat_map(|regex| regex.captures_iter(content))
+            .collect();
+        caps.sort_by_key(|cap| cap.get(0).unwrap().start());
+
+        for cap in self.ref_regex.captures_iter(content) {
+            let marker = cap.get(0).unwrap();
+            let marker = marker.start()..marker.end();
+
+            if code
+                .iter()
+                .chain(regions.iter())
+                .any(|range| range.contains(&marker.start))
+            {
+                continue;
+            }
+            if content[..marker.start].ends_with('\\') {
+                scan.escapes.push(marker.start - 1);
+                continue;
+            }
+
+            scan.refs.push((marker, capture(&cap, "id")));
+        }
+
+        let mut markers: Vec<Range<usize>> = scan.exclude_markers.clone();
+        markers.extend(
+            self.ref_regex
+                .find_iter(content)
+                .map(|m| m.start()..m.end()),
+        );
+        markers.extend(caps.iter().map(|cap| {
+            let marker = cap.get(0).unwrap();
+            marker.start()..marker.end()
+        }));
+
+        for almost in self.almost_regex.find_iter(content) {
+            let almost = almost.start()..almost.end();
+
+            if !markers
+                .iter()
+                .chain(code.iter())
+                .chain(regions.iter())
+                .any(|range| range.start < almost.end && almost.start < range.end)
+                && !content[..almost.start].ends_with('\\')
+            {
+                scan.malformed.push(almost);
+            }
+        }
+
+        for cap in caps {
+            let marker = cap.get(0).unwrap();
+            let marker = marker.start()..marker.end();
+
+            if code.iter().any(|range| range.contains(&marker.start)) {
+                continue;
+            }
+            if regions.iter().any(|range| range.contains(&marker.start)) {
+                scan.excluded.push(marker);
+                continue;
+            }
+            if content[..marker.start].ends_with('\\') {
+                scan.escapes.push(marker.start - 1);
+                continue;
+            }
+
+            let key = capture(&cap, "key");
+            let local = cap.name("local").is_some();
+
+            if key != "end" {
+                let raw = cap.name("key").unwrap();
+                if let Some(colon) = shorthand_colon(raw.as_str()) {
+                    let text = &raw.as_str()[colon + 1..];
+                    let start = raw.start() + colon + 1 + text.len() - text.trim_start().len();
+
+                    if let Some(opener) = open.take() {
+                        self.close_unended(&mut scan, content, &code, opener, marker.start);
+                    }
+                    scan.blocks.push(Block {
+                        key: raw.as_str()[..colon].trim().to_string(),
+                        span: marker,
+                        body: start..start + text.trim().len(),
+                        local,
+                    });
+                    continue;
+                }
+            }
+
+            if key == "end" {
+                match open.take() {
+                    Some(opener)
+                        if self.until_heading(&opener.1)
+                            && heading_start(content, opener.0.end, &code) < marker.start =>
+                    {
+                        self.close_unended(&mut scan, content, &code, opener, marker.start);
+                        scan.orphans.push(marker);
+                    }
+                    Some((opener, key, local)) => scan.blocks.push(Block {
+                        key,
+                        span: opener.start..marker.end,
+                        body: opener.end..marker.start,
+                        local,
+                    }),
+                    None => scan.orphans.push(marker),
+                }
+            } else if let Some(opener) = open.replace((marker.clone(), key, local)) {
+                self.close_unended(&mut scan, content, &code, opener, marker.start);
+            }
+        }
+
+        if let Some(opener) = open {
+            self.close_unended(&mut scan, content, &code, opener, content.len());
+        }
+
+        scan
+    }
+
+    /// Handle an opening marker not followed by an end marker before `limit`:
+    /// an `until=heading` note runs to the next heading, in paragraph mode a
+    /// note runs to the end of the paragraph, otherwise it is reported as
+    /// unclosed.
+    fn close_unended(
+        &self,
+        scan: &mut Scan,
+        content: &str,
+        code: &[Range<usize>],
+        (opener, key, local): (Range<usize>, String, bool),
+        limit: usize,
+    ) {
+        let end = if self.until_heading(&key) {
+            heading_start(content, opener.end, code)
+        } else if self.paragraph_mode {
+            paragraph_end(content, opener.end)
+        } else {
+            scan.unclosed.push(opener);
+            return;
+        };
+
+        let end = end.min(limit);
+        scan.blocks.push(Block {
+            key,
+            span: opener.start..end,
+            body: opener.end..end,
+            local,
+        });
+    }
+
+    /// Report the markers which cannot be paired and the malformed ones, as
+    /// warnings or as an error in strict mode.
+    pub(super) fn check_chapter(&self, chapter: &Chapter) -> Result<(), Error> {
+        let scan = self.scan(&chapter.content);
+
+        let mut problems: Vec<(Range<usize>, &str)> = vec![];
+        problems.extend(scan.unclosed.iter().map(|m| (m.clone(), "unclosed")));
+        problems.extend(scan.orphans.iter().map(|m| (m.clone(), "orphan")));
+        problems.extend(scan.malformed.iter().map(|m| (m.clone(), "malformed")));
+        problems.sort_by_key(|(marker, _)| marker.start);
+
+        let messages: Vec<String> = problems
+            .into_iter()
+            .map(|(marker, problem)| {
+                format!(
+                    "{} note marker {} in {}",
+                    problem,
+                    &chapter.content[marker.clone()],
+                    location(chapter, marker.start)
+                )
+            })
+            .collect();
+
+        for message in messages {
+            if self.strict {
+                return Err(Error::msg(message));
+            }
+
+            warn!("{}", message);
+        }
+
+        for block in scan.blocks.iter() {
+            for warning in self.parse_marker(&block.key).warnings {
+                warn!("{} in {}", warning, location(chapter, block.span.start));
+            }
+            let body = scan.text(&chapter.content, block.body.clone());
+            let (references, definitions) = footnotes(&body);
+            for label in references
+                .iter()
+                .filter(|label| !definitions.contains(label))
+            {
+                warn!(
+                    "note uses the footnote [^{}] defined outside of it, copied along in {}",
+                    label,
+                    location(chapter, block.span.start)
+                );
+            }
+            for label in undefined_references(&body) {
+                warn!(
+                    "note uses the reference link [{}] defined outside of it, left untouched in {}",
+                    label,
+                    location(chapter, block.span.start)
+                );
+            }
+            if body.trim().is_empty() {
+                warn!(
+                    "empty note {} in {}",
+                    &chapter.content[block.span.clone()],
+                    location(chapter, block.span.start)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn parse_chapter(&self, chapter: &Chapter) -> Vec<Extract> {
+        let mut res = vec![];
+
+        // keyed on the folded levels, so that `a|b` and `a | b` share a heading
+        let mut find_key: HashMap<Vec<String>, bool> = HashMap::new();
+
+        let scan = self.scan(&chapter.content);
+        let offset = deep_link_offset(&chapter.content);
+
+        // footnotes are per page, so the ones of notes are renamed after their
+        // chapter, `[^1]` of `ch03.md` becoming `[^ch03-1]`
+        let footnote_prefix = match &chapter.path {
+            Some(path) => slugify(&path.with_extension("").to_string_lossy()),
+            None => slugify(&chapter.name),
+        };
+
+        for (index, block) in scan.blocks.iter().enumerate() {
+            let val = scan.text(&chapter.content, block.body.clone());
+            let val = dedent(&val.replace("\r\n", "\n"));
+            let val = val.trim();
+
+            let marker = self.parse_marker(&block.key);
+            if self.is_local(block, &marker) {
+                continue;
+            }
+
+            // relative links are kept from the book root, as the generated
+            // chapter is elsewhere
+            let dir = chapter
+                .path
+                .as_ref()
+                .and_then(|path| path.parent())
+                .unwrap_or(Path::new(""));
+            let val = &rewrite_links(val, |url| rebase_link(dir, url));
+            let val = &namespace_footnotes(val, &chapter.content, &footnote_prefix);
+
+            let site = match chapter.path {
+                Some(_) if self.deep_links => Some(offset + index + 1),
+                _ => None,
+            };
+
+            let mut targets: Vec<Vec<String>> = vec![];
+
+            for key in marker.keys.iter() {
+                let mut keys = self.levels(key);
+                keys.reverse();
+
+                if keys.is_empty() {
+                    keys.extend(self.uncategorized.clone());
+                }
+
+                targets.push(keys);
+            }
+
+            if self.collect_hashtags {
+                for tag in hashtags(val) {
+                    targets.push(vec![tag]);
+                }
+            }
+
+            for keys in targets {
+                let folded = keys.iter().map(|k| self.fold(k)).collect();
+                if find_key.insert(folded, true).is_none() {
+                    res.push(Extract {
+                        key: keys.clone(),
+                        val: format!("### {}", chapter.name),
+                        title: None,
+                        attributes: HashMap::new(),
+                        heading: true,
+                        source: chapter.path.clone(),
+                        site: None,
+                        line: None,
+                        date: None,
+                        part: None,
+                        number: chapter.number.as_ref().map(|number| {
+                            let levels: Vec<String> = number.0.iter().map(u32::to_string).collect();
+                            levels.join(".")
+                        }),
+                    });
+                }
+                res.push(Extract {
+                    key: keys,
+                    val: val.to_string(),
+                    title: marker.title.clone(),
+                    attributes: marker.attributes.clone(),
+                    heading: false,
+                    source: match site.is_some() || self.provenance || self.read_more {
+                        true => chapter.path.clone(),
+                        false => None,
+                    },
+                    site,
+                    number: None,
+                    line: match self.provenance {
+                        true => Some(line_number(&chapter.content, block.span.start)),
+                        false => None,
+                    },
+                    date: None,
+                    part: None,
+                })
+            }
+        }
+
+        res
+    }
+
+    pub(super) fn parse_marker(&self, marker_key: &str) -> Marker {
+        let (key, title) = match split_unquoted(marker_key, "::").as_slice() {
+            [key, _, ..] => {
+                let title = marker_key[key.len() + 2..].trim();
+                (
+                    key.trim(),
+                    Some(title.to_string()).filter(|t| !t.is_empty()),
+                )
+            }
+            _ => (marker_key, None),
+        };
+
+        let mut marker = Marker {
+            keys: vec![],
+            title,
+            attributes: HashMap::new(),
+            warnings: vec![],
+        };
+
+        for item in split_unquoted(key, ",") {
+            let mut item_key = vec![];
+            let mut has_attributes = false;
+
+            let tokens = split_unquoted(item, " ");
+            let is_key_word = |token: &&str| {
+                !token.is_empty()
+                    && !FLAGS.contains(token)
+                    && split_unquoted(token, "=")[0].len() == token.len()
+            };
+            // the flags only follow the last word of the key
+            let flags_start = tokens.iter().rposition(is_key_word).map_or(0, |i| i + 1);
+
+            for (i, token) in tokens.into_iter().enumerate() {
+                if i >= flags_start && FLAGS.contains(&token) {
+                    has_attributes = true;
+                    marker
+                        .attributes
+                        .insert(token.to_string(), "true".to_string());
+                    continue;
+                }
+
+                let name = split_unquoted(token, "=")[0];
+                if name.len() == token.len() {
+                    item_key.push(token);
+                    continue;
+                }
+
+                has_attributes = true;
+                let value = unquote(&token[name.len() + 1..]);
+
+                if name.is_empty() || value.is_empty() {
+                    marker
+                        .warnings
+                        .push(format!("malformed note attribute {}", token));
+                    continue;
+                }
+
+                marker.attributes.insert(name.to_string(), value);
+            }
+
+            let item_key = item_key.join(" ");
+            if has_attributes && item_key.trim().is_empty() {
+                continue;
+            }
+
+            for key in split_unquoted(&item_key, "||") {
+                match self.aliases.get(key.trim()) {
+                    Some(canonical) => {
+                        marker.warnings.push(format!(
+                            "note key {} is an alias of {}",
+                            key.trim(),
+                            canonical
+                        ));
+                        marker.keys.push(canonical.clone());
+                    }
+                    None => marker.keys.push(key.to_string()),
+                }
+            }
+        }
+
+        if marker.keys.is_empty() {
+            marker.keys.push(String::new());
+        }
+
+        if let Some(depth) = self.max_depth {
+            for key in marker.keys.iter() {
+                let levels = self.key_levels(key);
+                if levels.iter().filter(|l| !l.trim().is_empty()).count() > depth {
+                    marker.warnings.push(format!(
+                        "note key {} is deeper than max-depth {}",
+                        key.trim(),
+                        depth
+                    ));
+                }
+            }
+        }
+
+        for key in marker.keys.iter() {
+            if key.contains(|c| "{}[]<>`".contains(c)) {
+                marker
+                    .warnings
+                    .push(format!("note key {} looks like markup", key.trim()));
+            }
+        }
+
+        if let Some(order) = marker.attributes.get("order") {
+            if order.parse::<i64>().is_err() {
+                marker
+                    .warnings
+                    .push(format!("note attribute order={} is not an integer", order));
+            }
+        }
+        if let Some(until) = marker.attributes.get("until") {
+            if until != "heading" {
+                marker
+                    .warnings
+                    .push(format!("note attribute until={} is not heading", until));
+            }
+        }
+        for flag in FLAGS.iter() {
+            if let Some(value) = marker.attributes.get(*flag) {
+                if value != "true" && value != "false" {
+                    marker.warnings.push(format!(
+                        "note attribute {}={} is not a boolean",
+                        flag, value
+                    ));
+                }
+            }
+        }
+
+        marker
+    }
+
+    /// Whether the note of a marker runs to the next heading when it has no
+    /// end marker.
+    fn until_heading(&self, marker_key: &str) -> bool {
+        self.parse_marker(marker_key)
+            .attributes
+            .get("until")
+            .map(String::as_str)
+            == Some("heading")
+    }
+
+    /// Whether the note of a marker is dropped from its chapter.
+    pub(super) fn is_hidden(&self, marker: &Marker) -> bool {
+        match marker.attributes.get("hidden").map(String::as_str) {
+            Some("true") => true,
+            Some("false") => false,
+            _ => self.hidden_by_default,
+        }
+    }
+
+    /// Whether a note is only styled in its chapter and not collected.
+    pub(super) fn is_local(&self, block: &Block, marker: &Marker) -> bool {
+        block.local || marker.attributes.get("local").map(String::as_str) == Some("true")
+    }
+
+    /// Unquoted levels of a key, the levels beyond the maximum depth being
+    /// joined into the last allowed one.
+    pub(super) fn levels(&self, key: &str) -> Vec<String> {
+        let mut levels: Vec<String> = self
+            .key_levels(key)
+            .into_iter()
+            .map(|s| unquote(s.trim()))
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        if let Some(depth) = self.max_depth {
+            if levels.len() > depth {
+                let tail = levels.split_off(depth - 1);
+                levels.push(tail.join(&self.key_separator.to_string()));
+            }
+        }
+
+        levels
+    }
+
+    /// Split a key into its levels, on `|` or `/`.
+    fn key_levels<'a>(&self, key: &'a str) -> Vec<&'a str> {
+        let (first, second) = match self.key_separator {
+            '/' => ("/", "|"),
+            _ => ("|", "/"),
+        };
+
+        let levels = split_unquoted(key, first);
+        if levels.len() > 1 {
+            return levels;
+        }
+
+        split_unquoted(key, second)
+    }
+}
+
+/// Regex of the markers of a syntax: `braces` for `{{#note key}}` and
+/// `comments` for `<!-- note: key -->`, `note` being the marker word.
+pub(super) fn syntax_regex(syntax: &str, marker: &str) -> Option<Regex> {
+    let pattern = match syntax {
+        "braces" => r"\{\{#MARKER(?P<local>!)?(?P<key>(?:\s[^}]*)?)}}",
+        "comments" => r"<!--\s*MARKER(?P<local>!)?(?::|\s)\s*(?P<key>(?:[^-]|-[^-])*?)\s*-->",
+        _ => return None,
+    };
+
+    Some(
+        RegexBuilder::new(&pattern.replace("MARKER", &regex::escape(marker)))
+            .multi_line(true)
+            .build()
+            .unwrap(),
+    )
+}
+
+/// Regex of the markers delimiting an exclude region, `note` being the marker
+/// word.
+pub(super) fn exclude_regex(marker: &str) -> Regex {
+    RegexBuilder::new(&format!(
+        r"\{{\{{#{}-exclude(?P<end>\s+end)?\s*}}}}",
+        regex::escape(marker)
+    ))
+    .build()
+    .unwrap()
+}
+
+/// Regex of the markers referencing a note by id, `note` being the marker word.
+pub(super) fn ref_regex(marker: &str) -> Regex {
+    RegexBuilder::new(&format!(
+        r"\{{\{{#{}-ref\s+(?P<id>[^}}]*)}}}}",
+        regex::escape(marker)
+    ))
+    .build()
+    .unwrap()
+}
+
+/// Regex of the text starting like a marker, with any number of braces and
+/// spaces around the `#`, in any case, up to the end of its line or braces.
+pub(super) fn almost_regex(marker: &str) -> Regex {
+    RegexBuilder::new(&format!(
+        r"\{{+\s*#\s*{}\b[^{{}}\n]*\}}*",
+        regex::escape(marker)
+    ))
+    .case_insensitive(true)
+    .build()
+    .unwrap()
+}
+
+/// Split on a separator, except inside double quoted parts.
+fn split_unquoted<'a>(s: &'a str, separator: &str) -> Vec<&'a str> {
+    let mut parts = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+    let mut i = 0;
+
+    while i < s.len() {
+        if s[i..].starts_with("\\\"") {
+            i += 2;
+        } else if s[i..].starts_with('"') {
+            quoted = !quoted;
+            i += 1;
+        } else if !quoted && s[i..].starts_with(separator) {
+            parts.push(&s[start..i]);
+            i += separator.len();
+            start = i;
+        } else {
+            i += s[i..].chars().next().unwrap().len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+
+    parts
+}
+
+/// Remove the indentation common to the lines of a note body, the first line
+/// being ignored as it follows the opening marker.
+pub(super) fn dedent(text: &str) -> String {
+    let mut lines = text.split('\n');
+    let first = lines.next().unwrap_or_default();
+    let lines: Vec<&str> = lines.collect();
+
+    let indent = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    let mut dedented = vec![first];
+    for line in lines {
+        let strip = indent.min(line.len() - line.trim_start().len());
+        dedented.push(&line[strip..]);
+    }
+
+    dedented.join("\n")
+}
+
+/// The distinct `#tags` of a text, a tag starting with a letter after a
+/// space or a line start. Headings and code are ignored.
+fn hashtags(text: &str) -> Vec<String> {
+    let mut code = code_fences(text);
+    code.append(&mut code_spans(text, &code));
+
+    let mut tags: Vec<String> = vec![];
+
+    for (offset, _) in text.match_indices('#') {
+        let before = text[..offset].chars().last();
+        let tag: String = text[offset + 1..]
+            .chars()
+            .take_while(|&c| c.is_alphanumeric() || c == '_' || c == '-')
+            .collect();
+
+        if before.is_none_or(char::is_whitespace)
+            && tag.starts_with(char::is_alphabetic)
+            && !code.iter().any(|range| range.contains(&offset))
+            && !tags.contains(&tag)
+        {
+            tags.push(tag);
+        }
+    }
+
+    tags
+}
+
+/// Offset of the first unquoted `:` of a marker before any `::` title
+/// separator, making `{{#note key: body}}` a single-line note, so that a key
+/// with a `:` or a `/` of its own is quoted, as `{{#note "what?: yes/no"}}`.
+fn shorthand_colon(s: &str) -> Option<usize> {
+    let mut quoted = false;
+    let mut i = 0;
+
+    while i < s.len() {
+        if s[i..].starts_with("\\\"") {
+            i += 1;
+        } else if s[i..].starts_with('"') {
+            quoted = !quoted;
+        } else if !quoted && s[i..].starts_with("::") {
+            return None;
+        } else if !quoted && s[i..].starts_with(':') {
+            return Some(i);
+        }
+        i += s[i..].chars().next().unwrap().len_utf8();
+    }
+
+    None
+}
+
+/// Remove the double quotes around a key level or an attribute value and
+/// unescape the `\"` inside.
+fn unquote(s: &str) -> String {
+    let s = if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') && !s.ends_with("\\\"") {
+        &s[1..s.len() - 1]
+    } else {
+        s
+    };
+
+    s.replace("\\\"", "\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note::fixture::{chapter, preprocessor};
+
+    #[test]
+    fn test_extract_inline() {
+        let chapter = chapter(
+            "some name",
+            "some outer content {{#note my_key}}inside contente{{#note end}} other outer content",
+            vec![],
+        );
+
+        let note = Note::new();
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "inside contente".to_string(),
+                    ..Default::default()
+                },
+            ]
+        )
+    }
+
+    #[test]
+    fn test_extract_multiline() {
+        let chapter = chapter(
+            "some name",
+            "some outer content
+            {{#note my_key}}
+            inside contente
+            {{#note end}}
+            other outer content",
+            vec![],
+        );
+
+        let note = Note::new();
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "inside contente".to_string(),
+                    ..Default::default()
+                },
+            ]
+        )
+    }
+
+    #[test]
+    fn test_extract_multiline_multicapture() {
+        let chapter = chapter(
+            "some name",
+            "some outer content
+{{#note ||my_key| my sub key}}
+inside contente split
+{{#note end}}
+other outer content
+blablabla
+{{#note my key 2}}
+other content
+split
+{{#note end}}
+{{#note}}
+some global note
+{{#note end}}
+{{#note my key 2}}
+my other key 2
+{{#note end}}
+end
+",
+            vec![],
+        );
+
+        let note = Note::new();
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec![],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec![],
+                    val: "inside contente split".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my sub key".to_string(), "my_key".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my sub key".to_string(), "my_key".to_string()],
+                    val: "inside contente split".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my key 2".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my key 2".to_string()],
+                    val: "other content\nsplit".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec![],
+                    val: "some global note".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my key 2".to_string()],
+                    val: "my other key 2".to_string(),
+                    ..Default::default()
+                },
+            ]
+        )
+    }
+
+    #[test]
+    fn test_extract_with_include() {
+        let chapter = chapter(
+            "some name",
+            "some outer content
+{{#note my_key}}
+before include
+{{#include snippets/foo.rs}}
+after include
+{{#note end}}
+other outer content",
+            vec![],
+        );
+
+        let note = Note::new();
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "before include\n{{#include snippets/foo.rs}}\nafter include".to_string(),
+                    ..Default::default()
+                },
+            ]
+        )
+    }
+
+    #[test]
+    fn test_extract_with_code_braces() {
+        let chapter = chapter(
+            "some name",
+            "{{#note my_key}}
+```rust
+fn main() {
+    println!(\"{}\", 42);
+}
+```
+{{#note end}}",
+            vec![],
+        );
+
+        let note = Note::new();
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "```rust\nfn main() {\n    println!(\"{}\", 42);\n}\n```".to_string(),
+                    ..Default::default()
+                },
+            ]
+        )
+    }
+
+    #[test]
+    fn test_extract_with_stray_brace() {
+        let chapter = chapter(
+            "some name",
+            "{{#note my_key}}a stray { brace{{#note end}} then {{#note my_key}}second{{#note end}}",
+            vec![],
+        );
+
+        let note = Note::new();
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "a stray { brace".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "second".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let mut chapter = chapter;
+        note.clean_chapter(&mut chapter, &HashMap::new(), &HashMap::new());
+
+        assert_eq!(chapter.content, "a stray { brace then second".to_string())
+    }
+
+    #[test]
+    fn test_extract_unclosed() {
+        let chapter = Chapter {
+            path: Some("some/path.md".parse().unwrap()),
+            ..chapter(
+                "some name",
+                "some outer content
+{{#note my_key}}
+inside contente
+{{#note end}}
+other outer content
+{{#note other key}}
+never closed
+",
+                vec![],
+            )
+        };
+
+        let note = Note::new();
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    source: Some("some/path.md".parse().unwrap()),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "inside contente".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+
+        assert_eq!(
+            cleaned.content,
+            "some outer content
+
+inside contente
+
+other outer content
+{{#note other key}}
+never closed
+"
+        );
+
+        let note = Note {
+            strict: true,
+            ..Note::new()
+        };
+
+        assert_eq!(
+            note.check_chapter(&chapter).unwrap_err().to_string(),
+            "unclosed note marker {{#note other key}} in chapter \"some name\" (some/path.md) line 6"
+        );
+    }
+
+    #[test]
+    fn test_extract_orphan_end() {
+        let chapter = Chapter {
+            path: Some("some/path.md".parse().unwrap()),
+            ..chapter(
+                "some name",
+                "some outer content
+{{#note end}}
+{{#note my_key}}inside contente{{#note end}}
+other outer content {{#note end}}
+",
+                vec![],
+            )
+        };
+
+        let note = Note::new();
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    source: Some("some/path.md".parse().unwrap()),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "inside contente".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+
+        assert_eq!(
+            cleaned.content,
+            "some outer content\n\ninside contente\nother outer content \n"
+        );
+
+        assert!(note.check_chapter(&chapter).is_ok());
+
+        let note = Note {
+            strict: true,
+            ..Note::new()
+        };
+
+        assert_eq!(
+            note.check_chapter(&chapter).unwrap_err().to_string(),
+            "orphan note marker {{#note end}} in chapter \"some name\" (some/path.md) line 2"
+        );
+    }
+
+    #[test]
+    fn test_extract_ignore_fenced_code() {
+        let chapter = chapter(
+            "some name",
+            "how to write a note:
+```markdown
+{{#note example}}
+some example
+{{#note end}}
+```
+an opener alone:
+~~~
+{{#note example}}
+~~~
+{{#note my_key}}inside contente{{#note end}}
+",
+            vec![],
+        );
+
+        let note = Note {
+            strict: true,
+            ..Note::new()
+        };
+
+        assert!(note.check_chapter(&chapter).is_ok());
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "inside contente".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+
+        assert_eq!(
+            cleaned.content,
+            "how to write a note:
+```markdown
+{{#note example}}
+some example
+{{#note end}}
+```
+an opener alone:
+~~~
+{{#note example}}
+~~~
+inside contente
+"
+        );
+    }
+
+    #[test]
+    fn test_extract_ignore_code_spans() {
+        let chapter = chapter("some name", "write `{{#note key}}` then ``{{#note end}}``, like {{#note my_key}}inside `code` contente{{#note end}}
+a stray ` backtick {{#note other}}is not code{{#note end}}
+", vec![]);
+
+        let note = Note {
+            strict: true,
+            ..Note::new()
+        };
+
+        assert!(note.check_chapter(&chapter).is_ok());
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "inside `code` contente".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["other".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["other".to_string()],
+                    val: "is not code".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+
+        assert_eq!(
+            cleaned.content,
+            "write `{{#note key}}` then ``{{#note end}}``, like inside `code` contente
+a stray ` backtick is not code
+"
+        );
+    }
+
+    #[test]
+    fn test_extract_escaped_markers() {
+        let chapter = chapter("some name", "write \\{{#note key}} and \\{{#note end}} around {{#note my_key}}a \\{{#note key}} note{{#note end}} text", vec![]);
+
+        let note = Note {
+            strict: true,
+            ..Note::new()
+        };
+
+        assert!(note.check_chapter(&chapter).is_ok());
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["my_key".to_string()],
+                    val: "a {{#note key}} note".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+
+        assert_eq!(
+            cleaned.content,
+            "write {{#note key}} and {{#note end}} around a {{#note key}} note text"
+        );
+    }
+
+    #[test]
+    fn test_extract_comma_keys() {
+        let chapter = chapter(
+            "some name",
+            "{{#note rust, performance}}fast code{{#note end}}
+{{#note \"tips, tricks\", rust|perf}}quoted{{#note end}}",
+            vec![],
+        );
+
+        let note = Note::new();
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["rust".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["rust".to_string()],
+                    val: "fast code".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["performance".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["performance".to_string()],
+                    val: "fast code".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["tips, tricks".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["tips, tricks".to_string()],
+                    val: "quoted".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["perf".to_string(), "rust".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["perf".to_string(), "rust".to_string()],
+                    val: "quoted".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_slash_keys() {
+        let chapter = chapter(
+            "some name",
+            "{{#note projects/alpha/design}}slash{{#note end}}
+{{#note projects | alpha | design}}pipe{{#note end}}
+{{#note input/output | formats}}both{{#note end}}",
+            vec![],
+        );
+
+        let design = vec![
+            "design".to_string(),
+            "alpha".to_string(),
+            "projects".to_string(),
+        ];
+
+        let note = Note::new();
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: design.clone(),
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: design.clone(),
+                    val: "slash".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: design.clone(),
+                    val: "pipe".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["formats".to_string(), "input/output".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["formats".to_string(), "input/output".to_string()],
+                    val: "both".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let note = Note {
+            key_separator: '/',
+            ..Note::new()
+        };
+
+        assert_eq!(
+            note.parse_chapter(&chapter)[4],
+            Extract {
+                key: vec!["output | formats".to_string(), "input".to_string()],
+                val: "both".to_string(),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_extract_title() {
+        let chapter = chapter(
+            "some name",
+            "{{#note api :: Rate limiting: rules}}no more than 10 calls{{#note end}}
+{{#note api::}}untitled{{#note end}}",
+            vec![],
+        );
+
+        let note = Note::new();
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["api".to_string()],
+                    val: "### some name".to_string(),
+                    title: None,
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["api".to_string()],
+                    val: "no more than 10 calls".to_string(),
+                    title: Some("Rate limiting: rules".to_string()),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["api".to_string()],
+                    val: "untitled".to_string(),
+                    title: None,
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_attributes() {
+        let chapter = chapter(
+            "some name",
+            "{{#note api, order=2, author=alice}}ordered{{#note end}}
+{{#note api|rate limits review=\"jane doe\" =broken order=}}attributes{{#note end}}",
+            vec![],
+        );
+
+        let note = Note::new();
+
+        let mut ordered = HashMap::new();
+        ordered.insert("order".to_string(), "2".to_string());
+        ordered.insert("author".to_string(), "alice".to_string());
+
+        let mut reviewed = HashMap::new();
+        reviewed.insert("review".to_string(), "jane doe".to_string());
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["api".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["api".to_string()],
+                    val: "ordered".to_string(),
+                    attributes: ordered,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["rate limits".to_string(), "api".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["rate limits".to_string(), "api".to_string()],
+                    val: "attributes".to_string(),
+                    attributes: reviewed,
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_opener_before_end() {
+        let chapter = chapter(
+            "some name",
+            "{{#note a}}forgotten {{#note b}}inside{{#note end}}",
+            vec![],
+        );
+
+        let note = Note {
+            strict: true,
+            ..Note::new()
+        };
+
+        assert_eq!(
+            note.check_chapter(&chapter).unwrap_err().to_string(),
+            "unclosed note marker {{#note a}} in chapter \"some name\" line 1"
+        );
+    }
+
+    #[test]
+    fn test_extract_hidden() {
+        let chapter = chapter(
+            "some name",
+            "before {{#note review hidden}}reviewer only{{#note end}}after
+{{#note api hidden=false}}shown{{#note end}}",
+            vec![],
+        );
+
+        let note = Note::new();
+
+        let mut hidden = HashMap::new();
+        hidden.insert("hidden".to_string(), "true".to_string());
+
+        let mut shown = HashMap::new();
+        shown.insert("hidden".to_string(), "false".to_string());
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["review".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["review".to_string()],
+                    val: "reviewer only".to_string(),
+                    attributes: hidden,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["api".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["api".to_string()],
+                    val: "shown".to_string(),
+                    attributes: shown,
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+        assert_eq!(cleaned.content, "before after\nshown");
+
+        let note = Note {
+            hidden_by_default: true,
+            ..Note::new()
+        };
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+        assert_eq!(cleaned.content, "before after\nshown");
+
+        let mut chapter = chapter;
+        chapter.content = "{{#note}}side channel{{#note end}}".to_string();
+        note.clean_chapter(&mut chapter, &HashMap::new(), &HashMap::new());
+        assert_eq!(chapter.content, "");
+    }
+
+    #[test]
+    fn test_extract_local() {
+        let chapter = chapter(
+            "some name",
+            "{{#note!}} styled {{#note end}}
+{{#note api local}}also styled{{#note end}}
+{{#note api}}collected{{#note end}}",
+            vec![],
+        );
+
+        let note = Note {
+            local_class: "callout".to_string(),
+            ..Note::new()
+        };
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["api".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["api".to_string()],
+                    val: "collected".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+
+        assert_eq!(
+            cleaned.content,
+            "<div class=\"callout\">\n\nstyled\n\n</div>
+<div class=\"callout\">\n\nalso styled\n\n</div>
+collected"
+        );
+    }
+
+    #[test]
+    fn test_extract_flag_words_in_keys() {
+        let chapter = chapter(
+            "some name",
+            "{{#note hidden gems}}one{{#note end}} {{#note local development}}two{{#note end}}
+{{#note pinned tweets|archive}}three{{#note end}} {{#note hidden gems pinned}}four{{#note end}}",
+            vec![],
+        );
+
+        let note = Note::new();
+
+        let keys: Vec<(Vec<String>, String)> = note
+            .parse_chapter(&chapter)
+            .into_iter()
+            .filter(|e| !e.heading)
+            .map(|e| (e.key, e.val))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                (vec!["hidden gems".to_string()], "one".to_string()),
+                (vec!["local development".to_string()], "two".to_string()),
+                (
+                    vec!["archive".to_string(), "pinned tweets".to_string()],
+                    "three".to_string()
+                ),
+                (vec!["hidden gems".to_string()], "four".to_string()),
+            ]
+        );
+
+        let pinned = note.parse_marker("hidden gems pinned");
+        assert_eq!(pinned.keys, vec!["hidden gems".to_string()]);
+        assert_eq!(
+            pinned.attributes.get("pinned").map(String::as_str),
+            Some("true")
+        );
+        assert!(note.parse_marker("hidden gems").attributes.is_empty());
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+        assert_eq!(cleaned.content, "one two\nthree four");
+    }
+
+    #[test]
+    fn test_extract_single_line() {
+        let chapter = chapter("some name", "A {{#note glossary: A monad: a monoid}} and {{#note \"a:b\" :: title}}long{{#note end}}", vec![]);
+
+        let note = Note {
+            strict: true,
+            ..Note::new()
+        };
+
+        assert!(note.check_chapter(&chapter).is_ok());
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["glossary".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["glossary".to_string()],
+                    val: "A monad: a monoid".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["a:b".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["a:b".to_string()],
+                    val: "long".to_string(),
+                    title: Some("title".to_string()),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+
+        assert_eq!(cleaned.content, "A A monad: a monoid and long");
+    }
+
+    #[test]
+    fn test_extract_paragraph_mode() {
+        let chapter = chapter(
+            "some name",
+            "{{#note a}}first
+paragraph
+
+text {{#note b}}short {{#note c}}block{{#note end}}
+
+{{#note d}}at the end",
+            vec![],
+        );
+
+        let note = Note {
+            strict: true,
+            paragraph_mode: true,
+            ..Note::new()
+        };
+
+        assert!(note.check_chapter(&chapter).is_ok());
+
+        let extracts: Vec<String> = note
+            .parse_chapter(&chapter)
+            .into_iter()
+            .filter(|e| !e.heading)
+            .map(|e| format!("{}: {}", e.key.join("|"), e.val))
+            .collect();
+
+        assert_eq!(
+            extracts,
+            vec![
+                "a: first\nparagraph",
+                "b: short",
+                "c: block",
+                "d: at the end"
+            ]
+        );
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+
+        assert_eq!(
+            cleaned.content,
+            "first\nparagraph\n\ntext short block\n\nat the end"
+        );
+
+        let note = Note {
+            strict: true,
+            ..Note::new()
+        };
+
+        assert!(note.check_chapter(&chapter).is_err());
+    }
+
+    #[test]
+    fn test_extract_comment_syntax() {
+        let chapter = chapter(
+            "some name",
+            "<!-- note: a|b -->commented<!-- note end -->
+{{#note c}}braced{{#note end}} <!--note: d: short-->
+<!-- not a note -->",
+            vec![],
+        );
+
+        let note = preprocessor(
+            "[preprocessor.note]
+strict = true
+syntax = [\"braces\", \"comments\"]",
+        )
+        .unwrap();
+
+        assert!(note.check_chapter(&chapter).is_ok());
+
+        let extracts: Vec<String> = note
+            .parse_chapter(&chapter)
+            .into_iter()
+            .filter(|e| !e.heading)
+            .map(|e| format!("{}: {}", e.key.join("|"), e.val))
+            .collect();
+
+        assert_eq!(extracts, vec!["b|a: commented", "c: braced", "d: short"]);
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+
+        assert_eq!(
+            cleaned.content,
+            "commented\nbraced short\n<!-- not a note -->"
+        );
+
+        let braces = Note::new();
+        assert_eq!(braces.parse_chapter(&chapter).len(), 2);
+    }
+
+    #[test]
+    fn test_extract_custom_marker() {
+        let chapter = chapter(
+            "some name",
+            "{{#fiche a}}french{{#fiche end}} {{#note b}}ignored{{#note end}} {{#fiches c}}",
+            vec![],
+        );
+
+        let note = preprocessor("[preprocessor.note]\nmarker = \"fiche\"").unwrap();
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["a".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["a".to_string()],
+                    val: "french".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+
+        assert_eq!(
+            cleaned.content,
+            "french {{#note b}}ignored{{#note end}} {{#fiches c}}"
+        );
+
+        assert_eq!(
+            preprocessor("[preprocessor.note]\nmarker = \"fi(che\"")
+                .err()
+                .unwrap()
+                .to_string(),
+            "note marker must only contain letters, digits, \"-\" or \"_\", found \"fi(che\""
+        );
+    }
+
+    #[test]
+    fn test_extract_dedent() {
+        let chapter = chapter(
+            "some name",
+            "- level 1
+    - level 2
+
+        {{#note a}}
+        nested note
+
+            indented code
+        end of note
+        {{#note end}}
+",
+            vec![],
+        );
+
+        let note = Note::new();
+
+        assert_eq!(
+            note.parse_chapter(&chapter)[1].val,
+            "nested note\n\n    indented code\nend of note"
+        );
+
+        assert_eq!(dedent("inline\n    a\n      b"), "inline\na\n  b");
+    }
+
+    #[test]
+    fn test_extract_aliases() {
+        let chapter = chapter(
+            "some name",
+            "{{#note perf}}a{{#note end}} {{#note speed, other}}b{{#note end}}",
+            vec![],
+        );
+
+        let note = preprocessor(
+            "[preprocessor.note.aliases]
+perf = \"engineering|performance\"
+speed = \"engineering|performance\"",
+        )
+        .unwrap();
+
+        let extracts: Vec<String> = note
+            .parse_chapter(&chapter)
+            .into_iter()
+            .map(|e| format!("{}: {}", e.key.join("<"), e.val))
+            .collect();
+
+        assert_eq!(
+            extracts,
+            vec![
+                "performance<engineering: ### some name",
+                "performance<engineering: a",
+                "performance<engineering: b",
+                "other: ### some name",
+                "other: b",
+            ]
+        );
+
+        assert_eq!(
+            note.parse_marker("speed, other").warnings,
+            vec!["note key speed is an alias of engineering|performance"]
+        );
+    }
+
+    #[test]
+    fn test_extract_uncategorized() {
+        let chapter = chapter(
+            "some name",
+            "{{#note}}no key{{#note end}} {{#note a}}keyed{{#note end}}",
+            vec![],
+        );
+
+        let note = Note {
+            uncategorized: Some("General".to_string()),
+            ..Note::new()
+        };
+
+        let extracts: Vec<String> = note
+            .parse_chapter(&chapter)
+            .into_iter()
+            .map(|e| format!("{}: {}", e.key.join("<"), e.val))
+            .collect();
+
+        assert_eq!(
+            extracts,
+            vec![
+                "General: ### some name",
+                "General: no key",
+                "a: ### some name",
+                "a: keyed",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_exclude_regions() {
+        let chapter = chapter(
+            "some name",
+            "{{#note a}}before{{#note end}}
+{{#note-exclude}}
+quoted {{#note b}}body{{#note end}}
+{{#note-exclude end}}
+{{#note c}}after{{#note end}}",
+            vec![],
+        );
+
+        let note = Note {
+            strict: true,
+            ..Note::new()
+        };
+
+        assert!(note.check_chapter(&chapter).is_ok());
+
+        let extracts: Vec<String> = note
+            .parse_chapter(&chapter)
+            .into_iter()
+            .filter(|e| !e.heading)
+            .map(|e| format!("{}: {}", e.key.join("<"), e.val))
+            .collect();
+
+        assert_eq!(extracts, vec!["a: before", "c: after"]);
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+        assert_eq!(
+            cleaned.content,
+            "before\n\nquoted {{#note b}}body{{#note end}}\n\nafter"
+        );
+
+        let note = Note {
+            strip_excluded: true,
+            ..Note::new()
+        };
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+        assert_eq!(cleaned.content, "before\n\nquoted body\n\nafter");
+    }
+
+    #[test]
+    fn test_extract_crlf() {
+        let chapter = chapter("some name", "text\r\n{{#note a}}\r\n  first line\r\n  second line\r\n{{#note end}}\r\n```\r\n{{#note b}}\r\n```\r\n", vec![]);
+
+        let note = Note {
+            strict: true,
+            ..Note::new()
+        };
+
+        assert!(note.check_chapter(&chapter).is_ok());
+
+        assert_eq!(
+            note.parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: vec!["a".to_string()],
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["a".to_string()],
+                    val: "first line\nsecond line".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+        assert_eq!(
+            cleaned.content,
+            "text\r\n\r\n  first line\r\n  second line\r\n\r\n```\r\n{{#note b}}\r\n```\r\n"
+        );
+    }
+
+    #[test]
+    fn test_extract_malformed_markers() {
+        let chapter = chapter(
+            "some name",
+            "{{#note a}}fine{{#note end}} {{#note key} one brace
+{{ #note key}} spaced and {{#NOTE key}} upper
+`{{#note code}` \\{{#note escaped} {{#notebook}}",
+            vec![],
+        );
+
+        let note = Note {
+            strict: true,
+            ..Note::new()
+        };
+
+        assert_eq!(
+            note.check_chapter(&chapter).unwrap_err().to_string(),
+            "malformed note marker {{#note key} in chapter \"some name\" line 1"
+        );
+
+        let malformed: Vec<&str> = note
+            .scan(&chapter.content)
+            .malformed
+            .into_iter()
+            .map(|m| &chapter.content[m])
+            .collect();
+        assert_eq!(
+            malformed,
+            vec!["{{#note key}", "{{ #note key}}", "{{#NOTE key}}"]
+        );
+
+        assert!(Note::new().check_chapter(&chapter).is_ok());
+    }
+
+    #[test]
+    fn test_extract_quoted_keys() {
+        let chapter = chapter("some name", "{{#note experiments | \"A|B testing\", \"say \\\"a, b\\\"\"/quotes by=\"\\\"me\\\"\"}}x{{#note end}}", vec![]);
+
+        let note = Note::new();
+
+        let mut by = HashMap::new();
+        by.insert("by".to_string(), "\"me\"".to_string());
+
+        let extracts: Vec<Extract> = note
+            .parse_chapter(&chapter)
+            .into_iter()
+            .filter(|e| !e.heading)
+            .collect();
+
+        assert_eq!(
+            extracts,
+            vec![
+                Extract {
+                    key: vec!["A|B testing".to_string(), "experiments".to_string()],
+                    val: "x".to_string(),
+                    attributes: by.clone(),
+                    ..Default::default()
+                },
+                Extract {
+                    key: vec!["quotes".to_string(), "say \"a, b\"".to_string()],
+                    val: "x".to_string(),
+                    attributes: by,
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_max_depth() {
+        let chapter = chapter(
+            "some name",
+            "{{#note a|b|c|d}}deep{{#note end}} {{#note x|y}}shallow{{#note end}}",
+            vec![],
+        );
+
+        let note = Note {
+            max_depth: Some(2),
+            ..Note::new()
+        };
+
+        let extracts: Vec<Vec<String>> = note
+            .parse_chapter(&chapter)
+            .into_iter()
+            .filter(|e| !e.heading)
+            .map(|e| e.key)
+            .collect();
+
+        assert_eq!(
+            extracts,
+            vec![
+                vec!["b|c|d".to_string(), "a".to_string()],
+                vec!["y".to_string(), "x".to_string()],
+            ]
+        );
+
+        assert_eq!(
+            note.parse_marker("a|b|c|d").warnings,
+            vec!["note key a|b|c|d is deeper than max-depth 2"]
+        );
+        assert!(note.parse_marker("x|y").warnings.is_empty());
+    }
+
+    #[test]
+    fn test_extract_key_spacing() {
+        let chapter = chapter(
+            "some name",
+            "{{#note a|b}}one{{#note end}} {{#note a | b}}two{{#note end}}",
+            vec![],
+        );
+
+        let key = vec!["b".to_string(), "a".to_string()];
+        assert_eq!(
+            Note::new().parse_chapter(&chapter),
+            vec![
+                Extract {
+                    key: key.clone(),
+                    val: "### some name".to_string(),
+                    heading: true,
+                    ..Default::default()
+                },
+                Extract {
+                    key: key.clone(),
+                    val: "one".to_string(),
+                    ..Default::default()
+                },
+                Extract {
+                    key,
+                    val: "two".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_markup_keys() {
+        let note = Note::new();
+
+        assert_eq!(
+            note.parse_marker("{{title}}").warnings,
+            vec!["note key {{title}} looks like markup"]
+        );
+        assert!(note.parse_marker("a|b").warnings.is_empty());
+    }
+
+    #[test]
+    fn test_extract_hashtags() {
+        let chapter = chapter("some name", "{{#note work}}\n### Heading\nship it #urgent, ask#not `#code` #urgent #2x #follow-up\n{{#note end}}", vec![]);
+
+        let note = Note {
+            collect_hashtags: true,
+            ..Note::new()
+        };
+
+        let extracts: Vec<Extract> = note
+            .parse_chapter(&chapter)
+            .into_iter()
+            .filter(|e| !e.heading)
+            .collect();
+
+        assert_eq!(
+            extracts.iter().map(|e| e.key.clone()).collect::<Vec<_>>(),
+            vec![
+                vec!["work".to_string()],
+                vec!["urgent".to_string()],
+                vec!["follow-up".to_string()],
+            ]
+        );
+        assert!(extracts.iter().all(|e| e.val.contains("#urgent")));
+
+        assert_eq!(Note::new().parse_chapter(&chapter).len(), 2);
+    }
+
+    #[test]
+    fn test_extract_until_heading() {
+        let chapter = chapter("some name", "# One\n{{#note summary until=heading}}\nfirst section\n```\n# not a heading\n```\n## Two\n{{#note summary until=heading}}\nlast section\n", vec![]);
+
+        let note = Note::new();
+
+        let extracts: Vec<String> = note
+            .parse_chapter(&chapter)
+            .into_iter()
+            .filter(|e| !e.heading)
+            .map(|e| e.val)
+            .collect();
+
+        assert_eq!(
+            extracts,
+            vec![
+                "first section\n```\n# not a heading\n```".to_string(),
+                "last section".to_string(),
+            ]
+        );
+
+        let mut cleaned = chapter.clone();
+        note.clean_chapter(&mut cleaned, &HashMap::new(), &HashMap::new());
+        assert_eq!(
+            cleaned.content,
+            "# One\n\nfirst section\n```\n# not a heading\n```\n## Two\n\nlast section\n"
+        );
+
+        assert!(note.scan(&chapter.content).unclosed.is_empty());
+    }
+}
@@ -0,0 +1,506 @@
+use mdbook::book::Chapter;
+use mdbook::errors::Error;
+use mdbook::BookItem;
+use regex::{Captures, Regex, RegexBuilder};
+use std::ops::Range;
+use std::path::Path;
+
+/// Text escaped to be written in HTML, in an element or an attribute.
+pub(super) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Markdown fitting in a table cell, with its pipes escaped and its line
+/// breaks turned into `<br>`.
+pub(super) fn table_cell(text: &str) -> String {
+    let lines: Vec<&str> = text.trim().lines().map(str::trim).collect();
+
+    lines.join("<br>").replace('|', "\\|")
+}
+
+/// Link to the rendered page of a chapter, relative to the book root.
+pub(super) fn page_link(source: &Path) -> String {
+    let link: Vec<String> = source
+        .with_extension("html")
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().replace(' ', "%20"))
+        .collect();
+
+    link.join("/")
+}
+
+/// Visit every chapter in reading order, a chapter before its sub chapters,
+/// keeping the book hierarchy untouched.
+pub(super) fn for_each_chapter_mut<F>(items: &mut [BookItem], func: &mut F) -> Result<(), Error>
+where
+    F: FnMut(&mut Chapter) -> Result<(), Error>,
+{
+    for item in items {
+        if let BookItem::Chapter(chapter) = item {
+            func(chapter)?;
+            for_each_chapter_mut(&mut chapter.sub_items, func)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a text matches a pattern where `*` stands for any text.
+pub(super) fn wildcard_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            text.starts_with(prefix)
+                && (0..=text.len() - prefix.len())
+                    .filter(|&i| text.is_char_boundary(prefix.len() + i))
+                    .any(|i| wildcard_match(rest, &text[prefix.len() + i..]))
+        }
+    }
+}
+
+/// Byte ranges of the fenced code blocks (``` or ~~~) of a markdown content,
+/// an unclosed fence running up to the end of the content.
+pub(super) fn code_fences(content: &str) -> Vec<Range<usize>> {
+    let mut fences = vec![];
+    let mut open: Option<(usize, char, usize)> = None;
+    let mut offset = 0;
+
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+
+        if let Some(c @ ('`' | '~')) = trimmed.chars().next() {
+            let len = trimmed.chars().take_while(|&x| x == c).count();
+
+            if len >= 3 {
+                match open {
+                    None => open = Some((offset, c, len)),
+                    Some((start, open_char, open_len))
+                        if open_char == c
+                            && len >= open_len
+                            && trimmed[len..].trim().is_empty() =>
+                    {
+                        fences.push(start..offset + line.len());
+                        open = None;
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        offset += line.len();
+    }
+
+    if let Some((start, _, _)) = open {
+        fences.push(start..content.len());
+    }
+
+    fences
+}
+
+/// Byte ranges of the inline code spans of a markdown content, outside of the
+/// given fenced code blocks.
+///
+/// A span opens with a run of backticks and closes with a run of the same
+/// length in the same paragraph, an unmatched run being literal text.
+pub(super) fn code_spans(content: &str, fences: &[Range<usize>]) -> Vec<Range<usize>> {
+    let bytes = content.as_bytes();
+    let backticks = |at: usize| bytes[at..].iter().take_while(|&&b| b == b'`').count();
+    let stop = |at: usize| {
+        fences.iter().any(|fence| fence.start == at)
+            || (bytes[at] == b'\n'
+                && content[at + 1..]
+                    .lines()
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .is_empty())
+    };
+
+    let mut spans = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if let Some(fence) = fences.iter().find(|fence| fence.contains(&i)) {
+            i = fence.end;
+            continue;
+        }
+        if bytes[i] != b'`' {
+            i += 1;
+            continue;
+        }
+
+        let len = backticks(i);
+        let mut j = i + len;
+
+        while j < bytes.len() && !stop(j) {
+            if bytes[j] == b'`' {
+                let closing = backticks(j);
+                if closing == len {
+                    spans.push(i..j + closing);
+                    break;
+                }
+                j += closing;
+            } else {
+                j += 1;
+            }
+        }
+
+        i = match spans.last() {
+            Some(span) if span.start == i => span.end,
+            _ => i + len,
+        };
+    }
+
+    spans
+}
+
+/// Line, from 1, of a byte offset in a content.
+pub(super) fn line_number(content: &str, offset: usize) -> usize {
+    content[..offset].matches('\n').count() + 1
+}
+
+/// A count followed by the singular or plural noun, as `1 note` or `2 notes`,
+/// for diagnostics.
+pub(super) fn count(n: usize, singular: &str, plural: &str) -> String {
+    match n {
+        1 => format!("1 {}", singular),
+        n => format!("{} {}", n, plural),
+    }
+}
+
+/// Human readable position of a byte offset in a chapter, for diagnostics.
+pub(super) fn location(chapter: &Chapter, offset: usize) -> String {
+    let line = line_number(&chapter.content, offset);
+
+    match &chapter.path {
+        Some(path) => format!(
+            "chapter \"{}\" ({}) line {}",
+            chapter.name,
+            path.display(),
+            line
+        ),
+        None => format!("chapter \"{}\" line {}", chapter.name, line),
+    }
+}
+
+/// Byte ranges of the targets of the inline links, images and link
+/// definitions of a markdown content, outside code.
+fn link_targets(content: &str) -> Vec<Range<usize>> {
+    let mut code = code_fences(content);
+    code.append(&mut code_spans(content, &code));
+
+    let inline = Regex::new(r"!?\[[^\]\n]*\]\([ \t]*(?P<url>[^)\s]+)").unwrap();
+    let definition = RegexBuilder::new(r"^ {0,3}\[[^\]^\n][^\]\n]*\]:[ \t]*(?P<url>\S+)")
+        .multi_line(true)
+        .build()
+        .unwrap();
+
+    let mut targets: Vec<Range<usize>> = inline
+        .captures_iter(content)
+        .chain(definition.captures_iter(content))
+        .filter_map(|cap| cap.name("url"))
+        .map(|url| url.start()..url.end())
+        .filter(|range| !code.iter().any(|code| code.contains(&range.start)))
+        .collect();
+    targets.sort_by_key(|range| range.start);
+
+    targets
+}
+
+/// Whether a link target is relative to the page it is in, rather than an
+/// absolute path, an URL with a scheme, an in-page anchor or a template.
+fn is_relative_link(url: &str) -> bool {
+    let scheme = url.split(['/', '?', '#']).next().unwrap_or("");
+
+    !url.starts_with(['#', '/', '<', '{']) && !scheme.contains(':')
+}
+
+/// Content with its relative link targets replaced by `rewrite`.
+pub(super) fn rewrite_links(content: &str, rewrite: impl Fn(&str) -> String) -> String {
+    let mut res = String::new();
+    let mut last = 0;
+
+    for range in link_targets(content) {
+        if is_relative_link(&content[range.clone()]) {
+            res.push_str(&content[last..range.start]);
+            res.push_str(&rewrite(&content[range.clone()]));
+            last = range.end;
+        }
+    }
+    res.push_str(&content[last..]);
+
+    res
+}
+
+/// Relative link from a page in `dir` as a link from the book root, with its
+/// `.` and `..` levels resolved.
+pub(super) fn rebase_link(dir: &Path, url: &str) -> String {
+    let mut levels: Vec<String> = dir
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+    let mut up = 0;
+
+    for level in url.split('/') {
+        match level {
+            "." => {}
+            ".." if levels.is_empty() => up += 1,
+            ".." => {
+                levels.pop();
+            }
+            _ => levels.push(level.to_string()),
+        }
+    }
+
+    format!("{}{}", "../".repeat(up), levels.join("/"))
+}
+
+/// Labels of the reference links of a markdown content, `[text][label]` or
+/// `[label][]`, without a definition in that content.
+pub(super) fn undefined_references(content: &str) -> Vec<String> {
+    let reference = Regex::new(r"\[(?P<text>[^\]\n]*)\]\[(?P<label>[^\]\n]*)\]").unwrap();
+    let definition = Regex::new(r"(?m)^ {0,3}\[(?P<label>[^\]\n]+)\]:").unwrap();
+
+    let defined: Vec<String> = definition
+        .captures_iter(content)
+        .map(|cap| capture(&cap, "label").to_lowercase())
+        .collect();
+    let mut labels: Vec<String> = vec![];
+
+    for cap in reference.captures_iter(content) {
+        // `[^1][^2]` are footnotes
+        if capture(&cap, "text").starts_with('^') {
+            continue;
+        }
+
+        let label = match capture(&cap, "label") {
+            label if label.is_empty() => capture(&cap, "text"),
+            label => label,
+        };
+
+        if !defined.contains(&label.to_lowercase()) && !labels.contains(&label) {
+            labels.push(label);
+        }
+    }
+
+    labels
+}
+
+/// Leading paragraphs of a markdown content longer than `max_length`
+/// characters or `max_lines` lines, as many as fit, or the first one, never
+/// cutting a code block. `None` when it fits or has a single paragraph.
+pub(super) fn shorten(
+    content: &str,
+    max_length: Option<usize>,
+    max_lines: Option<usize>,
+) -> Option<String> {
+    let fits = |text: &str| {
+        max_length.is_none_or(|max| text.chars().count() <= max)
+            && max_lines.is_none_or(|max| text.lines().count() <= max)
+    };
+    if fits(content) {
+        return None;
+    }
+
+    let fences = code_fences(content);
+    let ends: Vec<usize> = content
+        .match_indices("\n\n")
+        .map(|(offset, _)| offset)
+        .filter(|&offset| !fences.iter().any(|fence| fence.contains(&(offset + 1))))
+        .collect();
+
+    let end = ends
+        .iter()
+        .rev()
+        .find(|&&end| fits(&content[..end]))
+        .or_else(|| ends.first())?;
+
+    Some(content[..*end].trim_end().to_string())
+}
+
+/// Markdown with its ATX headings outside code demoted alike, so that the
+/// highest one is at least at `level`, down to level 6.
+pub(super) fn shift_headings(content: &str, level: usize) -> String {
+    let heading =
+        Regex::new(r"(?m)^(?P<indent> {0,3})(?P<hashes>#{1,6})(?P<rest>[ \t].*|)$").unwrap();
+    let fences = code_fences(content);
+    let outside = |cap: &Captures| {
+        let start = cap.get(0).unwrap().start();
+        !fences.iter().any(|fence| fence.contains(&start))
+    };
+
+    let top = heading
+        .captures_iter(content)
+        .filter(|cap| outside(cap))
+        .map(|cap| capture(&cap, "hashes").len())
+        .min();
+    let shift = match top {
+        Some(top) if top < level => level - top,
+        _ => return content.to_string(),
+    };
+
+    heading
+        .replace_all(content, |cap: &Captures| match outside(cap) {
+            true => format!(
+                "{}{}{}",
+                &cap["indent"],
+                "#".repeat((cap["hashes"].len() + shift).min(6)),
+                &cap["rest"]
+            ),
+            false => cap.get(0).unwrap().as_str().to_string(),
+        })
+        .to_string()
+}
+
+/// Regex of the footnote references of a markdown content, a reference
+/// followed by `:` at the start of a line being a definition.
+fn footnote_regex() -> Regex {
+    Regex::new(r"(?m)(?P<start>^ {0,3})?\[\^(?P<label>[^\]\s]+)\](?P<colon>:)?").unwrap()
+}
+
+/// Labels of the footnotes referenced by a markdown content, outside code,
+/// and of the footnotes it defines.
+pub(super) fn footnotes(content: &str) -> (Vec<String>, Vec<String>) {
+    let mut code = code_fences(content);
+    code.append(&mut code_spans(content, &code));
+
+    let mut references: Vec<String> = vec![];
+    let mut definitions: Vec<String> = vec![];
+
+    for cap in footnote_regex().captures_iter(content) {
+        let offset = cap.get(0).unwrap().start();
+        if code.iter().any(|range| range.contains(&offset)) {
+            continue;
+        }
+
+        let label = capture(&cap, "label");
+        let labels = match cap.name("start").is_some() && cap.name("colon").is_some() {
+            true => &mut definitions,
+            false => &mut references,
+        };
+        if !labels.contains(&label) {
+            labels.push(label);
+        }
+    }
+
+    (references, definitions)
+}
+
+/// Note body followed by the definitions of the footnotes it references from
+/// elsewhere in `chapter`, with its footnote labels prefixed by `prefix` so
+/// that the notes of several chapters can share a page.
+pub(super) fn namespace_footnotes(body: &str, chapter: &str, prefix: &str) -> String {
+    let (references, definitions) = footnotes(body);
+    if references.is_empty() && definitions.is_empty() {
+        return body.to_string();
+    }
+
+    let mut body = body.to_string();
+
+    for label in references
+        .iter()
+        .filter(|label| !definitions.contains(label))
+    {
+        let definition = Regex::new(&format!(
+            r"(?m)^ {{0,3}}\[\^{}\]:.*(?:\n(?: {{4}}|\t).*)*",
+            regex::escape(label)
+        ))
+        .unwrap();
+
+        if let Some(found) = definition.find(chapter) {
+            body.push_str("\n\n");
+            body.push_str(found.as_str().trim_end());
+        }
+    }
+
+    let mut code = code_fences(&body);
+    code.append(&mut code_spans(&body, &code));
+
+    footnote_regex()
+        .replace_all(&body, |cap: &Captures| {
+            let found = cap.get(0).unwrap();
+            match code.iter().any(|range| range.contains(&found.start())) {
+                true => found.as_str().to_string(),
+                false => format!(
+                    "{}[^{}-{}]{}",
+                    cap.name("start").map_or("", |start| start.as_str()),
+                    prefix,
+                    capture(cap, "label"),
+                    capture(cap, "colon")
+                ),
+            }
+        })
+        .to_string()
+}
+
+/// Offset of the first markdown heading line after the line of `start`,
+/// outside code, or the end of the content.
+pub(super) fn heading_start(content: &str, start: usize, code: &[Range<usize>]) -> usize {
+    let mut offset = start;
+
+    for line in content[start..].split_inclusive('\n') {
+        let level = line.len() - line.trim_start_matches('#').len();
+        let rest = &line[level..];
+
+        if offset > start
+            && (1..=6).contains(&level)
+            && (rest.is_empty() || rest.starts_with(char::is_whitespace))
+            && !code.iter().any(|range| range.contains(&offset))
+        {
+            return offset;
+        }
+        offset += line.len();
+    }
+
+    content.len()
+}
+
+/// Offset of the blank line ending the paragraph started on the line of
+/// `start`, or the end of the content.
+pub(super) fn paragraph_end(content: &str, start: usize) -> usize {
+    let mut offset = start;
+
+    for line in content[start..].split_inclusive('\n') {
+        if offset > start && line.trim().is_empty() {
+            return offset;
+        }
+        offset += line.len();
+    }
+
+    content.len()
+}
+
+pub(super) fn capture(cap: &Captures, k: &str) -> String {
+    match cap.name(k) {
+        Some(res) => res.as_str().trim().to_string(),
+        None => "".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_footnotes() {
+        assert_eq!(
+            footnotes("a[^1] [^2][^3]\n```\n[^4]\n```\n[^3]: three\n[^5]: five"),
+            (
+                vec!["1".to_string(), "2".to_string(), "3".to_string()],
+                vec!["3".to_string(), "5".to_string()]
+            )
+        );
+        assert!(undefined_references("[^1][^2]").is_empty());
+    }
+
+    #[test]
+    fn test_undefined_references() {
+        assert_eq!(
+            undefined_references("[a][x] [b][] [c][y] [d](d.md)\n\n[Y]: y.md"),
+            vec!["x", "b"]
+        );
+    }
+}